@@ -0,0 +1,405 @@
+use std::collections::{BTreeMap, HashSet};
+use std::io::{Write, Read};
+
+use anyhow::Result;
+use bincode::{serialize_into, deserialize_from};
+
+use crate::ops::{Arg, ErrKind, calc_err, id_to_pos};
+use crate::sheet::Sheet;
+use crate::expr::Expr;
+use crate::udf;
+
+// Named ranges and constants: lowercase name <=> a range with the page name or a value
+pub type Names = BTreeMap<String, Arg>;
+static NO_NAMES: Names = BTreeMap::new();
+// Spills that move other spills are recalculated up to this number of times
+const MAX_SPILL_PASSES: usize = 8;
+
+// Moves named ranges of the page after inserting or deleting rows or columns
+pub fn shift_names(names: &mut Names, page: &str, dcol: isize, drow: isize, bcol: usize, brow: usize) {
+    let page = page.to_lowercase();
+    for val in names.values_mut() {
+        if matches!(val, Arg::Rng(Some(name), _) if name.to_lowercase() == page) {
+            val.move_by(dcol, drow, bcol, brow);
+        }
+    }
+}
+pub fn save_names<W: Write+Copy>(f: W, names: &Names) -> Result<()> {
+    serialize_into(f, &names.len())?;
+    for (name, val) in names.iter() {
+        serialize_into(f, name)?;
+        serialize_into(f, &val.to_expr())?;
+    }
+    Ok(())
+}
+pub fn load_names<R: Read+Copy>(f: R, sheet: &Sheet) -> Result<Names> {
+    let mut names = Names::new();
+    let cnt: usize = deserialize_from(f)?;
+    for _i in 0..cnt {
+        let name: String = deserialize_from(f)?;
+        let val: String = deserialize_from(f)?;
+        names.insert(name, sheet.parse_name_value(&val));
+    }
+    Ok(names)
+}
+
+// Iterative calculation of circular references: formulas of a cycle read values of the previous
+// pass, passes repeat until no value changes more than `tolerance` or `max` passes are done
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct Iteration {
+    pub max: usize,
+    pub tolerance: f64,
+}
+impl Default for Iteration {
+    fn default() -> Iteration {
+        Iteration { max: 100, tolerance: 0.001 }
+    }
+}
+
+// Pages of a workbook as formulas see them. A reference without a page name points to
+// the page of the cell which formula is being calculated, e.g. 'page2'!A1 reads the other page
+pub struct Book<'a> {
+    pages: &'a mut [Sheet],
+    pub page: usize, // index of the page which formula is being calculated
+    names: &'a Names, // workbook names, names of a page hide them
+}
+
+impl<'a> Book<'a> {
+    pub fn new(pages: &'a mut [Sheet], page: usize) -> Book<'a> {
+        Book { pages, page, names: &NO_NAMES }
+    }
+    pub fn with_names(pages: &'a mut [Sheet], page: usize, names: &'a Names) -> Book<'a> {
+        Book { pages, page, names }
+    }
+    pub fn sheet(&mut self) -> &mut Sheet {
+        &mut self.pages[self.page]
+    }
+    pub fn page_sheet(&mut self, page: usize) -> &mut Sheet {
+        &mut self.pages[page]
+    }
+    pub fn page_name(&self, page: usize) -> String {
+        self.pages[page].name.clone()
+    }
+    // Formulas and names of other pages follow the cells of the page after its rows
+    // or columns are inserted or deleted
+    pub fn shift_page_refs(&mut self, page: usize, dcol: isize, drow: isize, bcol: usize, brow: usize) {
+        let name = self.page_name(page);
+        for (idx, sheet) in self.pages.iter_mut().enumerate() {
+            if idx != page {
+                sheet.shift_page_refs(&name, dcol, drow, bcol, brow);
+            }
+        }
+    }
+    // None - circular references are errors
+    pub fn iteration(&self) -> Option<Iteration> {
+        self.pages[self.page].iteration
+    }
+    // Value of a name for formulas of the page
+    fn lookup(&self, page: usize, name: &str) -> Option<Arg> {
+        let name = name.to_lowercase();
+        self.pages[page].names.get(&name).or_else(|| self.names.get(&name)).cloned()
+    }
+    pub fn find_name(&self, name: &str) -> Option<Arg> {
+        self.lookup(self.page, name)
+    }
+    pub fn name_value(&self, name: &str) -> Result<Arg> {
+        self.find_name(name).ok_or_else(|| calc_err(ErrKind::Name, format!("unknown name '{}'", name)))
+    }
+    // Index of the page a reference points to. Page names are case-insensitive
+    pub fn page_index(&self, name: &Option<String>) -> Result<usize> {
+        match name {
+            None => Ok(self.page),
+            Some(n) => {
+                let n = n.to_lowercase();
+                self.pages.iter().position(|s| s.name.to_lowercase() == n).ok_or_else(|| calc_err(ErrKind::Ref, format!("unknown page '{}'", n)))
+            },
+        }
+    }
+    // Cells of all pages which formulas read the cell. Formulas reading values a formula spills
+    // depend on it, and a formula depends on the cells its array would cover
+    fn dependents(&self, page: usize, uid: u64) -> Vec<(usize, u64)> {
+        let name = self.pages[page].name.to_lowercase();
+        let (col, row) = id_to_pos(uid);
+        let mut res: Vec<(usize, u64)> = self.pages[page].spill_anchors(col, row, col, row).into_iter()
+            .filter(|&a| a != uid).map(|a| (page, a)).collect();
+        let mut cells = self.pages[page].spill_area(uid);
+        cells.push(uid);
+        for (idx, sheet) in self.pages.iter().enumerate() {
+            for &id in cells.iter() {
+                res.extend(sheet.deps.dependents(&name, idx == page, id).into_iter().map(|id| (idx, id)));
+                let named = sheet.deps.named_dependents(&name, idx == page, id, |n| self.lookup(idx, n).or_else(|| udf::lookup(n)));
+                res.extend(named.into_iter().map(|id| (idx, id)));
+            }
+        }
+        res
+    }
+    // The cells and all cells that depend on them, every cell goes after the cells it reads.
+    // Cells in a circular reference are in any order, calculation marks them as errors
+    fn dirty_order(&self, cells: &[(usize, u64)]) -> Vec<(usize, u64)> {
+        let mut visited: HashSet<(usize, u64)> = HashSet::new();
+        let mut order: Vec<(usize, u64)> = Vec::new();
+        for cell in cells.iter() {
+            let mut stack: Vec<((usize, u64), bool)> = vec![(*cell, false)];
+            while let Some((node, finished)) = stack.pop() {
+                if finished {
+                    order.push(node);
+                    continue;
+                }
+                if !visited.insert(node) {
+                    continue;
+                }
+                stack.push((node, true));
+                for dep in self.dependents(node.0, node.1) {
+                    if !visited.contains(&dep) {
+                        stack.push((dep, false));
+                    }
+                }
+            }
+        }
+        order.reverse();
+        order
+    }
+    // Recalculates formulas that depend on the changed cells, and all volatile formulas
+    pub fn recalc_cells(&mut self, changed: &[(usize, u64)]) {
+        let mut cells = changed.to_vec();
+        for (idx, sheet) in self.pages.iter().enumerate() {
+            cells.extend(sheet.deps.volatile().into_iter().map(|id| (idx, id)));
+        }
+        let order = self.dirty_order(&cells);
+        self.update(&order, Some(order.iter().copied().collect()));
+    }
+    // Recalculates formulas that depend on cells changed on any page since the last recalculation
+    pub fn recalc_changed(&mut self) {
+        let mut cells = Vec::new();
+        for (idx, sheet) in self.pages.iter_mut().enumerate() {
+            cells.extend(sheet.changed.drain(..).map(|id| (idx, id)));
+        }
+        self.recalc_cells(&cells);
+    }
+    // Calculates the formulas. `stale` - formulas that must be recalculated, None - all formulas
+    fn update(&mut self, cells: &[(usize, u64)], stale: Option<HashSet<(usize, u64)>>) {
+        let circular = self.pass(cells, &stale);
+        if let Some(iteration) = self.iteration() {
+            self.iterate(cells, &stale, circular, iteration);
+        }
+        self.update_spilled();
+    }
+    // Formulas that read cells before a spill covered or uncovered them got wrong values
+    fn update_spilled(&mut self) {
+        for _ in 0..MAX_SPILL_PASSES {
+            let mut cells = Vec::new();
+            for (idx, sheet) in self.pages.iter_mut().enumerate() {
+                cells.extend(sheet.respilled.drain(..).map(|id| (idx, id)));
+            }
+            if cells.is_empty() {
+                return;
+            }
+            let order = self.dirty_order(&cells);
+            self.pass(&order, &Some(order.iter().copied().collect()));
+        }
+    }
+    // Repeats passes until values of circular references settle
+    fn iterate(&mut self, cells: &[(usize, u64)], stale: &Option<HashSet<(usize, u64)>>, mut circular: bool, iteration: Iteration) {
+        let mut passes = 1;
+        while circular && passes < iteration.max {
+            let before = self.values(cells);
+            circular = self.pass(cells, stale);
+            passes += 1;
+            let after = self.values(cells);
+            let converged = before.iter().zip(after.iter()).all(|(b, a)| match (b, a) {
+                (Arg::Number(b), Arg::Number(a)) => (a - b).abs() <= iteration.tolerance,
+                _ => a == b,
+            });
+            if converged {
+                break;
+            }
+        }
+        info!("calculated in {} passes", passes);
+    }
+    // Returns true if the formulas contain circular references
+    fn pass(&mut self, cells: &[(usize, u64)], stale: &Option<HashSet<(usize, u64)>>) -> bool {
+        let mut expr = Expr::default();
+        expr.stale = stale.clone();
+        for (page, uid) in cells.iter() {
+            self.page = *page;
+            expr.update_cell(self, *uid);
+        }
+        expr.circular
+    }
+    fn values(&self, cells: &[(usize, u64)]) -> Vec<Arg> {
+        cells.iter().map(|(page, uid)| match self.pages[*page].cells.get(uid) {
+            None => Arg::End,
+            Some(c) => c.calculated.clone(),
+        }).collect()
+    }
+    fn formulas(&self, page: usize) -> Vec<(usize, u64)> {
+        self.pages[page].cells.iter().filter(|(_, cell)| cell.is_expr()).map(|(id, _)| (page, *id)).collect()
+    }
+    // Recalculates all formulas of the current page
+    pub fn recalc_page(&mut self) {
+        let cells = self.formulas(self.page);
+        self.update(&cells, None);
+    }
+    // Recalculates all pages, so formulas pick up changes made on other pages
+    pub fn recalc(&mut self) {
+        let mut cells = Vec::new();
+        for page in 0..self.pages.len() {
+            cells.extend(self.formulas(page));
+            self.pages[page].changed.clear();
+        }
+        self.update(&cells, None);
+    }
+}
+
+#[rustfmt::skip]
+#[cfg(test)]
+mod book_test {
+    use super::*;
+    use crate::parse::{MAX_COLS, MAX_ROWS};
+
+    #[test]
+    fn cross_page_test() {
+        let mut pages = vec![Sheet::new(0, 80, 25), Sheet::new(1, 80, 25)];
+        pages[1].name = "Data Page".to_string();
+        pages[1].set_cell_text(0, 0, "10", true);
+        pages[1].set_cell_text(0, 1, "=A1*2", true);
+        pages[0].set_cell_text(0, 0, "1", true);
+        pages[0].set_cell_text(1, 0, "='data page'!A2+A1", true);
+        pages[0].set_cell_text(1, 1, "=SUM('Data Page'!A1:A2)", true);
+        pages[0].set_cell_text(1, 2, "='page9'!A1", true);
+        pages[0].set_cell_text(1, 3, "=page1!A1", true);
+        Book::new(&mut pages, 0).recalc();
+        assert_eq!(pages[0].cell(1, 0).title(), "21");
+        assert_eq!(pages[0].cell(1, 1).title(), "30");
+        assert_eq!(pages[0].cell(1, 2).title(), "#REF!");
+        assert_eq!(pages[0].cell(1, 3).title(), "1");
+
+        pages[1].set_cell_text(0, 0, "5", true);
+        Book::new(&mut pages, 0).recalc();
+        assert_eq!(pages[0].cell(1, 0).title(), "11");
+        assert_eq!(pages[0].cell(1, 1).title(), "15");
+    }
+
+    #[test]
+    fn cross_page_shift_test() {
+        let mut pages = vec![Sheet::new(0, 80, 25), Sheet::new(1, 80, 25)];
+        pages[1].set_cell_text(0, 4, "7", true);
+        pages[1].set_cell_text(1, 4, "8", true);
+        pages[0].set_cell_text(0, 0, "=page2!A5", true);
+        pages[0].set_cell_text(0, 1, "=SUM(page2!A5:B5)+A5", true);
+        let total = pages[0].parse_name_value("page2!B5");
+        pages[0].names.insert("total".to_string(), total);
+        pages[1].insert_rows(1, 2);
+        Book::new(&mut pages, 1).shift_page_refs(1, 0, 2, MAX_COLS, 1);
+        Book::new(&mut pages, 0).recalc();
+        assert_eq!(pages[0].cell(0, 0).val, "=page2!A7");
+        assert_eq!(pages[0].cell(0, 0).title(), "7");
+        assert_eq!(pages[0].cell(0, 1).val, "=SUM(page2!A7:B7)+A5");
+        assert_eq!(pages[0].cell(0, 1).title(), "15");
+        assert_eq!(pages[0].names["total"].to_expr(), "page2!B7");
+
+        pages[1].delete_cols(0, 1);
+        Book::new(&mut pages, 1).shift_page_refs(1, -1, 0, 1, MAX_ROWS);
+        Book::new(&mut pages, 0).recalc();
+        assert_eq!(pages[0].cell(0, 0).val, "=#REF!");
+        assert_eq!(pages[0].cell(0, 1).val, "=SUM(page2!A7:A7)+A5");
+        assert_eq!(pages[0].cell(0, 1).title(), "8");
+        assert_eq!(pages[0].names["total"].to_expr(), "page2!A7");
+    }
+
+    #[test]
+    fn cross_page_recursion_test() {
+        let mut pages = vec![Sheet::new(0, 80, 25), Sheet::new(1, 80, 25)];
+        pages[0].set_cell_text(0, 0, "='page2'!A1", true);
+        pages[1].set_cell_text(0, 0, "='page1'!A1", true);
+        pages[1].set_cell_text(0, 1, "='page1'!B1", true);
+        pages[0].set_cell_text(1, 0, "7", true);
+        Book::new(&mut pages, 0).recalc();
+        assert_eq!(pages[0].cell(0, 0).title(), "#CIRC!");
+        assert_eq!(pages[1].cell(0, 0).title(), "#CIRC!");
+        assert_eq!(pages[1].cell(0, 1).title(), "7");
+        assert_eq!(pages[0].cell(0, 0).reason, "circular reference: A1 -> page2!A1 -> A1");
+    }
+
+    #[test]
+    fn iteration_test() {
+        let value = |sheet: &Sheet, col: usize, row: usize| -> f64 {
+            match sheet.cell(col, row).calculated { Arg::Number(f) => f, a => panic!("{:?}", a) }
+        };
+        let mut pages = vec![Sheet::new(0, 80, 25)];
+        pages[0].set_cell_text(0, 0, "=B1+1", true);
+        pages[0].set_cell_text(1, 0, "=A1*0.5", true);
+        pages[0].set_cell_text(2, 0, "=A1*10", true);
+        assert_eq!(pages[0].cell(0, 0).title(), "#CIRC!");
+        assert_eq!(pages[0].cell(0, 0).reason, "circular reference: B1 -> A1 -> B1");
+        assert_eq!(pages[0].cell(1, 0).reason, "circular reference: B1 -> A1 -> B1");
+        assert_eq!(pages[0].cell(2, 0).title(), "#CIRC!");
+
+        pages[0].iteration = Some(Iteration::default());
+        Book::new(&mut pages, 0).recalc();
+        assert!((value(&pages[0], 0, 0) - 2.0).abs() < 0.01);
+        assert!((value(&pages[0], 1, 0) - 1.0).abs() < 0.01);
+        assert!((value(&pages[0], 2, 0) - 20.0).abs() < 0.1);
+        pages[0].set_cell_text(1, 0, "=A1*0.25", true);
+        assert!((value(&pages[0], 0, 0) - 4.0 / 3.0).abs() < 0.01);
+
+        pages[0].iteration = Some(Iteration { max: 2, tolerance: 0.001 });
+        pages[0].set_cell_text(1, 0, "=A1*0.5+1", true);
+        assert!(value(&pages[0], 0, 0) < 3.5);
+
+        pages[0].iteration = None;
+        Book::new(&mut pages, 0).recalc();
+        assert_eq!(pages[0].cell(0, 0).title(), "#CIRC!");
+    }
+
+    #[test]
+    fn cross_page_incremental_test() {
+        let mut pages = vec![Sheet::new(0, 80, 25), Sheet::new(1, 80, 25)];
+        pages[0].set_cell_text(0, 0, "1", false);
+        pages[1].set_cell_text(0, 0, "=page1!A1*2", false);
+        pages[0].set_cell_text(1, 0, "=page2!A1+1", false);
+        pages[1].set_cell_text(1, 0, "=SUM(page1!A1:B1)", false);
+        Book::new(&mut pages, 0).recalc_changed();
+        assert_eq!(pages[1].cell(0, 0).title(), "2");
+        assert_eq!(pages[0].cell(1, 0).title(), "3");
+        assert_eq!(pages[1].cell(1, 0).title(), "4");
+        assert!(pages[0].changed.is_empty() && pages[1].changed.is_empty());
+
+        pages[0].set_cell_text(0, 0, "5", false);
+        Book::new(&mut pages, 0).recalc_changed();
+        assert_eq!(pages[1].cell(0, 0).title(), "10");
+        assert_eq!(pages[0].cell(1, 0).title(), "11");
+        assert_eq!(pages[1].cell(1, 0).title(), "16");
+    }
+
+    #[test]
+    fn names_test() {
+        let mut pages = vec![Sheet::new(0, 80, 25), Sheet::new(1, 80, 25)];
+        for row in 1..=3 {
+            pages[1].set_cell_text(1, row, &format!("{}", row * 10), false);
+        }
+        let mut names = Names::new();
+        names.insert("revenue".to_string(), pages[0].parse_name_value("page2!$B$2:$B$4"));
+        names.insert("taxrate".to_string(), pages[0].parse_name_value("0.5"));
+        names.insert("label".to_string(), pages[0].parse_name_value("\"total\""));
+        let rate = pages[1].parse_name_value("2");
+        pages[1].names.insert("taxrate".to_string(), rate);
+        assert_eq!(names["revenue"].to_expr(), "page2!B2:B4");
+        pages[0].set_cell_text(0, 0, "=SUM(Revenue)*TaxRate", false);
+        pages[0].set_cell_text(0, 1, "=label&\" \"&taxrate", false);
+        pages[0].set_cell_text(0, 2, "=Unknown+1", false);
+        pages[1].set_cell_text(0, 0, "=SUM(Revenue)*TaxRate", false);
+        Book::with_names(&mut pages, 0, &names).recalc_changed();
+        assert_eq!(pages[0].cell(0, 0).title(), "30");
+        assert_eq!(pages[0].cell(0, 1).title(), "total 0.5");
+        assert_eq!(pages[0].cell(0, 2).title(), "#NAME?");
+        assert_eq!(pages[1].cell(0, 0).title(), "120");
+
+        // formulas reading a name follow changes of the range
+        pages[1].set_cell_text(1, 2, "40", false);
+        Book::with_names(&mut pages, 0, &names).recalc_changed();
+        assert_eq!(pages[0].cell(0, 0).title(), "40");
+        assert_eq!(pages[1].cell(0, 0).title(), "160");
+    }
+}
+
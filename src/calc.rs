@@ -10,14 +10,22 @@ use unicode_width::UnicodeWidthStr;
 use bincode::{serialize_into, deserialize_from};
 
 use crate::primitive::Screen;
-use crate::ui::{Widget,Context,Transition,NOTHING,MAIN_WIDGET,Dialog,PageListArgs,Msg,Command};
+use crate::ui::{Widget,Context,Transition,NOTHING,MAIN_WIDGET,Dialog,PageListArgs,PopupArgs,Msg,Command};
 use crate::edit::Edit;
 use crate::strs;
 use crate::sheet::{Sheet, CalcMode, VERSION, Align, SelectType};
-use crate::parse::{Range, idx_to_name, MAX_COLS, MAX_ROWS, DEF_NUM_WIDTH, is_white};
-use crate::ops::{err_msg};
+use crate::book::{Book, Iteration, Names, shift_names, save_names, load_names};
+use crate::parse::{Range, idx_to_name, MAX_COLS, MAX_ROWS, DEF_NUM_WIDTH, is_white, is_name, parse_ident};
+use crate::ops::Arg;
+use crate::stack::{syntax_error, references};
+use crate::deps::Area;
+use crate::complete::{complete, current_call};
+use crate::funcs;
+use crate::udf;
 
 const MAX_PAGES: usize = 100; // TODO:
+// Colors of references in an edited formula, used for the reference text and the cells it covers
+const REF_COLORS: [Color; 6] = [Color::DarkCyan, Color::DarkMagenta, Color::DarkGreen, Color::DarkRed, Color::DarkYellow, Color::DarkBlue];
 
 pub struct Calc {
     name: String,
@@ -31,6 +39,10 @@ pub struct Calc {
     ed_top: Edit,
     ed_bottom: Edit,
     err: Option<String>,
+    seed: Option<u64>, // random seed for RAND-like functions, None - use system entropy
+    iteration: Option<Iteration>, // iterative calculation, None - circular references are errors
+    names: Names, // workbook names, names of pages are stored in sheets
+    completion: Option<(usize, Vec<String>)>, // where the completed word starts and the items of the open popup
     /*
      * attr: Attr, // default attrs for even cols
      * alt_attr: Attr, // default attrs for odd cols
@@ -42,7 +54,7 @@ impl Default for Calc {
     fn default() -> Calc {
         let ctx = Context::new(0, 0);
         Calc {name: MAIN_WIDGET.to_string(), col: 0, row: 0, w: 0, h: 0, gen: 0,
-            sheets: Vec::new(), sheet: 0, err: None,
+            sheets: Vec::new(), sheet: 0, err: None, seed: None, iteration: None, names: Names::new(), completion: None,
             ed_top: Edit::new(&ctx, "ed-top", 1, 0, 0, Color::Black, Color::Grey, "[TOP]"),
             ed_bottom: Edit::new(&ctx, "ed-btm", 1, 0, 0, Color::Black, Color::Grey, "[BTM]"),
         }
@@ -52,8 +64,11 @@ impl Default for Calc {
 impl Calc {
     pub fn new(ctx: &Context) -> Calc {
         let def_sheet = Sheet::new(0, ctx.w, ctx.h-1);
+        // bad user-defined functions are reported in the status line
+        let errs = udf::load();
+        let err = if errs.is_empty() { None } else { Some(errs.join("; ")) };
         Calc {name: MAIN_WIDGET.to_string(), col: 0, row: 0, w: ctx.w, h: ctx.h-1, gen: 0,
-            sheets: vec![def_sheet], sheet: 0, err: None,
+            sheets: vec![def_sheet], sheet: 0, err, seed: None, iteration: None, names: Names::new(), completion: None,
             ed_top: Edit::new(ctx, "ed-top", 1, ctx.h-1, ctx.w-2, Color::Black, Color::Grey, "[TOP]"),
             ed_bottom: Edit::new(ctx, "ed-btm", 1, ctx.h-1, ctx.w-2, Color::Black, Color::Grey, "[BTM]"),
         }
@@ -114,6 +129,69 @@ impl Calc {
         }
         Ok(())
     }
+    // The editor takes the status line, so a rejected formula is explained in the line above it
+    fn show_edit_error(&self, ctx: &Context, scr: &mut Screen) -> Result<()> {
+        if let Some(msg) = &self.err {
+            scr.colors(Color::Red, Color::Black);
+            let title = strs::pad(&strs::cut(msg, 0, (ctx.w - 1) as usize), (ctx.w - 1) as usize);
+            scr.write_string(&title, 0, ctx.h - 2);
+        }
+        Ok(())
+    }
+    // Signature of the function the cursor is in, with the current argument highlighted
+    fn show_signature(&self, ctx: &Context, scr: &mut Screen) -> Result<()> {
+        if let Some(hint) = self.signature_hint() {
+            scr.colors(Color::White, Color::Black);
+            scr.fill_rect(0, ctx.h - 2, ctx.w, 1, ' ');
+            scr.write_string_highlight(&hint, 0, ctx.h - 2, Color::Yellow);
+        }
+        Ok(())
+    }
+    // Built-in functions come from the function table, user-defined ones show their LAMBDA parameters
+    fn signature_hint(&self) -> Option<String> {
+        let before: String = self.ed_top.text().chars().take(self.ed_top.cursor()).collect();
+        if !before.starts_with('=') {
+            return None;
+        }
+        let (name, arg) = current_call(&before)?;
+        if let Some(f) = funcs::find(&name) {
+            return Some(funcs::signature(f.name, &f.params(), arg));
+        }
+        let low = name.to_lowercase();
+        let f = self.sheets[self.sheet].names.get(&low).or_else(|| self.names.get(&low)).cloned().or_else(|| udf::lookup(&low));
+        match f {
+            Some(Arg::Lambda(_, params, ..)) => {
+                let params: Vec<&str> = params.iter().map(|p| p.as_str()).collect();
+                Some(funcs::signature(&name.to_uppercase(), &params, arg))
+            },
+            _ => None,
+        }
+    }
+    // References of the formula in the editor: their positions in the text, the cells they cover,
+    // and their colors. A repeated reference keeps its color
+    fn edit_refs(&self) -> Vec<(usize, usize, Area, Color)> {
+        match self.sheets[self.sheet].mode {
+            CalcMode::Edit | CalcMode::TempSelect | CalcMode::TempSelectStart => {},
+            _ => return Vec::new(),
+        }
+        let mut areas: Vec<Area> = Vec::new();
+        let mut res = Vec::new();
+        for (start, end, arg) in references(&self.ed_top.text()) {
+            let area = match Area::new(&arg) {
+                None => continue,
+                Some(area) => area,
+            };
+            let idx = match areas.iter().position(|a| *a == area) {
+                Some(idx) => idx,
+                None => {
+                    areas.push(area.clone());
+                    areas.len() - 1
+                },
+            };
+            res.push((start, end, area, REF_COLORS[idx % REF_COLORS.len()]));
+        }
+        res
+    }
     fn show_info(&self, ctx: &Context, scr: &mut Screen) -> Result<()> {
         if let Some(msg) = &self.err {
             scr.colors(Color::Red, Color::Black); // TODO:
@@ -128,7 +206,11 @@ impl Calc {
         let (col, row) = (sheet.cursor.col, sheet.cursor.row);
         let cell = sheet.cell(col, row);
         let addr = format!("{}", sheet.selected_range());
-        let title = format!("[{}][{}]{}", sheet.name, addr, cell.val);
+        let title = if cell.is_err() && !cell.reason.is_empty() {
+            format!("[{}][{}]{} [{}: {}]", sheet.name, addr, cell.val, cell.title(), cell.reason)
+        } else {
+            format!("[{}][{}]{}", sheet.name, addr, cell.val)
+        };
         let w = title.width();
         let title = title + &" ".repeat(self.w as usize - w);
         scr.colors(Color::White, Color::Black); // TODO:
@@ -154,15 +236,25 @@ impl Calc {
         let has_fixed_col = sheet.is_col_fixed();
         let from = if has_fixed_row { sheet.first_row+sheet.fixed_rows } else { sheet.first_row };
         let row_num_w = DEF_NUM_WIDTH; // TODO: support more than 10000 rows
+        // cells the edited formula refers to are tinted with the colors of its references
+        let refs = self.edit_refs();
+        let page = sheet.name.to_lowercase();
+        let cell_colors = |c: usize, r: usize| {
+            let attr = sheet.cell_attr(c, r);
+            match refs.iter().find(|(_, _, area, _)| area.contains(&page, true, c, r)) {
+                Some(&(_, _, _, color)) if !sheet.is_under_cursor(c, r) => (Color::White, color),
+                _ => (attr.fg, attr.bg),
+            }
+        };
         if has_fixed_row {
             for r in 0..sheet.fixed_rows {
                 let mut colpos = row_num_w;
                 if has_fixed_col {
                     for c in 0..sheet.fixed_cols {
                         let cwidth = sheet.col_width(c);
-                        let attr = sheet.cell_attr(c, r);
-                        let cell = sheet.cell(c, r);
-                        scr.colors(attr.fg, attr.bg);
+                        let (fg, bg) = cell_colors(c, r);
+                        let cell = sheet.shown_cell(c, r);
+                        scr.colors(fg, bg);
                         let align = cell.align();
                         let mut title = cell.title();
                         let l = title.width();
@@ -190,9 +282,9 @@ impl Calc {
                 }
                 for c in sheet.first_col..MAX_COLS {
                     let cwidth = sheet.col_width(c);
-                    let attr = sheet.cell_attr(c, r);
-                    let cell = sheet.cell(c, r);
-                    scr.colors(attr.fg, attr.bg);
+                    let (fg, bg) = cell_colors(c, r);
+                    let cell = sheet.shown_cell(c, r);
+                    scr.colors(fg, bg);
                     let align = cell.align();
                     let mut title = cell.title();
                     let l = title.width();
@@ -229,9 +321,9 @@ impl Calc {
             if has_fixed_col {
                 for c in 0..sheet.fixed_cols {
                     let cwidth = sheet.col_width(c);
-                    let attr = sheet.cell_attr(c, r);
-                    let cell = sheet.cell(c, r);
-                    scr.colors(attr.fg, attr.bg);
+                    let (fg, bg) = cell_colors(c, r);
+                    let cell = sheet.shown_cell(c, r);
+                    scr.colors(fg, bg);
                     let align = cell.align();
                     let mut title = cell.title();
                     let l = title.width();
@@ -257,9 +349,9 @@ impl Calc {
             }
             for c in sheet.first_col..MAX_COLS {
                 let cwidth = sheet.col_width(c);
-                let attr = sheet.cell_attr(c, r);
-                let cell = sheet.cell(c, r);
-                scr.colors(attr.fg, attr.bg);
+                let (fg, bg) = cell_colors(c, r);
+                let cell = sheet.shown_cell(c, r);
+                scr.colors(fg, bg);
                 let align = cell.align();
                 let mut title = cell.title();
                 let l = title.width();
@@ -335,12 +427,20 @@ impl Calc {
                 Transition::None
             },
             CalcMode::Edit => {
+                // a formula with a syntax error stays in the editor with the cursor at the mistake
+                let text = self.ed_top.text();
+                if let Some(e) = syntax_error(&text) {
+                    self.ed_top.set_cursor(e.pos);
+                    self.err = Some(e.to_string());
+                    return Transition::None;
+                }
                 let sheet = &mut self.sheets[self.sheet];
                 sheet.mode = CalcMode::Move;
                 self.ed_top.on_deactivate();
                 let (col, row) = (sheet.cursor.col, sheet.cursor.row);
                 info!("--> save text {} to {}x{}", self.ed_top.text(), col, row);
-                sheet.set_cell_text(col, row, &self.ed_top.text(), true);
+                sheet.set_cell_text(col, row, &self.ed_top.text(), false);
+                self.recalc();
                 Transition::None
             },
             CalcMode::Select => {
@@ -378,6 +478,28 @@ impl Calc {
             },
         }
     }
+    // Tab in the editor completes the word before the cursor. The only match is inserted at once,
+    // several ones are shown in a popup
+    fn complete_word(&mut self) -> Transition {
+        let before: String = self.ed_top.text().chars().take(self.ed_top.cursor()).collect();
+        let sheet = &self.sheets[self.sheet];
+        let names: Vec<String> = sheet.names.iter().chain(self.names.iter())
+            .map(|(name, val)| if let Arg::Lambda(..) = val { format!("{}(", name) } else { name.clone() })
+            .collect();
+        let pages: Vec<String> = self.sheets.iter().map(|s| s.name.clone()).collect();
+        match complete(&before, &names, &pages) {
+            None => Transition::None,
+            Some((start, mut items)) if items.len() == 1 => {
+                self.ed_top.replace_before_cursor(start, &items.remove(0));
+                Transition::None
+            },
+            Some((start, items)) => {
+                let (col, row) = self.ed_top.cursor_screen_pos();
+                self.completion = Some((start, items.clone()));
+                Transition::Push(Dialog::Popup(PopupArgs { items, col, row }))
+            },
+        }
+    }
     fn process_event_inner(&mut self, ctx: &Context, scr: &mut Screen, event: Event) -> Result<Transition> {
         // TODO: redesign: items are not needed always, but the next 'sheet' takes mutable reference
         let mut items: Vec<String> = Vec::new();
@@ -434,6 +556,7 @@ impl Calc {
                     KeyCode::Delete => if ev.modifiers == KeyModifiers::NONE {
                         sheet.clear_range();
                         sheet.cancel_select();
+                        self.recalc();
                         Transition::None
                     } else {
                         Transition::EventPass
@@ -484,6 +607,7 @@ impl Calc {
                             Transition::Push(Dialog::PageList(msg))
                         } else if ev.modifiers == KeyModifiers::NONE {
                             sheet.paste_yanked();
+                            self.recalc();
                             Transition::None
                         } else {
                             sheet.cancel_select();
@@ -499,6 +623,7 @@ impl Calc {
                             // TODO: display info that something was yanked
                             sheet.yank(true);
                             sheet.cancel_select();
+                            self.recalc();
                             Transition::None
                         },
                         'v' if ev.modifiers == KeyModifiers::NONE => {
@@ -527,6 +652,121 @@ impl Calc {
         self.sheets = vec![Sheet::new(0, self.w, self.h)];
         self.sheet = 0;
         self.gen = 0;
+        self.seed = None;
+        self.iteration = None;
+        self.names.clear();
+    }
+    // Sets the random seed for all pages and recalculates them
+    fn set_seed(&mut self, seed: Option<u64>) {
+        self.seed = seed;
+        for sheet in self.sheets.iter_mut() {
+            sheet.seed = seed;
+        }
+        self.recalc_all();
+    }
+    // Sets iterative calculation for all pages and recalculates them
+    fn set_iteration(&mut self, iteration: Option<Iteration>) {
+        self.iteration = iteration;
+        for sheet in self.sheets.iter_mut() {
+            sheet.iteration = iteration;
+        }
+        self.recalc_all();
+    }
+    // Formulas may refer to other pages, so changed cells are followed on all pages
+    fn recalc(&mut self) {
+        Book::with_names(&mut self.sheets, self.sheet, &self.names).recalc_changed();
+    }
+    fn recalc_all(&mut self) {
+        Book::with_names(&mut self.sheets, self.sheet, &self.names).recalc();
+    }
+    // name add [page] <name> [value] | name list | name rename <old> <new> | name del <name>
+    // `args` are not lowercased: names are case-insensitive, but the value keeps the case of its text
+    fn name_command(&mut self, args: &str) {
+        let (args, sub) = self.parse_cmd_any_str(args);
+        let page = self.sheets[self.sheet].name.clone();
+        match sub.to_lowercase().as_str() {
+            "add" => {
+                let (args, scope) = self.parse_cmd_one_of(args, |s| s.eq_ignore_ascii_case("page"));
+                let (value, name) = self.parse_cmd_any_str(args);
+                let name = name.to_lowercase();
+                let name = name.as_str();
+                let sheet = &mut self.sheets[self.sheet];
+                // without a value the name points to the selected cells
+                let val = if value.is_empty() {
+                    sheet.parse_name_value(&format!("{}", sheet.selected_range()))
+                } else {
+                    sheet.parse_name_value(value)
+                };
+                // a function is called with brackets, so its name can look like a column
+                let valid = match val {
+                    Arg::Lambda(..) => matches!(parse_ident(name), ("", ref id) if !id.is_empty()),
+                    _ => is_name(name),
+                };
+                if !valid {
+                    self.err = Some(format!("invalid name '{}'", name));
+                    return;
+                }
+                if scope.is_empty() {
+                    self.names.insert(name.to_string(), val);
+                } else {
+                    sheet.names.insert(name.to_string(), val);
+                }
+                sheet.dirty = true;
+            },
+            "list" => {
+                let mut items: Vec<String> = self.sheets[self.sheet].names.iter()
+                    .map(|(n, v)| format!("{}={} ({})", n, v.to_expr(), page)).collect();
+                items.extend(self.names.iter().map(|(n, v)| format!("{}={}", n, v.to_expr())));
+                self.err = Some(if items.is_empty() { String::from("no names") } else { items.join(", ") });
+                return;
+            },
+            "rename" => {
+                let (args, old) = self.parse_cmd_any_str(args);
+                let (_, new) = self.parse_cmd_any_str(args);
+                let (old, new) = (old.to_lowercase(), new.to_lowercase());
+                let (old, new) = (old.as_str(), new.as_str());
+                if !is_name(new) {
+                    self.err = Some(format!("invalid name '{}'", new));
+                    return;
+                }
+                let on_page = self.sheets[self.sheet].names.contains_key(old);
+                let names = if on_page { &mut self.sheets[self.sheet].names } else { &mut self.names };
+                if names.contains_key(new) {
+                    self.err = Some(format!("name '{}' already exists", new));
+                    return;
+                }
+                match names.remove(old) {
+                    None => {
+                        self.err = Some(format!("unknown name '{}'", old));
+                        return;
+                    },
+                    Some(val) => names.insert(new.to_string(), val),
+                };
+                // formulas of pages with the same page name do not read the workbook one
+                for (idx, sheet) in self.sheets.iter_mut().enumerate() {
+                    if (on_page && idx == self.sheet) || (!on_page && !sheet.names.contains_key(old)) {
+                        sheet.rename_in_formulas(old, new);
+                    }
+                }
+                self.sheets[self.sheet].dirty = true;
+            },
+            "del" | "delete" => {
+                let (_, name) = self.parse_cmd_any_str(args);
+                let name = name.to_lowercase();
+                let name = name.as_str();
+                let sheet = &mut self.sheets[self.sheet];
+                if sheet.names.remove(name).is_none() && self.names.remove(name).is_none() {
+                    self.err = Some(format!("unknown name '{}'", name));
+                    return;
+                }
+                sheet.dirty = true;
+            },
+            _ => {
+                self.err = Some(String::from("command format: name add [page] <name> [value]|list|rename <old> <new>|del <name>"));
+                return;
+            },
+        }
+        self.recalc_all();
     }
 
     fn parse_cmd_skip_white<'a>(&self, cmd: &'a str) -> &'a str {
@@ -573,7 +813,8 @@ impl Calc {
     }
     // Returns true if the application must be closed
     fn run_command(&mut self, args: &str) -> Transition { // true if app must close // TODO: enum?
-        let lowcase = args.trim().to_lowercase();
+        let text = args.trim();
+        let lowcase = text.to_lowercase();
         let args = lowcase.as_str();
         let (args, command) = self.parse_cmd_any_str(args);
         match command {
@@ -680,11 +921,62 @@ impl Calc {
                 }
                 let mut sheet = Sheet::new(idx, self.w, self.h);
                 sheet.dirty = true;
+                sheet.seed = self.seed;
+                sheet.iteration = self.iteration;
                 if !name.is_empty() {
                     sheet.name = name.to_string();
                 }
                 self.sheets.push(sheet);
                 self.sheet = self.sheets.len() - 1;
+                self.recalc_all();
+            },
+            "seed" => {
+                let (args, seed) = self.parse_cmd_int(args);
+                match seed {
+                    Some(n) => self.set_seed(Some(n as u64)),
+                    None => if args.is_empty() || args == "off" {
+                        self.set_seed(None);
+                    } else {
+                        self.err = Some(String::from("command format: seed [number|off]"));
+                        return Transition::None;
+                    },
+                }
+                for sheet in self.sheets.iter_mut() {
+                    sheet.dirty = true;
+                }
+            },
+            "iterate" => {
+                const FORMAT: &str = "command format: iterate [count [tolerance]]|off";
+                if args == "off" {
+                    self.set_iteration(None);
+                } else {
+                    let mut it = Iteration::default();
+                    let (args, cnt) = self.parse_cmd_int(args);
+                    if let Some(n) = cnt {
+                        it.max = n;
+                    }
+                    if !args.is_empty() {
+                        match args.parse::<f64>() {
+                            Ok(f) if cnt.is_some() && f >= 0.0 => it.tolerance = f,
+                            _ => {
+                                self.err = Some(String::from(FORMAT));
+                                return Transition::None;
+                            },
+                        }
+                    }
+                    if it.max == 0 {
+                        self.err = Some(String::from(FORMAT));
+                        return Transition::None;
+                    }
+                    self.set_iteration(Some(it));
+                }
+                for sheet in self.sheets.iter_mut() {
+                    sheet.dirty = true;
+                }
+            },
+            "name" => {
+                let (args, _) = self.parse_cmd_any_str(text);
+                self.name_command(args);
             },
             "insert" => {
                 let args = args.trim();
@@ -712,10 +1004,21 @@ impl Calc {
                     from += 1;
                 }
                 info!("inserting {} {}s from {}", cnt, what, from);
-                match what {
-                    "row" => sheet.insert_rows(from, cnt, after),
-                    _ => sheet.insert_cols(from, cnt, after),
+                let (dcol, drow, bcol, brow) = match what {
+                    "row" => {
+                        sheet.insert_rows(from, cnt);
+                        (0, cnt as isize, MAX_COLS, from)
+                    },
+                    _ => {
+                        sheet.insert_cols(from, cnt);
+                        (cnt as isize, 0, from, MAX_ROWS)
+                    },
+                };
+                if cnt != 0 {
+                    shift_names(&mut self.names, &sheet.name, dcol, drow, bcol, brow);
+                    Book::new(&mut self.sheets, self.sheet).shift_page_refs(self.sheet, dcol, drow, bcol, brow);
                 }
+                self.recalc_all();
             },
             "delete" => {
                 let args = args.trim();
@@ -743,10 +1046,21 @@ impl Calc {
                     from += 1;
                 }
                 info!("deleting {} {}s from {}", cnt, what, from);
-                match what {
-                    "row" => sheet.delete_rows(from, cnt, after),
-                    _ => sheet.delete_cols(from, cnt, after),
+                let (dcol, drow, bcol, brow) = match what {
+                    "row" => {
+                        sheet.delete_rows(from, cnt);
+                        (0, -(cnt as isize), MAX_COLS, from+cnt)
+                    },
+                    _ => {
+                        sheet.delete_cols(from, cnt);
+                        (-(cnt as isize), 0, from+cnt, MAX_ROWS)
+                    },
+                };
+                if cnt != 0 {
+                    shift_names(&mut self.names, &sheet.name, dcol, drow, bcol, brow);
+                    Book::new(&mut self.sheets, self.sheet).shift_page_refs(self.sheet, dcol, drow, bcol, brow);
                 }
+                self.recalc_all();
             },
             _ => {
                 self.err = Some(format!("invalid command '{}'", command));
@@ -763,9 +1077,13 @@ impl Calc {
         serialize_into(&f, &self.sheet)?;
         let reserv = 0usize;
         serialize_into(&f, &reserv)?;
+        serialize_into(&f, &self.seed)?;
+        let iteration = self.iteration.map(|it| (it.max as u64, it.tolerance));
+        serialize_into(&f, &iteration)?;
         for sheet in &self.sheets {
             sheet.save(&f)?;
         }
+        save_names(&f, &self.names)?;
         for sheet in self.sheets.iter_mut() {
             sheet.dirty = false;
         }
@@ -775,7 +1093,7 @@ impl Calc {
         let f = File::open(path)?;
         let mut calc = Calc::default();
         let v: u16 = deserialize_from(&f)?;
-        if v != VERSION {
+        if v == 0 || v > VERSION {
             return Err(anyhow!("unsupported version {}. Expected {}", v, VERSION)); // TODO:
         }
         let sheets: usize = deserialize_from(&f)?;
@@ -790,14 +1108,23 @@ impl Calc {
         if reserv != 0usize { // TODO:
             return Err(anyhow!("reserved field must be 0"));
         }
+        let seed: Option<u64> = if v >= 2 { deserialize_from(&f)? } else { None };
+        let iteration: Option<(u64, f64)> = if v >= 3 { deserialize_from(&f)? } else { None };
         for _i in 0..sheets {
             let mut sheet = Sheet::load(&f, self.w, self.h, v)?;
             sheet.ensure_visible_col();
             sheet.ensure_visible_row();
             calc.sheets.push(sheet);
         }
+        let names = if v >= 4 { load_names(&f, &calc.sheets[0])? } else { Names::new() };
         self.sheet = calc.sheet;
         self.sheets = calc.sheets;
+        self.names = names;
+        self.iteration = iteration.map(|(max, tolerance)| Iteration { max: max as usize, tolerance });
+        for sheet in self.sheets.iter_mut() {
+            sheet.iteration = self.iteration;
+        }
+        self.set_seed(seed);
         Ok(())
     }
     fn is_dirty(&self) -> bool {
@@ -816,7 +1143,16 @@ impl Widget for Calc {
         self.draw_cells(ctx, scr)?;
         self.draw_mode(ctx, scr)?;
         match  self.sheets[self.sheet].mode {
-            CalcMode::Edit | CalcMode::TempSelect | CalcMode::TempSelectStart => self.ed_top.draw(ctx, scr),
+            CalcMode::Edit | CalcMode::TempSelect | CalcMode::TempSelectStart => {
+                self.ed_top.draw(ctx, scr)?;
+                let parts: Vec<(usize, usize, Color)> = self.edit_refs().into_iter().map(|(start, end, _, color)| (start, end, color)).collect();
+                self.ed_top.draw_colored(scr, &parts);
+                if self.err.is_some() {
+                    self.show_edit_error(ctx, scr)
+                } else {
+                    self.show_signature(ctx, scr)
+                }
+            },
             CalcMode::Command => self.ed_bottom.draw(ctx, scr),
             _ => self.show_info(ctx, scr),
         }
@@ -824,7 +1160,10 @@ impl Widget for Calc {
     fn process_event(&mut self, ctx: &Context, scr: &mut Screen, event: Event) -> Result<Transition> {
         let mode = self.sheets[self.sheet].mode;
         let ev = if let CalcMode::Edit = mode {
-            self.ed_top.process_event(ctx, scr, event)?
+            match event {
+                Event::Key(ev) if ev.code == KeyCode::Tab => self.complete_word(),
+                _ => self.ed_top.process_event(ctx, scr, event)?,
+            }
         } else if let CalcMode::Command = mode {
             self.ed_bottom.process_event(ctx, scr, event)?
         } else {
@@ -868,6 +1207,14 @@ impl Widget for Calc {
                         self.sheet = id;
                         Ok(Transition::None)
                     },
+                    Command::Item(idx) => {
+                        if let Some((start, items)) = self.completion.take() {
+                            if let Some(item) = items.get(idx) {
+                                self.ed_top.replace_before_cursor(start, item);
+                            }
+                        }
+                        Ok(Transition::None)
+                    },
                     _ => Err(anyhow!("unsupported command: {:?}", cmd)),
                 }
             },
@@ -0,0 +1,207 @@
+use std::cmp::Ordering;
+
+use anyhow::{anyhow, Result};
+
+use crate::ops::Arg;
+use crate::parse::parse_float;
+use crate::datetime::{parse_date, parse_time};
+
+// Conversions and ordering of cell values shared by operators, functions, and criteria.
+//
+// A blank is zero in arithmetic, an empty text in text functions, and FALSE in logical ones.
+// When a blank is compared with a value, it is the empty value of that value's type:
+// 0, "", or FALSE. Values of different types are never equal, and they are ordered
+// numbers < text < booleans. Text is compared case-insensitively.
+
+// Dates and times are compared as numbers
+pub fn date_to_num(a: Arg) -> Arg {
+    match a {
+        Arg::Date(n) | Arg::Time(n) => Arg::Number(n),
+        _ => a,
+    }
+}
+
+pub fn try_to_num(a: Arg) -> Result<f64> {
+    match a {
+        Arg::End => Ok(0.0),
+        Arg::Str(s) => {
+            if s.is_empty() {
+                return Ok(0.0);
+            }
+            if let Ok(f) = str_to_num(&s) {
+                Ok(f)
+            } else if let Some(d) = parse_date(&s) {
+                Ok(d)
+            } else if let Some(t) = parse_time(&s) {
+                Ok(t)
+            } else {
+                Err(anyhow!("cannot convert {} to a number", s))
+            }
+        },
+        Arg::Number(n) | Arg::Date(n) | Arg::Time(n) => Ok(n),
+        Arg::Bool(b) => Ok(if b { 1.0 } else { 0.0 }),
+        Arg::Err(kind) => Err(kind.into()),
+        _ => Err(anyhow!("failed to convert to a number")),
+    }
+}
+
+// Booleans are TRUE and FALSE, as they are shown in cells
+pub fn try_to_str(a: &Arg) -> Result<String> {
+    match a {
+        Arg::End => Ok(String::new()),
+        Arg::Str(s) => Ok(s.to_string()),
+        Arg::Number(_) | Arg::Bool(_) | Arg::Date(_) | Arg::Time(_) => Ok(a.title()),
+        Arg::Err(kind) => Err((*kind).into()),
+        _ => Err(anyhow!("failed to convert to a string")),
+    }
+}
+
+// Text is a boolean only if it is empty, TRUE, or FALSE
+pub fn try_to_bool(a: Arg) -> Result<bool> {
+    match a {
+        Arg::End => Ok(false),
+        Arg::Str(s) => {
+            if s.is_empty() || s.eq_ignore_ascii_case("false") {
+                Ok(false)
+            } else if s.eq_ignore_ascii_case("true") {
+                Ok(true)
+            } else {
+                Err(anyhow!("cannot convert {} to a boolean", s))
+            }
+        },
+        Arg::Number(n) | Arg::Date(n) | Arg::Time(n) => Ok(n != 0.0),
+        Arg::Bool(b) => Ok(b),
+        Arg::Err(kind) => Err(kind.into()),
+        _ => Err(anyhow!("failed to convert to a boolean")),
+    }
+}
+
+// Converts text to a number: surrounding spaces, a sign and a trailing percent sign are allowed
+pub fn str_to_num(s: &str) -> Result<f64> {
+    let t = s.trim();
+    let (t, pct) = match t.strip_suffix('%') {
+        Some(t) => (t.trim_end(), true),
+        None => (t, false),
+    };
+    let (body, neg) = match t.strip_prefix('-') {
+        Some(b) => (b, true),
+        None => (t.strip_prefix('+').unwrap_or(t), false),
+    };
+    let body = if body.starts_with('.') { format!("0{}", body) } else { body.to_string() };
+    let (rest, mut n) = parse_float(&body)?;
+    if !rest.is_empty() {
+        return Err(anyhow!("cannot convert {} to a number", s));
+    }
+    if neg {
+        n = -n;
+    }
+    if pct {
+        n /= 100.0;
+    }
+    Ok(n)
+}
+
+fn rank(v: &Arg) -> u8 {
+    match v {
+        Arg::Number(_) | Arg::Date(_) | Arg::Time(_) => 0,
+        Arg::Str(_) => 1,
+        Arg::Bool(_) => 2,
+        Arg::End => 4,
+        _ => 3,
+    }
+}
+
+// The empty value of the other operand's type replaces a blank
+fn blank_as(v: &Arg, other: &Arg) -> Arg {
+    match (v, other) {
+        (Arg::End, Arg::Str(_)) => Arg::Str(String::new()),
+        (Arg::End, Arg::Bool(_)) => Arg::Bool(false),
+        (Arg::End, Arg::End) => Arg::End,
+        (Arg::End, _) => Arg::Number(0.0),
+        (v, _) => date_to_num(v.clone()),
+    }
+}
+
+pub fn text_cmp(a: &str, b: &str) -> Ordering {
+    a.to_lowercase().cmp(&b.to_lowercase())
+}
+
+// Order of values of the same type, None for values of different types
+pub fn same_type_cmp(a: &Arg, b: &Arg) -> Option<Ordering> {
+    match (&date_to_num(a.clone()), &date_to_num(b.clone())) {
+        (Arg::Number(x), Arg::Number(y)) => Some(x.partial_cmp(y).unwrap_or(Ordering::Equal)),
+        (Arg::Str(x), Arg::Str(y)) => Some(text_cmp(x, y)),
+        (Arg::Bool(x), Arg::Bool(y)) => Some(x.cmp(y)),
+        (Arg::End, Arg::End) => Some(Ordering::Equal),
+        (Arg::Err(x), Arg::Err(y)) => Some(x.number().cmp(&y.number())),
+        _ => None,
+    }
+}
+
+// Order used by comparison operators: a blank takes the other operand's type,
+// then values of different types are ordered by type
+pub fn compare(a: &Arg, b: &Arg) -> Ordering {
+    let (a, b) = (blank_as(a, b), blank_as(b, a));
+    same_type_cmp(&a, &b).unwrap_or_else(|| rank(&a).cmp(&rank(&b)))
+}
+
+// Order of sorting: values of different types are ordered by type, errors after booleans.
+// Blanks go last in both ascending and descending order
+pub fn sort_cmp(a: &Arg, b: &Arg, descending: bool) -> Ordering {
+    match (a, b) {
+        (Arg::End, Arg::End) => Ordering::Equal,
+        (Arg::End, _) => Ordering::Greater,
+        (_, Arg::End) => Ordering::Less,
+        _ => {
+            let ord = rank(a).cmp(&rank(b)).then_with(|| same_type_cmp(a, b).unwrap_or(Ordering::Equal));
+            if descending { ord.reverse() } else { ord }
+        },
+    }
+}
+
+#[rustfmt::skip]
+#[cfg(test)]
+mod coerce_test {
+    use super::*;
+
+    #[test]
+    fn compare_test() {
+        let s = |v: &str| Arg::Str(v.to_string());
+        let tests: Vec<(Arg, Arg, Ordering)> = vec![
+            (Arg::Number(1.0), Arg::Number(2.0), Ordering::Less),
+            (Arg::Number(100.0), s("2"), Ordering::Less),
+            (s("zzz"), Arg::Bool(false), Ordering::Less),
+            (Arg::Number(1.0), Arg::Bool(true), Ordering::Less),
+            (s("abc"), s("ABC"), Ordering::Equal),
+            (s("b"), s("A"), Ordering::Greater),
+            (Arg::End, Arg::Number(0.0), Ordering::Equal),
+            (Arg::End, Arg::Number(-1.0), Ordering::Greater),
+            (Arg::End, s(""), Ordering::Equal),
+            (Arg::End, s("a"), Ordering::Less),
+            (Arg::End, Arg::Bool(false), Ordering::Equal),
+            (Arg::Bool(true), Arg::End, Ordering::Greater),
+            (Arg::End, Arg::End, Ordering::Equal),
+            (Arg::Date(45000.0), Arg::Number(45000.0), Ordering::Equal),
+        ];
+        for (a, b, res) in tests {
+            assert_eq!(compare(&a, &b), res, "{:?} vs {:?}", a, b);
+            assert_eq!(compare(&b, &a), res.reverse(), "{:?} vs {:?}", b, a);
+        }
+        assert_eq!(sort_cmp(&Arg::End, &Arg::Number(-1.0), false), Ordering::Greater);
+        assert_eq!(sort_cmp(&Arg::End, &Arg::Number(-1.0), true), Ordering::Greater);
+        assert_eq!(sort_cmp(&Arg::Bool(false), &Arg::Err(crate::ops::ErrKind::NA), false), Ordering::Less);
+    }
+
+    #[test]
+    fn convert_test() {
+        let s = |v: &str| Arg::Str(v.to_string());
+        assert_eq!(try_to_num(s(" 5% ")).unwrap(), 0.05);
+        assert_eq!(try_to_num(Arg::End).unwrap(), 0.0);
+        assert!(try_to_num(s("five")).is_err());
+        assert_eq!(try_to_str(&Arg::Bool(true)).unwrap(), "TRUE");
+        assert_eq!(try_to_str(&Arg::End).unwrap(), "");
+        assert!(try_to_bool(s("True")).unwrap());
+        assert!(!try_to_bool(Arg::End).unwrap());
+        assert!(try_to_bool(s("yes")).is_err());
+    }
+}
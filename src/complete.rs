@@ -0,0 +1,133 @@
+use crate::funcs;
+use crate::parse::{is_ident_cont, is_ident_start, is_sheet_name};
+use crate::udf;
+
+// Completion of the word before the cursor of a formula. `before` is the text before the cursor,
+// `names` are names visible to the formula with '(' after the ones that are functions.
+// Returns the position(in characters) where the word starts and its replacements:
+// built-in and user-defined functions, names, and pages
+pub fn complete(before: &str, names: &[String], pages: &[String]) -> Option<(usize, Vec<String>)> {
+    if !before.starts_with('=') {
+        return None;
+    }
+    let chars: Vec<char> = before.chars().collect();
+    let count = |ch: char| chars.iter().filter(|&&c| c == ch).count();
+    // text is not completed
+    if count('"') % 2 == 1 {
+        return None;
+    }
+    // a quoted page name: ='my pa
+    if count('\'') % 2 == 1 {
+        let start = chars.iter().rposition(|&c| c == '\'')?;
+        let prefix: String = chars[start+1..].iter().collect();
+        let mut items: Vec<String> = pages.iter().filter(|p| starts_with(p, &prefix)).map(|p| format!("'{}'!", p)).collect();
+        items.sort_by_key(|item| item.to_lowercase());
+        return if items.is_empty() { None } else { Some((start, items)) };
+    }
+    let mut start = chars.len();
+    while start > 0 && is_ident_cont(chars[start-1]) {
+        start -= 1;
+    }
+    if start == chars.len() || !is_ident_start(chars[start]) {
+        return None;
+    }
+    // a column of a fixed reference or a reference to another page: $B, page!A
+    if matches!(chars[start-1], '$' | '!') {
+        return None;
+    }
+    let prefix: String = chars[start..].iter().collect();
+    let page_ref = |p: &String| if p.chars().all(is_sheet_name) { format!("{}!", p) } else { format!("'{}'!", p) };
+    let mut items: Vec<String> = funcs::names().map(|f| format!("{}(", f))
+        .chain(udf::names().into_iter().map(|f| format!("{}(", f.to_uppercase())))
+        .chain(names.iter().cloned())
+        .chain(pages.iter().map(page_ref))
+        .filter(|item| starts_with(item.trim_start_matches('\''), &prefix))
+        .collect();
+    items.sort_by_key(|item| item.to_lowercase());
+    items.dedup_by(|a, b| a.eq_ignore_ascii_case(b));
+    if items.is_empty() { None } else { Some((start, items)) }
+}
+
+// Function call the cursor is in: the function name and the index of the argument.
+// `before` is the text before the cursor
+pub fn current_call(before: &str) -> Option<(String, usize)> {
+    // open brackets: the function before the bracket(empty for a group) and the number of commas
+    let mut calls: Vec<(String, usize)> = Vec::new();
+    let mut ident = String::new();
+    let mut quote: Option<char> = None;
+    for c in before.chars() {
+        if let Some(q) = quote {
+            if c == q {
+                quote = None;
+            }
+            continue;
+        }
+        match c {
+            '"' | '\'' => quote = Some(c),
+            '(' => calls.push((std::mem::take(&mut ident), 0)),
+            ')' => { calls.pop(); },
+            ',' => if let Some(call) = calls.last_mut() {
+                call.1 += 1;
+            },
+            c if is_ident_cont(c) => {
+                ident.push(c);
+                continue;
+            },
+            _ => {},
+        }
+        ident.clear();
+    }
+    calls.into_iter().rev().find(|(name, _)| !name.is_empty())
+}
+
+fn starts_with(s: &str, prefix: &str) -> bool {
+    s.to_lowercase().starts_with(&prefix.to_lowercase())
+}
+
+#[rustfmt::skip]
+#[cfg(test)]
+mod complete_test {
+    use super::*;
+
+    #[test]
+    fn complete_test() {
+        let names = vec!["taxrate".to_string(), "tax_free".to_string(), "vat(".to_string()];
+        let pages = vec!["Page1".to_string(), "page 2".to_string(), "Vlogs".to_string()];
+        // no items means that nothing is completed
+        let tests: Vec<(&str, usize, Vec<&str>)> = vec![
+            ("=VLO", 1, vec!["Vlogs!", "VLOOKUP("]),
+            ("=1+sum(tax", 7, vec!["tax_free", "taxrate"]),
+            ("=SUMIF", 1, vec!["SUMIF(", "SUMIFS("]),
+            ("=va", 1, vec!["VALUE(", "VAR(", "VAR.P(", "VAR.S(", "vat("]),
+            ("='pa", 1, vec!["'page 2'!", "'Page1'!"]),
+            ("=pa", 1, vec!["'page 2'!", "Page1!"]),
+            ("=SUM(Page1!A", 0, vec![]),
+            ("=$A", 0, vec![]),
+            ("=\"VLO", 0, vec![]),
+            ("=12", 0, vec![]),
+            ("=QQ", 0, vec![]),
+            ("VLO", 0, vec![]),
+        ];
+        for (before, start, items) in tests {
+            let res = if items.is_empty() { None } else { Some((start, items.iter().map(|s| s.to_string()).collect())) };
+            assert_eq!(complete(before, &names, &pages), res, "{}", before);
+        }
+    }
+
+    #[test]
+    fn current_call_test() {
+        let tests: Vec<(&str, Option<(&str, usize)>)> = vec![
+            ("=SUMIF(", Some(("SUMIF", 0))),
+            ("=SUMIF(A1:A9, \">5\", ", Some(("SUMIF", 2))),
+            ("=IF(A1, SUM(1, 2), (3", Some(("IF", 2))),
+            ("=IF(A1, \"a,(b\"", Some(("IF", 1))),
+            ("=SUM('page, 2'!A1, ", Some(("SUM", 1))),
+            ("=ERROR.TYPE(", Some(("ERROR.TYPE", 0))),
+            ("=SUM(1) + 2", None),
+            ("=(1+", None),
+        ];
+        for (before, res) in tests {
+            assert_eq!(current_call(before), res.map(|(f, n)| (f.to_string(), n)), "{}", before);
+        }
+    }
+}
@@ -0,0 +1,128 @@
+use std::cmp::Ordering;
+
+use crate::ops::Arg;
+use crate::datetime::{parse_date, parse_time};
+use crate::coerce::{date_to_num, str_to_num, same_type_cmp};
+use crate::parse::parse_arg;
+use crate::strs;
+
+// Condition used by SUMIF, COUNTIF and similar functions: an optional comparison
+// operator followed by a value, e.g. ">=10", "<>done", "apple*"
+#[derive(Debug,Clone,PartialEq)]
+pub struct Criteria {
+    op: String,
+    val: Arg,
+}
+
+impl Criteria {
+    pub fn new(arg: &Arg) -> Criteria {
+        let s = match arg {
+            Arg::Str(s) => s,
+            Arg::Number(_) | Arg::Bool(_) | Arg::Date(_) | Arg::Time(_) => {
+                return Criteria { op: "=".to_string(), val: date_to_num(arg.clone()) };
+            },
+            _ => return Criteria { op: "=".to_string(), val: Arg::End },
+        };
+        match parse_arg(s) {
+            Ok((rest, Arg::Eq(op))) => Criteria { op, val: criteria_value(rest) },
+            _ => Criteria { op: "=".to_string(), val: criteria_value(s) },
+        }
+    }
+
+    pub fn matches(&self, val: &Arg) -> bool {
+        // a failed cell matches nothing, not even "<>"
+        if let Arg::Err(_) = val {
+            return false;
+        }
+        let is_blank = match val {
+            Arg::End => true,
+            Arg::Str(s) => s.is_empty(),
+            _ => false,
+        };
+        let ord = match (&self.val, val) {
+            (Arg::End, _) => return match self.op.as_str() {
+                "=" => is_blank,
+                "<>" => !is_blank,
+                _ => false,
+            },
+            (Arg::Str(c), Arg::Str(v)) if self.op == "=" || self.op == "<>" => {
+                let same = strs::wildcard_match(c, v);
+                return same == (self.op == "=");
+            },
+            // a blank cell matches only criteria for blanks
+            (_, Arg::End) => None,
+            _ => same_type_cmp(val, &self.val),
+        };
+        match ord {
+            None => self.op == "<>",
+            Some(o) => match self.op.as_str() {
+                "=" => o == Ordering::Equal,
+                "<>" => o != Ordering::Equal,
+                "<" => o == Ordering::Less,
+                "<=" => o != Ordering::Greater,
+                ">" => o == Ordering::Greater,
+                ">=" => o != Ordering::Less,
+                _ => false,
+            },
+        }
+    }
+}
+
+// The value part of a criteria string: a number, a boolean, or a text
+fn criteria_value(s: &str) -> Arg {
+    if s.is_empty() {
+        return Arg::End;
+    }
+    match s.to_lowercase().as_str() {
+        "true" => return Arg::Bool(true),
+        "false" => return Arg::Bool(false),
+        _ => {},
+    }
+    if let Some(d) = parse_date(s).or_else(|| parse_time(s)) {
+        return Arg::Number(d);
+    }
+    match str_to_num(s) {
+        Ok(f) => Arg::Number(f),
+        Err(_) => Arg::Str(s.to_string()),
+    }
+}
+
+#[rustfmt::skip]
+#[cfg(test)]
+mod criteria_test {
+    use super::*;
+    use crate::ops::ErrKind;
+
+    #[test]
+    fn criteria_match_test() {
+        let tests: Vec<(Arg, Arg, bool)> = vec![
+            (Arg::Str(">=10".to_string()), Arg::Number(10.0), true),
+            (Arg::Str(">=10".to_string()), Arg::Number(9.5), false),
+            (Arg::Str(">=10".to_string()), Arg::Str("text".to_string()), false),
+            (Arg::Str("<5".to_string()), Arg::Number(-1.0), true),
+            (Arg::Str(">-2.5".to_string()), Arg::Number(-1.0), true),
+            (Arg::Number(3.0), Arg::Number(3.0), true),
+            (Arg::Str("3".to_string()), Arg::Number(3.0), true),
+            (Arg::Str("<>done".to_string()), Arg::Str("Done".to_string()), false),
+            (Arg::Str("<>done".to_string()), Arg::Str("open".to_string()), true),
+            (Arg::Str("<>done".to_string()), Arg::End, true),
+            (Arg::Str("apple*".to_string()), Arg::Str("Apples".to_string()), true),
+            (Arg::Str("apple*".to_string()), Arg::Str("pineapple".to_string()), false),
+            (Arg::Str("=b?".to_string()), Arg::Str("by".to_string()), true),
+            (Arg::Str(">b".to_string()), Arg::Str("C".to_string()), true),
+            (Arg::Str("".to_string()), Arg::End, true),
+            (Arg::Str("".to_string()), Arg::Number(0.0), false),
+            (Arg::Str("<>".to_string()), Arg::Number(0.0), true),
+            (Arg::Str("<>".to_string()), Arg::End, false),
+            (Arg::Bool(true), Arg::Bool(true), true),
+            (Arg::Str("FALSE".to_string()), Arg::Bool(false), true),
+            (Arg::Str(">=2026-10-01".to_string()), Arg::Date(46312.0), true),
+            (Arg::Str("<2026-10-01".to_string()), Arg::Date(46312.0), false),
+            (Arg::Str("<>done".to_string()), Arg::Err(ErrKind::Div0), false),
+            (Arg::Str("<>".to_string()), Arg::Err(ErrKind::NA), false),
+        ];
+        for (crit, val, res) in tests {
+            assert_eq!(Criteria::new(&crit).matches(&val), res, "{:?} vs {:?}", crit, val);
+        }
+    }
+}
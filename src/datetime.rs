@@ -0,0 +1,202 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Dates are stored as a number of days since 1899-12-30 (compatible with other spreadsheets),
+// the fractional part is the time of day
+const UNIX_EPOCH_SERIAL: i64 = 25569; // 1970-01-01
+const SECS_IN_DAY: f64 = 86400.0;
+
+// Number of days since 1970-01-01 for a date of the proleptic Gregorian calendar
+fn days_from_civil(y: i64, m: u32, d: u32) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let m = m as i64;
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + d as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+fn civil_from_days(z: i64) -> (i64, u32, u32) {
+    let z = z + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+    (y, m, d)
+}
+
+pub fn is_leap_year(y: i64) -> bool {
+    (y % 4 == 0 && y % 100 != 0) || y % 400 == 0
+}
+
+pub fn days_in_month(y: i64, m: u32) -> u32 {
+    match m {
+        2 => if is_leap_year(y) { 29 } else { 28 },
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// Serial number of a date. Month and day out of range roll over to the next or previous month/year
+pub fn date_serial(y: i64, m: i64, d: i64) -> f64 {
+    let months = y * 12 + m - 1;
+    let (y, m) = (months.div_euclid(12), months.rem_euclid(12) as u32 + 1);
+    (days_from_civil(y, m, 1) + d - 1 + UNIX_EPOCH_SERIAL) as f64
+}
+
+// Year, month and day of a serial number. The time part is ignored
+pub fn serial_to_ymd(serial: f64) -> (i64, u32, u32) {
+    civil_from_days(serial.floor() as i64 - UNIX_EPOCH_SERIAL)
+}
+
+// Hours, minutes and seconds of the time part of a serial number
+pub fn serial_to_hms(serial: f64) -> (u32, u32, u32) {
+    let secs = (serial.rem_euclid(1.0) * SECS_IN_DAY).round() as u32 % 86400;
+    (secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+// Adds months to a date. If the day does not exist in the new month, the last day of the month is used
+pub fn add_months(serial: f64, months: i64) -> f64 {
+    let (y, m, d) = serial_to_ymd(serial);
+    let total = y * 12 + m as i64 - 1 + months;
+    let (y, m) = (total.div_euclid(12), total.rem_euclid(12) as u32 + 1);
+    let d = d.min(days_in_month(y, m));
+    date_serial(y, m as i64, d as i64)
+}
+
+// Current date and time at `offset` seconds from UTC
+pub fn now_serial(offset: i64) -> f64 {
+    let secs = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs_f64(),
+        Err(_) => 0.0,
+    };
+    unix_to_serial(secs, offset)
+}
+
+// Converts seconds since 1970-01-01 UTC to a serial number at `offset` seconds from UTC
+pub fn unix_to_serial(secs: f64, offset: i64) -> f64 {
+    (secs + offset as f64) / SECS_IN_DAY + UNIX_EPOCH_SERIAL as f64
+}
+
+// Offset of the local time zone from UTC in seconds, daylight saving time included
+pub fn local_offset() -> i64 {
+    chrono::Local::now().offset().local_minus_utc() as i64
+}
+
+fn parse_num(s: &str, min_len: usize, max_len: usize) -> Option<u32> {
+    if s.len() < min_len || s.len() > max_len || !s.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    s.parse::<u32>().ok()
+}
+
+// Time of day in ISO format: HH:MM or HH:MM:SS. Returns a fraction of a day
+pub fn parse_time(s: &str) -> Option<f64> {
+    let parts: Vec<&str> = s.split(':').collect();
+    if parts.len() < 2 || parts.len() > 3 {
+        return None;
+    }
+    let h = parse_num(parts[0], 1, 2)?;
+    let m = parse_num(parts[1], 2, 2)?;
+    let sec = if parts.len() == 3 { parse_num(parts[2], 2, 2)? } else { 0 };
+    if h > 23 || m > 59 || sec > 59 {
+        return None;
+    }
+    Some((h * 3600 + m * 60 + sec) as f64 / SECS_IN_DAY)
+}
+
+// Date in ISO format: YYYY-MM-DD with optional time separated with 'T' or a space
+pub fn parse_date(s: &str) -> Option<f64> {
+    let (dt, tm) = match s.find(['T', ' ']) {
+        Some(pos) => (&s[..pos], Some(&s[pos+1..])),
+        None => (s, None),
+    };
+    let parts: Vec<&str> = dt.split('-').collect();
+    if parts.len() != 3 {
+        return None;
+    }
+    let y = parse_num(parts[0], 4, 4)? as i64;
+    let m = parse_num(parts[1], 2, 2)?;
+    let d = parse_num(parts[2], 2, 2)?;
+    if m == 0 || m > 12 || d == 0 || d > days_in_month(y, m) {
+        return None;
+    }
+    let t = match tm {
+        Some(t) => parse_time(t)?,
+        None => 0.0,
+    };
+    Some(date_serial(y, m as i64, d as i64) + t)
+}
+
+// The date is shown without time if the time is midnight
+pub fn format_date(serial: f64) -> String {
+    let (y, m, d) = serial_to_ymd(serial);
+    let (h, mi, s) = serial_to_hms(serial);
+    let dt = format!("{:04}-{:02}-{:02}", y, m, d);
+    match (h, mi, s) {
+        (0, 0, 0) => dt,
+        (_, _, 0) => format!("{} {:02}:{:02}", dt, h, mi),
+        _ => format!("{} {:02}:{:02}:{:02}", dt, h, mi, s),
+    }
+}
+
+// Time of day or a duration. Durations longer than a day show total hours
+pub fn format_time(days: f64) -> String {
+    let sign = if days < 0.0 { "-" } else { "" };
+    let secs = (days.abs() * SECS_IN_DAY).round() as u64;
+    let (h, m, s) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if s == 0 {
+        format!("{}{:02}:{:02}", sign, h, m)
+    } else {
+        format!("{}{:02}:{:02}:{:02}", sign, h, m, s)
+    }
+}
+
+#[rustfmt::skip]
+#[cfg(test)]
+mod datetime_test {
+    use super::*;
+
+    #[test]
+    fn serial_test() {
+        assert_eq!(date_serial(1899, 12, 31), 1.0);
+        assert_eq!(date_serial(1970, 1, 1), 25569.0);
+        assert_eq!(date_serial(2026, 10, 17), 46312.0);
+        assert_eq!(date_serial(2026, 13, 1), date_serial(2027, 1, 1));
+        assert_eq!(date_serial(2024, 3, 0), date_serial(2024, 2, 29));
+        assert_eq!(serial_to_ymd(46312.75), (2026, 10, 17));
+        assert_eq!(serial_to_hms(46312.75), (18, 0, 0));
+        assert_eq!(add_months(date_serial(2024, 1, 31), 1), date_serial(2024, 2, 29));
+        assert_eq!(add_months(date_serial(2024, 3, 31), -13), date_serial(2023, 2, 28));
+        // 2026-10-17 23:30 UTC is the next day in Tokyo and the same day in New York
+        let secs = 1_792_279_800.0;
+        assert_eq!(serial_to_ymd(unix_to_serial(secs, 0)), (2026, 10, 17));
+        assert_eq!(serial_to_hms(unix_to_serial(secs, 0)), (23, 30, 0));
+        assert_eq!(serial_to_ymd(unix_to_serial(secs, 9 * 3600)), (2026, 10, 18));
+        assert_eq!(serial_to_hms(unix_to_serial(secs, 9 * 3600)), (8, 30, 0));
+        assert_eq!(serial_to_ymd(unix_to_serial(secs, -4 * 3600)), (2026, 10, 17));
+    }
+
+    #[test]
+    fn parse_format_test() {
+        assert_eq!(parse_date("2026-10-17"), Some(46312.0));
+        assert_eq!(parse_date("2026-10-17T12:00"), Some(46312.5));
+        assert_eq!(parse_date("2026-10-17 06:00:00"), Some(46312.25));
+        assert_eq!(parse_date("2026-02-29"), None);
+        assert_eq!(parse_date("2026-1-17"), None);
+        assert_eq!(parse_date("10-17"), None);
+        assert_eq!(parse_time("14:30"), Some(14.5 / 24.0));
+        assert_eq!(parse_time("24:00"), None);
+        assert_eq!(parse_time("14"), None);
+        assert_eq!(format_date(46312.0), "2026-10-17");
+        assert_eq!(format_date(46312.5), "2026-10-17 12:00");
+        assert_eq!(format_time(14.5 / 24.0), "14:30");
+        assert_eq!(format_time(1.5), "36:00");
+        assert_eq!(format_time(-0.25), "-06:00");
+        assert_eq!(format_time(1.0 / 86400.0), "00:00:01");
+    }
+}
@@ -0,0 +1,234 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::ops::{Arg, id_to_pos, pos_to_id};
+use crate::stack::{str_expr_to_vec, bind_local_names};
+use crate::funcs;
+
+// Functions that return a new value on every recalculation
+const VOLATILE: [&str; 5] = ["rand", "randbetween", "randarray", "now", "today"];
+// Ranges up to this number of cells are tracked cell by cell
+const MAX_EXPANDED: usize = 64;
+
+// Cells a formula reads. Open ends of ranges like A:A or H2:H reach the last row or column
+#[derive(Clone,Debug,PartialEq)]
+pub struct Area {
+    page: Option<String>, // lowercase page name, None - the page of the formula
+    start_col: usize,
+    start_row: usize,
+    end_col: usize,
+    end_row: usize,
+}
+
+impl Area {
+    pub fn new(arg: &Arg) -> Option<Area> {
+        let (page, v) = match arg {
+            Arg::Rng(page, v) if !v.is_empty() => (page, v),
+            _ => return None,
+        };
+        let (first, last) = (v[0], v[v.len() - 1]);
+        let start_col = if first.full_row { 0 } else { first.col.min(last.col) };
+        let start_row = if first.full_col { 0 } else { first.row.min(last.row) };
+        let end_col = if last.full_row { usize::MAX } else { first.col.max(last.col) };
+        let end_row = if last.full_col { usize::MAX } else { first.row.max(last.row) };
+        Some(Area { page: page.as_ref().map(|p| p.to_lowercase()), start_col, start_row, end_col, end_row })
+    }
+    fn size(&self) -> usize {
+        let w = (self.end_col - self.start_col).saturating_add(1);
+        let h = (self.end_row - self.start_row).saturating_add(1);
+        w.saturating_mul(h)
+    }
+    // `page` is the lowercase name of the cell's page, `own` - the cell is on the page of the formula
+    pub fn contains(&self, page: &str, own: bool, col: usize, row: usize) -> bool {
+        let same = match &self.page {
+            None => own,
+            Some(p) => p == page,
+        };
+        same && col >= self.start_col && col <= self.end_col && row >= self.start_row && row <= self.end_row
+    }
+}
+
+// Dependencies between the formulas of a page and the cells they read. The cells can be on any page
+#[derive(Default)]
+pub struct Graph {
+    precedents: HashMap<u64, Vec<Area>>, // formula => cells it reads
+    dependents: HashMap<(Option<String>, u64), HashSet<u64>>, // (page, cell) => formulas reading the cell
+    wide: HashMap<u64, Vec<Area>>, // formula => ranges too big to be in `dependents`
+    // formula => names it reads and functions it calls that are not built-in, they can change without editing the formula
+    named: HashMap<u64, Vec<String>>,
+    volatile: HashSet<u64>,
+}
+
+impl Graph {
+    pub fn clear(&mut self) {
+        self.precedents.clear();
+        self.dependents.clear();
+        self.wide.clear();
+        self.named.clear();
+        self.volatile.clear();
+    }
+    // Replaces dependencies of the cell. `expr` is the formula without leading '=' or None for a value
+    pub fn set_cell(&mut self, id: u64, expr: Option<&str>) {
+        self.remove(id);
+        let expr = match expr {
+            None => return,
+            Some(e) => e,
+        };
+        // A formula that cannot be parsed reads nothing: it fails until it is edited
+        // names of LET and LAMBDA are not columns even if they look like them
+        let args = match str_expr_to_vec(expr) {
+            Err(_) => return,
+            Ok(a) => bind_local_names(&a),
+        };
+        let mut areas = Vec::new();
+        for arg in args.iter() {
+            match arg {
+                Arg::Func(name, _) if VOLATILE.contains(&name.to_lowercase().as_str()) => {
+                    self.volatile.insert(id);
+                },
+                // a LAMBDA kept in a name or a user-defined function
+                Arg::Func(name, _) if funcs::find(name).is_none() => {
+                    self.named.entry(id).or_default().push(name.to_lowercase());
+                },
+                Arg::Rng(_, _) => {
+                    if let Some(area) = Area::new(arg) {
+                        areas.push(area);
+                    }
+                },
+                Arg::Name(name) => {
+                    self.named.entry(id).or_default().push(name.to_lowercase());
+                },
+                _ => {},
+            }
+        }
+        for area in areas.iter() {
+            if area.size() > MAX_EXPANDED {
+                self.wide.entry(id).or_default().push(area.clone());
+                continue;
+            }
+            for row in area.start_row..=area.end_row {
+                for col in area.start_col..=area.end_col {
+                    self.dependents.entry((area.page.clone(), pos_to_id(col, row))).or_default().insert(id);
+                }
+            }
+        }
+        self.precedents.insert(id, areas);
+    }
+    pub fn remove(&mut self, id: u64) {
+        self.volatile.remove(&id);
+        self.wide.remove(&id);
+        self.named.remove(&id);
+        let areas = match self.precedents.remove(&id) {
+            None => return,
+            Some(a) => a,
+        };
+        for area in areas.iter().filter(|a| a.size() <= MAX_EXPANDED) {
+            for row in area.start_row..=area.end_row {
+                for col in area.start_col..=area.end_col {
+                    let key = (area.page.clone(), pos_to_id(col, row));
+                    if let Some(set) = self.dependents.get_mut(&key) {
+                        set.remove(&id);
+                        if set.is_empty() {
+                            self.dependents.remove(&key);
+                        }
+                    }
+                }
+            }
+        }
+    }
+    // Formulas of the page that read the cell. `page` is the lowercase name of the cell's page,
+    // `own` - the cell is on this page, so references without a page name point to it
+    pub fn dependents(&self, page: &str, own: bool, id: u64) -> Vec<u64> {
+        let mut res: HashSet<u64> = HashSet::new();
+        if own {
+            if let Some(set) = self.dependents.get(&(None, id)) {
+                res.extend(set.iter());
+            }
+        }
+        if let Some(set) = self.dependents.get(&(Some(page.to_string()), id)) {
+            res.extend(set.iter());
+        }
+        let (col, row) = id_to_pos(id);
+        for (f, areas) in self.wide.iter() {
+            if areas.iter().any(|a| a.contains(page, own, col, row)) {
+                res.insert(*f);
+            }
+        }
+        res.into_iter().collect()
+    }
+    // Formulas of the page that read the cell through names or functions kept in names. `value` returns
+    // what a name points to, `page` and `own` are the same as for `dependents`
+    pub fn named_dependents<F>(&self, page: &str, own: bool, id: u64, value: F) -> Vec<u64>
+        where F: Fn(&str) -> Option<Arg>
+    {
+        let (col, row) = id_to_pos(id);
+        let cell = Cell { page, own, col, row };
+        let reads = |name: &String| name_reads(name, &cell, &value, &mut HashSet::new());
+        self.named.iter().filter(|(_, names)| names.iter().any(reads)).map(|(f, _)| *f).collect()
+    }
+    pub fn volatile(&self) -> Vec<u64> {
+        self.volatile.iter().copied().collect()
+    }
+}
+
+// A changed cell as `named_dependents` looks for it
+struct Cell<'a> {
+    page: &'a str,
+    own: bool,
+    col: usize,
+    row: usize,
+}
+
+// A name reads the cell if its range covers the cell, or if it keeps a function which body reads it.
+// References without a page name in the body point to the page of the calling formula.
+// `seen` are the names already checked, so recursive functions stop
+fn name_reads<F>(name: &str, cell: &Cell, value: &F, seen: &mut HashSet<String>) -> bool
+    where F: Fn(&str) -> Option<Arg>
+{
+    if !seen.insert(name.to_lowercase()) {
+        return false;
+    }
+    match value(name) {
+        None => false,
+        Some(Arg::Lambda(_, params, body, _)) => body_reads(&body, &params, cell, value, seen),
+        Some(val) => Area::new(&val).is_some_and(|a| a.contains(cell.page, false, cell.col, cell.row)),
+    }
+}
+fn body_reads<F>(body: &[Arg], params: &[String], cell: &Cell, value: &F, seen: &mut HashSet<String>) -> bool
+    where F: Fn(&str) -> Option<Arg>
+{
+    body.iter().any(|arg| match arg {
+        Arg::Rng(..) => Area::new(arg).is_some_and(|a| a.contains(cell.page, cell.own, cell.col, cell.row)),
+        Arg::Lazy(inner) => body_reads(inner, params, cell, value, seen),
+        Arg::Name(name) | Arg::Func(name, _) if !params.contains(&name.to_lowercase()) => name_reads(name, cell, value, seen),
+        _ => false,
+    })
+}
+
+#[rustfmt::skip]
+#[cfg(test)]
+mod deps_test {
+    use super::*;
+
+    #[test]
+    fn graph_test() {
+        let mut g = Graph::default();
+        let (a1, b1, c1, a100) = (pos_to_id(0, 0), pos_to_id(1, 0), pos_to_id(2, 0), pos_to_id(0, 99));
+        g.set_cell(c1, Some("A1+B1*2"));
+        g.set_cell(pos_to_id(3, 0), Some("SUM(A:A)+RAND()"));
+        g.set_cell(pos_to_id(4, 0), Some("'Other'!A1+page1!B1"));
+        let mut v = g.dependents("page1", true, a1);
+        v.sort();
+        assert_eq!(v, vec![c1, pos_to_id(3, 0)]);
+        assert_eq!(g.dependents("page1", true, a100), vec![pos_to_id(3, 0)]);
+        assert_eq!(g.dependents("other", false, a1), vec![pos_to_id(4, 0)]);
+        assert_eq!(g.dependents("page1", false, b1), vec![pos_to_id(4, 0)]);
+        assert_eq!(g.dependents("page2", false, a1), Vec::<u64>::new());
+        assert_eq!(g.volatile(), vec![pos_to_id(3, 0)]);
+
+        g.set_cell(c1, Some("B1"));
+        assert_eq!(g.dependents("page1", true, a1), vec![pos_to_id(3, 0)]);
+        g.set_cell(pos_to_id(3, 0), None);
+        assert_eq!(g.dependents("page1", true, a1), Vec::<u64>::new());
+        assert_eq!(g.volatile(), Vec::<u64>::new());
+    }
+}
@@ -69,6 +69,42 @@ impl Edit {
         self.first_char += diff as u16;
         self.cursor_pos = self.w;
     }
+    pub fn cursor_screen_pos(&self) -> (u16, u16) {
+        (self.col + self.cursor_pos, self.row)
+    }
+    // Position of the cursor in characters
+    pub fn cursor(&self) -> usize {
+        (self.first_char + self.cursor_pos) as usize
+    }
+    // Redraws parts of the visible text in their own colors. A part is (start, end, fg) in characters
+    pub fn draw_colored(&self, scr: &mut Screen, parts: &[(usize, usize, Color)]) {
+        if !self.visible {
+            return;
+        }
+        let (first, w) = (self.first_char as usize, self.w as usize);
+        for &(start, end, fg) in parts {
+            scr.colors(fg, self.bg);
+            for (idx, c) in self.text.chars().enumerate().take(end.min(first + w)).skip(start.max(first)) {
+                scr.write_char(c, self.col + (idx - first) as u16, self.row);
+            }
+        }
+    }
+    // Replaces the text from the character `start` to the cursor
+    pub fn replace_before_cursor(&mut self, start: usize, txt: &str) {
+        let end = self.cursor();
+        let text: String = self.text.chars().take(start).chain(self.text.chars().skip(end)).collect();
+        self.text = text;
+        self.set_cursor(start);
+        self.insert(txt);
+    }
+    // Puts the cursor before the character `pos`, scrolling the text if the character is hidden
+    pub fn set_cursor(&mut self, pos: usize) {
+        let pos = pos.min(self.text.chars().count()) as u16;
+        if pos < self.first_char || pos > self.first_char + self.w {
+            self.first_char = pos.saturating_sub(self.w);
+        }
+        self.cursor_pos = pos - self.first_char;
+    }
 }
 
 impl Widget for Edit {
@@ -1,316 +1,2916 @@
-use std::collections::HashMap;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
 use std::ops::Bound::Included;
 
+use nanorand::{WyRand, RNG};
+
 use anyhow::{anyhow, Result};
 
-use crate::ops::{Arg, NEG_SIGN, POS_SIGN, pos_to_id, id_to_pos};
-use crate::sheet::{Sheet};
-use crate::stack::{str_expr_to_vec, expr_to_stack};
+use crate::ops::{Arg, Pos, ErrKind, calc_err, err_kind, NEG_SIGN, POS_SIGN, pos_to_id, id_to_pos};
+use crate::book::Book;
+use crate::sheet::Sheet;
+use crate::stack::{local_name, lambda_parts};
+use crate::strs;
+use crate::criteria::Criteria;
+use crate::coerce::{try_to_num, try_to_str, try_to_bool, str_to_num, compare, same_type_cmp, sort_cmp};
+use crate::datetime;
+use crate::finance;
+use crate::udf;
+use crate::funcs;
+
+// Range corners: start column, start row, end column, end row
+type Bounds = (usize, usize, usize, usize);
+
+const MAX_TEXT_LEN: usize = 32767;
+// LAMBDA calls inside each other, it stops endless recursion
+const MAX_CALL_DEPTH: usize = 64;
+// Number of values an array function can return
+const MAX_ARRAY_SIZE: usize = 1_000_000;
 
 pub struct Expr {
     stk: Vec<Arg>,
-    pub cache: HashMap<u64, u8>,
+    cache: HashMap<(usize, u64), u8>, // (page, cell ID) <=> 1 - being calculated, 2 - done
+    cell: u64, // ID of the cell which formula is being calculated
+    // formulas with outdated values, other formulas keep their values. None - all formulas are outdated
+    pub stale: Option<HashSet<(usize, u64)>>,
+    path: Vec<(usize, u64)>, // cells being calculated, the innermost is the last one
+    pub circular: bool, // a formula read a cell of a circular reference in iterative mode
+    draws: usize, // number of random numbers generated for the current cell
+    rng: Option<WyRand>, // generator used when there is no workbook seed
+    scope: Vec<(String, Arg)>, // names of LET and parameters of LAMBDA, the innermost is the last one
+    calls: usize, // depth of LAMBDA calls
 }
 
 impl Default for Expr {
     fn default() -> Expr {
-        Expr { cache: HashMap::new(), stk: Vec::new(), }
+        Expr { cache: HashMap::new(), stk: Vec::new(), cell: 0, draws: 0, rng: None, stale: None, path: Vec::new(), circular: false,
+            scope: Vec::new(), calls: 0, }
     }
 }
 
 impl Expr {
-    pub fn calculate(&mut self, args: &[Arg], sheet: &mut Sheet) -> Result<Arg> {
+    pub fn calculate(&mut self, args: &[Arg], book: &mut Book) -> Result<Arg> {
+        let a = self.eval(args, book)?;
+        match a {
+            Arg::Lambda(..) => Err(calc_err(ErrKind::Value, "LAMBDA must be called to get a value")),
+            // a range or an array spills into the cells below and to the right
+            Arg::Rng(_, ref v) if v.len() > 1 => Ok(Arg::Array(self.array_values(book, a)?)),
+            Arg::Array(_) => Ok(a),
+            _ => self.single_cell(book, a),
+        }
+    }
+
+    // Calculates the formula of the cell on the current page if it is outdated and saves the result
+    pub fn update_cell(&mut self, book: &mut Book, uid: u64) {
+        let (col, row) = id_to_pos(uid);
+        let _ = self.single_cell(book, Arg::Rng(None, vec![Pos::new(col, row)]));
+    }
+
+    // Cells of the circular reference that ends with the cell, e.g. "A1 -> B1 -> A1"
+    fn cycle(&self, book: &Book, page: usize, uid: u64) -> String {
+        let start = self.path.iter().position(|c| *c == (page, uid)).unwrap_or(0);
+        let mut cells = self.path[start..].to_vec();
+        cells.push((page, uid));
+        let names: Vec<String> = cells.iter().map(|(p, id)| {
+            let (col, row) = id_to_pos(*id);
+            let name = if *p == page { None } else { Some(book.page_name(*p)) };
+            Arg::Rng(name, vec![Pos::new(col, row)]).title()
+        }).collect();
+        names.join(" -> ")
+    }
+
+    fn is_stale(&self, page: usize, uid: u64) -> bool {
+        match &self.stale {
+            None => true,
+            Some(st) => st.contains(&(page, uid)),
+        }
+    }
+
+    // Runs a program and returns the only value it leaves on the stack. The values that
+    // were on the stack before the call are kept intact, even if the calculation fails
+    fn eval(&mut self, args: &[Arg], book: &mut Book) -> Result<Arg> {
+        let base = self.stk.len();
+        if let Err(e) = self.run(args, book) {
+            self.stk.truncate(base);
+            return Err(e);
+        }
+        if self.stk.len() != base + 1 {
+            self.stk.truncate(base);
+            return Err(anyhow!("invalid expression"));
+        }
+        self.stk.pop().ok_or(anyhow!("empty stack"))
+    }
+
+    fn run(&mut self, args: &[Arg], book: &mut Book) -> Result<()> {
         for arg in args {
             match arg {
-                Arg::Number(_) | Arg::Bool(_) | Arg::Str(_) | Arg::Rng(_, _) => {
+                Arg::Rng(name, v) if v.iter().any(|p| p.full_col || p.full_row) => {
+                    let rng = Expr::used_range(book, name, v)?;
+                    self.stk.push(rng);
+                },
+                Arg::Number(_) | Arg::Bool(_) | Arg::Str(_) | Arg::Rng(_, _) | Arg::Lazy(_) | Arg::End |
+                Arg::Date(_) | Arg::Time(_) | Arg::Lambda(..) | Arg::Array(_) => {
                     self.stk.push(arg.clone());
                     continue;
                 },
-                Arg::Op(op) => self.calc_op(op, sheet)?,
-                Arg::Eq(eq) => self.calc_condition(eq, sheet)?,
-                Arg::Func(nm, cnt) => self.calc_func(nm, *cnt, sheet)?,
+                Arg::Err(kind) => return Err((*kind).into()),
+                Arg::Name(name) => {
+                    let val = match self.local(name) {
+                        Some(v) => v,
+                        None => book.name_value(name)?,
+                    };
+                    self.run(std::slice::from_ref(&val), book)?;
+                },
+                Arg::Op(op) => self.calc_op(op, book)?,
+                Arg::Eq(eq) => self.calc_condition(eq, book)?,
+                Arg::Func(nm, cnt) => self.calc_func(nm, *cnt, book)?,
                 _ => unreachable!("{:?}", arg),
             }
         }
-        if self.stk.len() != 1 {
-            return Err(anyhow!("invalid expression"));
-        }
-        if let Some(a) = self.stk.pop() {
-            self.single_cell(sheet, a)
-        } else {
-            unreachable!("invalid expression")
+        Ok(())
+    }
+
+    // Value of a LET name or LAMBDA parameter. Inner declarations hide outer ones
+    fn local(&self, name: &str) -> Option<Arg> {
+        let name = name.to_lowercase();
+        self.scope.iter().rev().find(|(n, _)| *n == name).map(|(_, v)| v.clone())
+    }
+
+    // Converts full-column(A:A), full-row(2:2), and half-open(H2:H) ranges to regular ones
+    // that end at the last used row or column of the page
+    fn used_range(book: &mut Book, name: &Option<String>, v: &[Pos]) -> Result<Arg> {
+        let page = book.page_index(name)?;
+        let sheet = book.page_sheet(page);
+        let (first, last) = (v[0], v[v.len() - 1]);
+        let start_col = if first.full_row { 0 } else { first.col };
+        let start_row = if first.full_col { 0 } else { first.row };
+        let end_col = if last.full_row { sheet.max_col.max(start_col) } else { last.col };
+        let end_row = if last.full_col { sheet.max_row.max(start_row) } else { last.row };
+        Ok(Arg::Rng(name.clone(), vec![Pos::new(start_col, start_row), Pos::new(end_col, end_row)]))
+    }
+
+    // Calculates a postponed function argument. Ranges are returned as is
+    fn force(&mut self, book: &mut Book, arg: Arg) -> Result<Arg> {
+        match arg {
+            Arg::Lazy(body) => if body.is_empty() {
+                Ok(Arg::End)
+            } else {
+                self.eval(&body, book)
+            },
+            _ => Ok(arg),
         }
     }
 
-    fn single_cell(&mut self, sheet: &mut Sheet, arg: Arg) -> Result<Arg> {
+    // Calculates a postponed function argument and converts the result to a single value
+    fn force_value(&mut self, book: &mut Book, arg: Arg) -> Result<Arg> {
+        let a = self.force(book, arg)?;
+        self.single_cell(book, a)
+    }
+
+    fn single_cell(&mut self, book: &mut Book, arg: Arg) -> Result<Arg> {
         match arg {
-            Arg::Rng(_, ref v) => {
+            Arg::Rng(ref name, ref v) => {
                 info!("single cell: {:?}", v);
                 if v.len() != 1 {
                     return Err(anyhow!("cannot get a cell from a range {:?}", arg));
                 }
-                let mut cell = sheet.cell(v[0].col, v[0].row);
+                let page = book.page_index(name)?;
+                let mut cell = book.page_sheet(page).cell(v[0].col, v[0].row);
+                if cell.val.is_empty() {
+                    if let Some(val) = self.spilled(book, page, v[0].col, v[0].row) {
+                        return match val {
+                            Arg::Err(kind) => Err(kind.into()),
+                            val => Ok(val),
+                        };
+                    }
+                }
                 if !cell.is_expr() {
-                    return Ok(cell.calculated.clone());
+                    return match cell.calculated {
+                        Arg::Err(kind) => Err(kind.into()),
+                        val => Ok(val),
+                    };
                 }
                 let uid = pos_to_id(v[0].col, v[0].row);
-                let state = match self.cache.get(&uid) {
+                let state = match self.cache.get(&(page, uid)) {
                     None => 0,
                     Some(v) => *v,
                 };
                 if state == 1 {
-                    return Err(anyhow!("recursion"));
-                } else if state == 0 {
-                    self.cache.insert(uid, 1);
-                    let args = str_expr_to_vec(&cell.val[1..])?;
-                    let args = expr_to_stack(&args)?;
-                    let res = self.calculate(&args, sheet);
-                    sheet.set_cell_calc_value(v[0].col, v[0].row, res);
-                    self.cache.insert(uid, 2);
-                    cell = sheet.cell(v[0].col, v[0].row);
+                    if book.iteration().is_none() {
+                        return Err(calc_err(ErrKind::Circ, format!("circular reference: {}", self.cycle(book, page, uid))));
+                    }
+                    // the value of the previous pass, an error of strict mode is not a value to start from
+                    self.circular = true;
+                    return match cell.calculated {
+                        Arg::Err(_) => Ok(Arg::End),
+                        Arg::Array(rows) => Ok(rows[0][0].clone()),
+                        val => Ok(val),
+                    };
+                } else if state == 0 && self.is_stale(page, uid) {
+                    self.cache.insert((page, uid), 1);
+                    self.path.push((page, uid));
+                    let prog = book.page_sheet(page).program(v[0].col, v[0].row);
+                    let res = match prog.as_deref() {
+                        None => Ok(Arg::End),
+                        Some(Err(e)) => Err(calc_err(err_kind(e), e.to_string())),
+                        Some(Ok(args)) => {
+                            let outer = (book.page, self.cell, self.draws);
+                            book.page = page;
+                            self.cell = uid;
+                            self.draws = 0;
+                            let res = self.calculate(args, book);
+                            (book.page, self.cell, self.draws) = outer;
+                            res
+                        },
+                    };
+                    self.path.pop();
+                    book.page_sheet(page).set_cell_calc_value(v[0].col, v[0].row, res);
+                    self.cache.insert((page, uid), 2);
+                    cell = book.page_sheet(page).cell(v[0].col, v[0].row);
                 }
 
-                if cell.err != 0 {
-                    Err(anyhow!("invalid formula in {:?}", v[0]))
-                } else {
-                    Ok(cell.calculated.clone())
+                // the formula's own cell holds the first value of its array
+                match cell.calculated {
+                    Arg::Err(kind) => Err(calc_err(kind, cell.reason)),
+                    Arg::Array(rows) => Ok(rows[0][0].clone()),
+                    val => Ok(val),
                 }
             },
             _ => Ok(arg),
         }
     }
 
-    fn calc_op(&mut self, op: &str, sheet: &mut Sheet) -> Result<()> {
-        match op {
-            NEG_SIGN => {
-                let arg = self.stk.pop().ok_or(anyhow!("empty stack"))?;
-                let arg = self.single_cell(sheet, arg)?;
-                let f = try_to_num(arg)?;
-                self.stk.push(Arg::Number(-f));
+    // Value spilled into a blank cell. The formulas which arrays may cover the cell are calculated first
+    fn spilled(&mut self, book: &mut Book, page: usize, col: usize, row: usize) -> Option<Arg> {
+        let anchors = book.page_sheet(page).spill_anchors(col, row, col, row);
+        if anchors.is_empty() {
+            return None;
+        }
+        let name = Some(book.page_name(page));
+        for anchor in anchors {
+            let (ac, ar) = id_to_pos(anchor);
+            // an anchor that fails does not spill, its error belongs to its own cell
+            let _ = self.cell_value(book, &name, ac, ar);
+        }
+        book.page_sheet(page).spilled_value(col, row)
+    }
+
+    // Values of a range or an array by rows. Blank cells are `End`, failed cells are `Err`,
+    // and a single value makes a 1x1 array
+    fn array_values(&mut self, book: &mut Book, arg: Arg) -> Result<Vec<Vec<Arg>>> {
+        match arg {
+            Arg::Array(rows) => Ok(rows),
+            Arg::Rng(page, v) if v.len() > 1 => {
+                let (start_col, start_row, end_col, end_row) = Expr::range_bounds(&v);
+                if (end_col - start_col + 1).saturating_mul(end_row - start_row + 1) > MAX_ARRAY_SIZE {
+                    return Err(calc_err(ErrKind::Num, "the range is too big for an array"));
+                }
+                let mut rows = Vec::with_capacity(end_row - start_row + 1);
+                for row in start_row..=end_row {
+                    let mut vals = Vec::with_capacity(end_col - start_col + 1);
+                    for col in start_col..=end_col {
+                        let val = self.cell_value(book, &page, col, row).unwrap_or_else(|e| Arg::Err(err_kind(&e)));
+                        vals.push(val);
+                    }
+                    rows.push(vals);
+                }
+                Ok(rows)
             },
-            POS_SIGN => {
+            _ => Ok(vec![vec![self.single_cell(book, arg)?]]),
+        }
+    }
+    fn push_array(&mut self, rows: Vec<Vec<Arg>>) -> Result<()> {
+        if rows.is_empty() || rows[0].is_empty() {
+            return Err(calc_err(ErrKind::Calc, "empty array"));
+        }
+        self.stk.push(array_result(rows));
+        Ok(())
+    }
+
+    fn calc_op(&mut self, op: &str, book: &mut Book) -> Result<()> {
+        let res = match op {
+            NEG_SIGN | POS_SIGN | "%" => {
                 let arg = self.stk.pop().ok_or(anyhow!("empty stack"))?;
-                let arg = self.single_cell(sheet, arg)?;
-                let f = try_to_num(arg)?;
-                self.stk.push(Arg::Number(f));
+                self.element_wise(book, arg, Arg::End, |a, _| unary_op(op, a))?
             },
-            "%" => {
-                let arg = self.stk.pop().ok_or(anyhow!("empty stack"))?;
-                let arg = self.single_cell(sheet, arg)?;
-                let f = try_to_num(arg)?;
-                self.stk.push(Arg::Number(f/100.0));
+            "*" | "/" | "+" | "-" | "^" | "&" => {
+                let right = self.stk.pop().ok_or(anyhow!("empty stack"))?;
+                let left = self.stk.pop().ok_or(anyhow!("empty stack"))?;
+                self.element_wise(book, left, right, |a, b| binary_op(op, a, b))?
             },
-            "*" => {
-                let arg1 = self.stk.pop().ok_or(anyhow!("empty stack"))?;
-                let arg1 = self.single_cell(sheet, arg1)?;
-                let arg2 = self.stk.pop().ok_or(anyhow!("empty stack"))?;
-                let arg2 = self.single_cell(sheet, arg2)?;
-                let f1 = try_to_num(arg1)?;
-                let f2 = try_to_num(arg2)?;
-                self.stk.push(Arg::Number(f1*f2));
+            _ => return Err(anyhow!("invalid operator {}", op)),
+        };
+        self.stk.push(res);
+        Ok(())
+    }
+    fn calc_condition(&mut self, eq: &str, book: &mut Book) -> Result<()> {
+        let right = self.stk.pop().ok_or(anyhow!("empty stack"))?;
+        let left = self.stk.pop().ok_or(anyhow!("empty stack"))?;
+        let res = self.element_wise(book, left, right, |a, b| compare_op(eq, a, b))?;
+        self.stk.push(res);
+        Ok(())
+    }
+    // Applies an operator to two values. Ranges and arrays are calculated element by element:
+    // a single value, row, or column is repeated to match the other operand, and elements
+    // that fail become errors of the resulting array
+    fn element_wise<F>(&mut self, book: &mut Book, left: Arg, right: Arg, f: F) -> Result<Arg>
+        where F: Fn(Arg, Arg) -> Result<Arg>
+    {
+        if !is_array(&left) && !is_array(&right) {
+            let right = self.single_cell(book, right)?;
+            let left = self.single_cell(book, left)?;
+            return f(left, right);
+        }
+        let left = self.array_values(book, left)?;
+        let right = self.array_values(book, right)?;
+        let size = |a: usize, b: usize| if a == 1 { b } else if b == 1 { a } else { a.max(b) };
+        let rows = size(left.len(), right.len());
+        let cols = size(left[0].len(), right[0].len());
+        let res = (0..rows).map(|row| (0..cols).map(|col| {
+            match (element(&left, row, col), element(&right, row, col)) {
+                (Arg::Err(kind), _) | (_, Arg::Err(kind)) => Arg::Err(kind),
+                (a, b) => f(a, b).unwrap_or_else(|e| Arg::Err(err_kind(&e))),
+            }
+        }).collect()).collect();
+        Ok(array_result(res))
+    }
+    fn calc_func(&mut self, name: &str, cnt: usize, book: &mut Book) -> Result<()> {
+        // the function table knows how many arguments a built-in function takes
+        funcs::check_arity(name, cnt).map_err(|e| anyhow!(e))?;
+        match name.to_lowercase().as_str() {
+            "sum" => self.sum(cnt, book),
+            "product" => self.product(cnt, book),
+            "sumproduct" => self.sumproduct(cnt, book),
+            "average" => self.average(cnt, book),
+            "count" => self.count(cnt, book),
+            "counta" => self.counta(cnt, book),
+            "countblank" => self.countblank(book),
+            "min" => self.min_max(cnt, book, false),
+            "max" => self.min_max(cnt, book, true),
+            "median" => self.median(cnt, book),
+            "mode" => self.mode(cnt, book),
+            "stdev" | "stdev.s" => self.variance(cnt, book, true, true),
+            "stdev.p" => self.variance(cnt, book, false, true),
+            "var" | "var.s" => self.variance(cnt, book, true, false),
+            "var.p" => self.variance(cnt, book, false, false),
+            "large" => self.kth(book, true),
+            "small" => self.kth(book, false),
+            "if" => self.if_func(cnt, book),
+            "ifs" => self.ifs(cnt, book),
+            "switch" => self.switch(cnt, book),
+            "and" => self.and_or(cnt, book, true),
+            "or" => self.and_or(cnt, book, false),
+            "xor" => self.xor(cnt, book),
+            "not" => self.not(book),
+            "iferror" => self.iferror(cnt, book, false),
+            "ifna" => self.iferror(cnt, book, true),
+            "iserror" | "iserr" | "isna" => self.is_error(book, &name.to_lowercase()),
+            "error.type" => self.error_type(book),
+            "len" => self.len(cnt, book),
+            "left" | "right" => self.left_right(cnt, book, name.eq_ignore_ascii_case("left")),
+            "mid" => self.mid(cnt, book),
+            "upper" | "lower" | "proper" | "trim" => self.change_text(cnt, book, &name.to_lowercase()),
+            "concat" | "concatenate" => self.concat(cnt, book),
+            "textjoin" => self.textjoin(cnt, book),
+            "substitute" => self.substitute(cnt, book),
+            "replace" => self.replace(cnt, book),
+            "find" => self.find(cnt, book, false),
+            "search" => self.find(cnt, book, true),
+            "rept" => self.rept(cnt, book),
+            "exact" => self.exact(cnt, book),
+            "value" => self.value(cnt, book),
+            "text" => self.text(cnt, book),
+            "vlookup" => self.vhlookup(cnt, book, true),
+            "hlookup" => self.vhlookup(cnt, book, false),
+            "index" => self.index(cnt, book),
+            "match" => self.match_func(cnt, book),
+            "xlookup" => self.xlookup(cnt, book),
+            "sumif" => self.aggregate_if(cnt, book, "sum"),
+            "countif" => self.aggregate_if(cnt, book, "count"),
+            "averageif" => self.aggregate_if(cnt, book, "average"),
+            "sumifs" => self.aggregate_ifs(cnt, book, "sum"),
+            "countifs" => self.aggregate_ifs(cnt, book, "count"),
+            "averageifs" => self.aggregate_ifs(cnt, book, "average"),
+            "minifs" => self.aggregate_ifs(cnt, book, "min"),
+            "maxifs" => self.aggregate_ifs(cnt, book, "max"),
+            "date" => self.date(cnt, book),
+            "time" => self.time(cnt, book),
+            "today" => self.now(true),
+            "now" => self.now(false),
+            "year" | "month" | "day" | "hour" | "minute" => self.date_part(&name.to_lowercase(), cnt, book),
+            "weekday" => self.weekday(cnt, book),
+            "edate" => self.edate(cnt, book, false),
+            "eomonth" => self.edate(cnt, book, true),
+            "datedif" => self.datedif(cnt, book),
+            "abs" => self.math_func(cnt, book, f64::abs),
+            "int" => self.math_func(cnt, book, f64::floor),
+            "sign" => self.math_func(cnt, book, |x| if x == 0.0 { 0.0 } else { x.signum() }),
+            "sqrt" => self.math_func(cnt, book, f64::sqrt),
+            "exp" => self.math_func(cnt, book, f64::exp),
+            "ln" => self.math_func(cnt, book, f64::ln),
+            "log10" => self.math_func(cnt, book, f64::log10),
+            "sin" => self.math_func(cnt, book, f64::sin),
+            "cos" => self.math_func(cnt, book, f64::cos),
+            "tan" => self.math_func(cnt, book, f64::tan),
+            "asin" => self.math_func(cnt, book, f64::asin),
+            "acos" => self.math_func(cnt, book, f64::acos),
+            "atan" => self.math_func(cnt, book, f64::atan),
+            "degrees" => self.math_func(cnt, book, f64::to_degrees),
+            "radians" => self.math_func(cnt, book, f64::to_radians),
+            "fact" => self.math_func(cnt, book, factorial),
+            "pi" => self.pi(),
+            "round" => self.round(cnt, book, "round"),
+            "roundup" => self.round(cnt, book, "up"),
+            "rounddown" | "trunc" => self.round(cnt, book, "down"),
+            "mod" => self.modulo(cnt, book),
+            "power" => self.power(cnt, book),
+            "log" => self.log(cnt, book),
+            "atan2" => self.atan2(cnt, book),
+            "ceiling" => self.ceiling_floor(cnt, book, true),
+            "floor" => self.ceiling_floor(cnt, book, false),
+            "rand" => self.rand(book),
+            "randbetween" => self.randbetween(cnt, book),
+            "randarray" => self.randarray(cnt, book),
+            "pmt" | "pv" | "fv" | "nper" => self.annuity(&name.to_lowercase(), cnt, book),
+            "rate" => self.rate(cnt, book),
+            "npv" => self.npv(cnt, book),
+            "irr" => self.irr(cnt, book),
+            "xnpv" => self.xnpv(cnt, book),
+            "xirr" => self.xirr(cnt, book),
+            "gcd" => self.gcd_lcm(cnt, book, true),
+            "lcm" => self.gcd_lcm(cnt, book, false),
+            "sequence" => self.sequence(cnt, book),
+            "sort" => self.sort(cnt, book),
+            "sortby" => self.sortby(cnt, book),
+            "filter" => self.filter(cnt, book),
+            "unique" => self.unique(cnt, book),
+            "transpose" => self.transpose(book),
+            "let" => self.let_func(cnt, book),
+            "lambda" => self.lambda(cnt),
+            _ => match self.local(name).or_else(|| book.find_name(name)).or_else(|| udf::lookup(name)) {
+                Some(f) => self.call(name, f, cnt, book),
+                None => Err(calc_err(ErrKind::Name, format!("unknown function {}", name.to_uppercase()))),
             },
-            "/" => {
-                let arg1 = self.stk.pop().ok_or(anyhow!("empty stack"))?;
-                let arg1 = self.single_cell(sheet, arg1)?;
-                let arg2 = self.stk.pop().ok_or(anyhow!("empty stack"))?;
-                let arg2 = self.single_cell(sheet, arg2)?;
-                let f1 = try_to_num(arg1)?;
-                if f1 == 0.0 {
-                    return Err(anyhow!("division by zero"));
+        }
+    }
+
+    // Pops `cnt` function arguments from the stack and returns them in the order they were written
+    fn pop_args(&mut self, cnt: usize) -> Result<Vec<Arg>> {
+        if self.stk.len() < cnt {
+            return Err(anyhow!("empty stack"));
+        }
+        let idx = self.stk.len() - cnt;
+        Ok(self.stk.split_off(idx))
+    }
+
+    // Returns the corners of a range: (start_col, start_row, end_col, end_row)
+    fn range_bounds(v: &[Pos]) -> (usize, usize, usize, usize) {
+        if v.len() == 1 {
+            (v[0].col, v[0].row, v[0].col, v[0].row)
+        } else {
+            (v[0].col, v[0].row, v[1].col, v[1].row)
+        }
+    }
+
+    // Calculated values of all non-empty cells inside a range. Formulas are evaluated first.
+    // Failed cells are `Err` values, so the caller decides whether to skip or propagate them
+    fn range_values(&mut self, book: &mut Book, page: &Option<String>, v: &[Pos]) -> Result<Vec<Arg>> {
+        let (start_col, start_row, end_col, end_row) = Expr::range_bounds(v);
+        let idx = book.page_index(page)?;
+        let st_id = pos_to_id(start_col, start_row);
+        let en_id = pos_to_id(end_col, end_row);
+        let inside = |id: &u64| {
+            let (col, row) = id_to_pos(*id);
+            col >= start_col && col <= end_col && row >= start_row && row <= end_row
+        };
+        // formulas spilling into the range are calculated first, so their arrays are known
+        let anchors = book.page_sheet(idx).spill_anchors(start_col, start_row, end_col, end_row);
+        for &anchor in anchors.iter() {
+            let (col, row) = id_to_pos(anchor);
+            let _ = self.cell_value(book, page, col, row);
+        }
+        let sheet = book.page_sheet(idx);
+        let mut ids: Vec<u64> = sheet.cells.range((Included(&st_id), Included(&en_id)))
+            .map(|(&id, _)| id)
+            .filter(inside)
+            .collect();
+        if !anchors.is_empty() {
+            for anchor in anchors {
+                ids.extend(sheet.spill_area(anchor).into_iter().filter(inside));
+            }
+            ids.sort_unstable();
+            ids.dedup();
+        }
+        let mut vals = Vec::new();
+        for id in ids {
+            let (col, row) = id_to_pos(id);
+            let val = error_value(self.cell_value(book, page, col, row))?;
+            if let Arg::End = val {
+                continue;
+            }
+            vals.push(val);
+        }
+        Ok(vals)
+    }
+
+    // Expands function arguments to a flat list of values. The flag is true if a value came
+    // from a range: spreadsheet functions treat text and booleans in ranges differently
+    fn arg_values(&mut self, cnt: usize, book: &mut Book) -> Result<Vec<(Arg, bool)>> {
+        self.expand_args(cnt, book, false)
+    }
+    // Like `arg_values`, but errors are values if `keep_errors` is set, so functions that count
+    // values can count or skip them. Such functions get their arguments postponed
+    fn expand_args(&mut self, cnt: usize, book: &mut Book, keep_errors: bool) -> Result<Vec<(Arg, bool)>> {
+        let mut vals = Vec::new();
+        for arg in self.pop_args(cnt)? {
+            let arg = if keep_errors { error_value(self.force(book, arg))? } else { arg };
+            let items = match arg {
+                Arg::Rng(page, v) if v.len() > 1 => self.range_values(book, &page, &v)?,
+                Arg::Rng(_, _) => {
+                    let val = self.single_cell(book, arg);
+                    vec![if keep_errors { error_value(val)? } else { val? }]
+                },
+                // an array is treated like a range
+                Arg::Array(rows) => rows.into_iter().flatten().collect(),
+                _ => {
+                    vals.push((arg, false));
+                    continue;
+                },
+            };
+            for val in items {
+                match val {
+                    Arg::End => {},
+                    Arg::Err(kind) if !keep_errors => return Err(kind.into()),
+                    _ => vals.push((val, true)),
                 }
-                let f2 = try_to_num(arg2)?;
-                self.stk.push(Arg::Number(f2/f1));
-            },
-            "+" => {
-                // TODO: dates
-                let arg1 = self.stk.pop().ok_or(anyhow!("empty stack"))?;
-                let arg1 = self.single_cell(sheet, arg1)?;
-                let arg2 = self.stk.pop().ok_or(anyhow!("empty stack"))?;
-                let arg2 = self.single_cell(sheet, arg2)?;
-                let f1 = try_to_num(arg1)?;
-                let f2 = try_to_num(arg2)?;
-                self.stk.push(Arg::Number(f1+f2));
-            },
-            "-" => {
-                // TODO: dates
-                let arg1 = self.stk.pop().ok_or(anyhow!("empty stack"))?;
-                let arg1 = self.single_cell(sheet, arg1)?;
-                let arg2 = self.stk.pop().ok_or(anyhow!("empty stack"))?;
-                let arg2 = self.single_cell(sheet, arg2)?;
-                let f1 = try_to_num(arg1)?;
-                let f2 = try_to_num(arg2)?;
-                self.stk.push(Arg::Number(f2-f1));
-            },
-            "^" => {
-                let arg1 = self.stk.pop().ok_or(anyhow!("empty stack"))?;
-                let arg1 = self.single_cell(sheet, arg1)?;
-                let arg2 = self.stk.pop().ok_or(anyhow!("empty stack"))?;
-                let arg2 = self.single_cell(sheet, arg2)?;
-                let f1 = try_to_num(arg1)?;
-                let f2 = try_to_num(arg2)?;
-                self.stk.push(Arg::Number(f2.powf(f1)));
-            },
-            "&" => {
-                let arg1 = self.stk.pop().ok_or(anyhow!("empty stack"))?;
-                let arg1 = self.single_cell(sheet, arg1)?;
-                let arg2 = self.stk.pop().ok_or(anyhow!("empty stack"))?;
-                let arg2 = self.single_cell(sheet, arg2)?;
-                let f1 = try_to_str(&arg1)?;
-                let f2 = try_to_str(&arg2)?;
-                self.stk.push(Arg::Str(f1+&f2));
-            },
-            _ => return Err(anyhow!("invalid operator {}", op)),
+            }
+        }
+        Ok(vals)
+    }
+
+    // Numbers for aggregate functions: text, booleans and blanks inside ranges are skipped,
+    // while values passed directly are converted and fail if they are not numbers
+    fn arg_numbers(&mut self, cnt: usize, book: &mut Book) -> Result<Vec<f64>> {
+        let mut nums = Vec::new();
+        for (val, in_range) in self.arg_values(cnt, book)? {
+            match val {
+                Arg::Number(n) | Arg::Date(n) | Arg::Time(n) => nums.push(n),
+                _ if in_range => {},
+                _ => nums.push(try_to_num(val)?),
+            }
+        }
+        Ok(nums)
+    }
+
+    fn sum(&mut self, cnt: usize, book: &mut Book) -> Result<()> {
+        let sum: f64 = self.arg_numbers(cnt, book)?.iter().sum();
+        self.stk.push(Arg::Number(sum));
+        Ok(())
+    }
+    // SUMPRODUCT(array1, [array2]...): arrays of the same size are multiplied element by element
+    // and the products are added up. Text, booleans, and blanks are zeros
+    fn sumproduct(&mut self, cnt: usize, book: &mut Book) -> Result<()> {
+        let mut size = None;
+        let mut products: Vec<f64> = Vec::new();
+        for arg in self.pop_args(cnt)? {
+            let rows = self.array_values(book, arg)?;
+            if size.is_some_and(|s| s != (rows.len(), rows[0].len())) {
+                return Err(anyhow!("SUMPRODUCT arrays must have the same size"));
+            }
+            let mut nums = Vec::new();
+            for val in rows.iter().flatten() {
+                nums.push(match val {
+                    Arg::Number(n) | Arg::Date(n) | Arg::Time(n) => *n,
+                    Arg::Err(kind) => return Err((*kind).into()),
+                    _ => 0.0,
+                });
+            }
+            products = if size.is_none() { nums } else { products.iter().zip(nums).map(|(p, n)| p * n).collect() };
+            size = Some((rows.len(), rows[0].len()));
         }
+        self.stk.push(Arg::Number(products.iter().sum()));
         Ok(())
     }
-    fn calc_condition(&mut self, eq: &str, sheet: &mut Sheet) -> Result<()> {
-        let arg1 = self.stk.pop().ok_or(anyhow!("empty stack"))?;
-        let arg1 = self.single_cell(sheet, arg1)?;
-        let arg2 = self.stk.pop().ok_or(anyhow!("empty stack"))?;
-        let arg2 = self.single_cell(sheet, arg2)?;
-        match eq {
-            "<>" | "!=" => {
-                let b = eq_op(&arg1, &arg2)?;
-                self.stk.push(Arg::Bool(!b));
-            },
-            "=" | "==" => {
-                let b = eq_op(&arg1, &arg2)?;
-                self.stk.push(Arg::Bool(b));
-            },
-            "<" => {
-                let b = less_op(&arg2, &arg1)?;
-                self.stk.push(Arg::Bool(b));
+    fn product(&mut self, cnt: usize, book: &mut Book) -> Result<()> {
+        let nums = self.arg_numbers(cnt, book)?;
+        let prod = if nums.is_empty() { 0.0 } else { nums.iter().product() };
+        self.stk.push(Arg::Number(prod));
+        Ok(())
+    }
+    fn average(&mut self, cnt: usize, book: &mut Book) -> Result<()> {
+        let nums = self.arg_numbers(cnt, book)?;
+        if nums.is_empty() {
+            return Err(calc_err(ErrKind::Div0, "division by zero"));
+        }
+        let sum: f64 = nums.iter().sum();
+        self.stk.push(Arg::Number(sum / nums.len() as f64));
+        Ok(())
+    }
+    // COUNT skips text that is not a number, even when it is passed directly, and errors in ranges and arrays
+    fn count(&mut self, cnt: usize, book: &mut Book) -> Result<()> {
+        let mut total = 0usize;
+        for (val, in_range) in self.expand_args(cnt, book, true)? {
+            match val {
+                Arg::Number(_) | Arg::Date(_) | Arg::Time(_) => total += 1,
+                _ if in_range => {},
+                Arg::Bool(_) => total += 1,
+                Arg::Str(s) if s.parse::<f64>().is_ok() => total += 1,
+                _ => {},
+            }
+        }
+        self.stk.push(Arg::Number(total as f64));
+        Ok(())
+    }
+    // COUNTA counts every value that is not blank, errors included
+    fn counta(&mut self, cnt: usize, book: &mut Book) -> Result<()> {
+        let total = self.expand_args(cnt, book, true)?.len();
+        self.stk.push(Arg::Number(total as f64));
+        Ok(())
+    }
+    // Empty strings are blank for COUNTBLANK, though COUNTA counts them too
+    fn countblank(&mut self, book: &mut Book) -> Result<()> {
+        let arg = self.stk.pop().ok_or(anyhow!("empty stack"))?;
+        let (page, v) = match arg {
+            Arg::Rng(page, v) => (page, v),
+            _ => return Err(anyhow!("COUNTBLANK requires a range")),
+        };
+        let (start_col, start_row, end_col, end_row) = Expr::range_bounds(&v);
+        let area = (end_col - start_col + 1) * (end_row - start_row + 1);
+        let filled = self.range_values(book, &page, &v)?.iter().filter(|a| !matches!(a, Arg::Str(s) if s.is_empty())).count();
+        self.stk.push(Arg::Number((area - filled) as f64));
+        Ok(())
+    }
+    fn min_max(&mut self, cnt: usize, book: &mut Book, is_max: bool) -> Result<()> {
+        let nums = self.arg_numbers(cnt, book)?;
+        let res = if is_max {
+            nums.iter().cloned().fold(f64::NEG_INFINITY, f64::max)
+        } else {
+            nums.iter().cloned().fold(f64::INFINITY, f64::min)
+        };
+        self.stk.push(Arg::Number(if nums.is_empty() { 0.0 } else { res }));
+        Ok(())
+    }
+    fn median(&mut self, cnt: usize, book: &mut Book) -> Result<()> {
+        let mut nums = self.arg_numbers(cnt, book)?;
+        if nums.is_empty() {
+            return Err(anyhow!("MEDIAN requires at least one number"));
+        }
+        sort_nums(&mut nums);
+        let mid = nums.len() / 2;
+        let res = if nums.len() % 2 == 0 { (nums[mid - 1] + nums[mid]) / 2.0 } else { nums[mid] };
+        self.stk.push(Arg::Number(res));
+        Ok(())
+    }
+    // The most frequent value; on a tie the one that appears first wins
+    fn mode(&mut self, cnt: usize, book: &mut Book) -> Result<()> {
+        let nums = self.arg_numbers(cnt, book)?;
+        let mut best: Option<(f64, usize)> = None;
+        for (idx, n) in nums.iter().enumerate() {
+            if nums[..idx].contains(n) {
+                continue;
+            }
+            let freq = nums[idx..].iter().filter(|&m| m == n).count();
+            if freq > 1 && best.is_none_or(|(_, f)| freq > f) {
+                best = Some((*n, freq));
+            }
+        }
+        match best {
+            None => Err(anyhow!("MODE: no value repeats")),
+            Some((n, _)) => {
+                self.stk.push(Arg::Number(n));
+                Ok(())
             },
-            ">=" => {
-                let b = less_op(&arg2, &arg1)?;
-                self.stk.push(Arg::Bool(!b));
+        }
+    }
+    fn variance(&mut self, cnt: usize, book: &mut Book, sample: bool, root: bool) -> Result<()> {
+        let nums = self.arg_numbers(cnt, book)?;
+        let n = nums.len();
+        if n == 0 || (sample && n == 1) {
+            return Err(calc_err(ErrKind::Div0, "division by zero"));
+        }
+        let mean = nums.iter().sum::<f64>() / n as f64;
+        let sq: f64 = nums.iter().map(|x| (x - mean) * (x - mean)).sum();
+        let var = if sample { sq / (n - 1) as f64 } else { sq / n as f64 };
+        self.stk.push(Arg::Number(if root { var.sqrt() } else { var }));
+        Ok(())
+    }
+    // LARGE and SMALL: k-th biggest or smallest number
+    fn kth(&mut self, book: &mut Book, largest: bool) -> Result<()> {
+        let k = self.stk.pop().ok_or(anyhow!("empty stack"))?;
+        let k = self.single_cell(book, k)?;
+        let k = try_to_num(k)?;
+        let mut nums = self.arg_numbers(1, book)?;
+        if k < 1.0 || k as usize > nums.len() {
+            return Err(anyhow!("k is out of range"));
+        }
+        sort_nums(&mut nums);
+        if largest {
+            nums.reverse();
+        }
+        self.stk.push(Arg::Number(nums[k as usize - 1]));
+        Ok(())
+    }
+
+    fn if_func(&mut self, cnt: usize, book: &mut Book) -> Result<()> {
+        let mut args = self.pop_args(cnt)?;
+        let cond = self.force_value(book, args.remove(0))?;
+        let res = if try_to_bool(cond)? {
+            self.force(book, args.remove(0))?
+        } else if cnt == 3 {
+            self.force(book, args.remove(1))?
+        } else {
+            Arg::Bool(false)
+        };
+        self.stk.push(res);
+        Ok(())
+    }
+    fn ifs(&mut self, cnt: usize, book: &mut Book) -> Result<()> {
+        if cnt % 2 == 1 {
+            return Err(anyhow!("IFS requires pairs of conditions and values"));
+        }
+        let args = self.pop_args(cnt)?;
+        let mut it = args.into_iter();
+        while let (Some(cond), Some(val)) = (it.next(), it.next()) {
+            let cond = self.force_value(book, cond)?;
+            if try_to_bool(cond)? {
+                let res = self.force(book, val)?;
+                self.stk.push(res);
+                return Ok(());
+            }
+        }
+        Err(ErrKind::NA.into())
+    }
+    // SWITCH(expr, value1, result1, [value2, result2]..., [default])
+    fn switch(&mut self, cnt: usize, book: &mut Book) -> Result<()> {
+        let mut args = self.pop_args(cnt)?;
+        let default = if cnt % 2 == 1 { None } else { args.pop() };
+        let mut it = args.into_iter();
+        let val = match it.next() {
+            Some(a) => self.force_value(book, a)?,
+            None => return Err(anyhow!("empty stack")),
+        };
+        while let (Some(case), Some(res)) = (it.next(), it.next()) {
+            let case = self.force_value(book, case)?;
+            if compare(&val, &case) == Ordering::Equal {
+                let res = self.force(book, res)?;
+                self.stk.push(res);
+                return Ok(());
+            }
+        }
+        match default {
+            Some(res) => {
+                let res = self.force(book, res)?;
+                self.stk.push(res);
+                Ok(())
             },
-            ">" => {
-                let b = greater_op(&arg2, &arg1)?;
-                self.stk.push(Arg::Bool(b));
+            None => Err(ErrKind::NA.into()),
+        }
+    }
+    // Booleans of a function argument. Text and blank cells in ranges are skipped
+    fn arg_bools(&mut self, book: &mut Book, arg: Arg) -> Result<Vec<bool>> {
+        let arg = self.force(book, arg)?;
+        let vals = match arg {
+            Arg::Rng(ref page, ref v) if v.len() > 1 => self.range_values(book, page, v)?,
+            Arg::Rng(_, _) => vec![self.single_cell(book, arg)?],
+            _ => return Ok(vec![try_to_bool(arg)?]),
+        };
+        let mut res = Vec::new();
+        for val in vals {
+            match val {
+                Arg::Bool(b) => res.push(b),
+                Arg::Number(n) => res.push(n != 0.0),
+                Arg::Err(kind) => return Err(kind.into()),
+                _ => {},
+            }
+        }
+        Ok(res)
+    }
+    // AND stops at the first FALSE, OR stops at the first TRUE
+    fn and_or(&mut self, cnt: usize, book: &mut Book, is_and: bool) -> Result<()> {
+        let mut found = false;
+        for arg in self.pop_args(cnt)? {
+            let vals = self.arg_bools(book, arg)?;
+            found = found || !vals.is_empty();
+            if vals.iter().any(|&b| b != is_and) {
+                self.stk.push(Arg::Bool(!is_and));
+                return Ok(());
+            }
+        }
+        if !found {
+            return Err(anyhow!("no logical values"));
+        }
+        self.stk.push(Arg::Bool(is_and));
+        Ok(())
+    }
+    fn xor(&mut self, cnt: usize, book: &mut Book) -> Result<()> {
+        let mut vals = Vec::new();
+        for arg in self.pop_args(cnt)? {
+            vals.extend(self.arg_bools(book, arg)?);
+        }
+        if vals.is_empty() {
+            return Err(anyhow!("no logical values"));
+        }
+        let odd = vals.iter().filter(|&&b| b).count() % 2 == 1;
+        self.stk.push(Arg::Bool(odd));
+        Ok(())
+    }
+    fn not(&mut self, book: &mut Book) -> Result<()> {
+        let arg = self.stk.pop().ok_or(anyhow!("empty stack"))?;
+        let arg = self.single_cell(book, arg)?;
+        let b = try_to_bool(arg)?;
+        self.stk.push(Arg::Bool(!b));
+        Ok(())
+    }
+    // IFERROR catches any error, IFNA catches only #N/A
+    fn iferror(&mut self, cnt: usize, book: &mut Book, only_na: bool) -> Result<()> {
+        let mut args = self.pop_args(cnt)?;
+        let fallback = args.pop().ok_or(anyhow!("empty stack"))?;
+        let val = args.pop().ok_or(anyhow!("empty stack"))?;
+        let res = match self.force_value(book, val) {
+            Ok(v) => v,
+            Err(e) => {
+                if only_na && e.downcast_ref::<ErrKind>() != Some(&ErrKind::NA) {
+                    return Err(e);
+                }
+                self.force_value(book, fallback)?
             },
-            "<=" => {
-                let b = greater_op(&arg2, &arg1)?;
-                self.stk.push(Arg::Bool(!b));
+        };
+        self.stk.push(res);
+        Ok(())
+    }
+    // ISERROR is true for any error, ISERR for any error except #N/A, ISNA only for #N/A
+    fn is_error(&mut self, book: &mut Book, name: &str) -> Result<()> {
+        let arg = self.stk.pop().ok_or(anyhow!("empty stack"))?;
+        let kind = self.force_value(book, arg).err().map(|e| err_kind(&e));
+        let res = match name {
+            "iserr" => kind.is_some() && kind != Some(ErrKind::NA),
+            "isna" => kind == Some(ErrKind::NA),
+            _ => kind.is_some(),
+        };
+        self.stk.push(Arg::Bool(res));
+        Ok(())
+    }
+    // ERROR.TYPE(value): the number of the error, #N/A if the value is not an error
+    fn error_type(&mut self, book: &mut Book) -> Result<()> {
+        let arg = self.stk.pop().ok_or(anyhow!("empty stack"))?;
+        match self.force_value(book, arg) {
+            Ok(_) => Err(ErrKind::NA.into()),
+            Err(e) => {
+                self.stk.push(Arg::Number(err_kind(&e).number() as f64));
+                Ok(())
             },
-            _ => return Err(anyhow!("invalid equality operator {}", eq)),
         }
+    }
+
+    // LET(name1, value1, [name2, value2]..., calculation): a value can use the names declared before it
+    fn let_func(&mut self, cnt: usize, book: &mut Book) -> Result<()> {
+        if cnt % 2 == 0 {
+            return Err(anyhow!("LET requires pairs of names and values followed by a calculation"));
+        }
+        let mut args = self.pop_args(cnt)?;
+        let body = args.pop().ok_or(anyhow!("empty stack"))?;
+        let base = self.scope.len();
+        let res = self.bind_and_force(args, body, book);
+        self.scope.truncate(base);
+        self.stk.push(res?);
         Ok(())
     }
-    fn calc_func(&mut self, name: &str, cnt: usize, sheet: &mut Sheet) -> Result<()> {
-        match name.to_lowercase().as_str() {
-            "sum" => self.sum(cnt, sheet),
-            _ => Err(anyhow!("unimplemented")),
+    fn bind_and_force(&mut self, args: Vec<Arg>, body: Arg, book: &mut Book) -> Result<Arg> {
+        let mut it = args.into_iter();
+        while let (Some(name), Some(val)) = (it.next(), it.next()) {
+            let name = local_name(&name)?;
+            let val = self.force(book, val)?;
+            self.scope.push((name, val));
+        }
+        self.force(book, body)
+    }
+    // LAMBDA(param1, ..., calculation) makes a function that a LET name or a workbook name can keep.
+    // The function sees the LET names and parameters visible where it is made, not where it is called
+    fn lambda(&mut self, cnt: usize) -> Result<()> {
+        let args = self.pop_args(cnt)?;
+        let (params, body) = lambda_parts(args)?;
+        self.stk.push(Arg::Lambda(String::new(), params, body, self.scope.clone()));
+        Ok(())
+    }
+    // Calls LAMBDA kept in a name as if it were a built-in function
+    fn call(&mut self, name: &str, f: Arg, cnt: usize, book: &mut Book) -> Result<()> {
+        let (params, body, captured) = match f {
+            Arg::Lambda(_, params, body, captured) => (params, body, captured),
+            _ => return Err(calc_err(ErrKind::Value, format!("{} is not a function", name.to_uppercase()))),
+        };
+        if cnt != params.len() {
+            return Err(anyhow!("{} requires {} arguments", name.to_uppercase(), params.len()));
+        }
+        if self.calls >= MAX_CALL_DEPTH {
+            return Err(calc_err(ErrKind::Num, format!("{}: too many nested calls", name.to_uppercase())));
+        }
+        let args = self.pop_args(cnt)?;
+        let outer = std::mem::replace(&mut self.scope, captured);
+        self.scope.extend(params.into_iter().zip(args));
+        self.calls += 1;
+        let res = self.eval(&body, book);
+        self.calls -= 1;
+        self.scope = outer;
+        self.stk.push(res?);
+        Ok(())
+    }
+
+    // Pops function arguments and converts each of them to a single value
+    fn pop_values(&mut self, cnt: usize, book: &mut Book) -> Result<Vec<Arg>> {
+        let mut vals = Vec::with_capacity(cnt);
+        for arg in self.pop_args(cnt)? {
+            vals.push(self.single_cell(book, arg)?);
+        }
+        Ok(vals)
+    }
+    fn cell_value(&mut self, book: &mut Book, page: &Option<String>, col: usize, row: usize) -> Result<Arg> {
+        self.single_cell(book, Arg::Rng(page.clone(), vec![Pos::new(col, row)]))
+    }
+
+    fn len(&mut self, cnt: usize, book: &mut Book) -> Result<()> {
+        let vals = self.pop_values(cnt, book)?;
+        let s = try_to_str(&vals[0])?;
+        self.stk.push(Arg::Number(s.chars().count() as f64));
+        Ok(())
+    }
+    fn left_right(&mut self, cnt: usize, book: &mut Book, is_left: bool) -> Result<()> {
+        let vals = self.pop_values(cnt, book)?;
+        let s = try_to_str(&vals[0])?;
+        let n = if cnt == 2 { try_to_count(vals[1].clone())? } else { 1 };
+        let res = if is_left {
+            strs::substr(&s, 0, n)
+        } else {
+            let l = s.chars().count();
+            strs::substr(&s, l.saturating_sub(n), n)
+        };
+        self.stk.push(Arg::Str(res));
+        Ok(())
+    }
+    fn mid(&mut self, cnt: usize, book: &mut Book) -> Result<()> {
+        let vals = self.pop_values(cnt, book)?;
+        let s = try_to_str(&vals[0])?;
+        let start = try_to_count(vals[1].clone())?;
+        if start == 0 {
+            return Err(anyhow!("MID start must be greater than 0"));
         }
+        let n = try_to_count(vals[2].clone())?;
+        self.stk.push(Arg::Str(strs::substr(&s, start - 1, n)));
+        Ok(())
     }
-    fn sum(&mut self, cnt: usize, sheet: &mut Sheet) -> Result<()> {
-        if cnt == 0 {
-            return Err(anyhow!("SUM requires at least one argument"));
+    // UPPER, LOWER, PROPER and TRIM
+    fn change_text(&mut self, cnt: usize, book: &mut Book, name: &str) -> Result<()> {
+        let vals = self.pop_values(cnt, book)?;
+        let s = try_to_str(&vals[0])?;
+        let res = match name {
+            "upper" => s.to_uppercase(),
+            "lower" => s.to_lowercase(),
+            "proper" => strs::proper(&s),
+            _ => s.split(' ').filter(|w| !w.is_empty()).collect::<Vec<&str>>().join(" "),
+        };
+        self.stk.push(Arg::Str(res));
+        Ok(())
+    }
+    fn concat(&mut self, cnt: usize, book: &mut Book) -> Result<()> {
+        let mut res = String::new();
+        for (val, _) in self.arg_values(cnt, book)? {
+            res += &try_to_str(&val)?;
         }
-        let mut sum: f64 = 0.0;
-        for _i in 0..cnt {
-            let arg = self.stk.pop().ok_or(anyhow!("empty stack"))?;
+        self.stk.push(Arg::Str(res));
+        Ok(())
+    }
+    // TEXTJOIN(delimiter, ignore_empty, text1, [text2]...)
+    fn textjoin(&mut self, cnt: usize, book: &mut Book) -> Result<()> {
+        let mut args = self.pop_args(cnt)?;
+        let texts = args.split_off(2);
+        let ignore_empty = self.single_cell(book, args.pop().ok_or(anyhow!("empty stack"))?)?;
+        let ignore_empty = try_to_bool(ignore_empty)?;
+        let delim = self.single_cell(book, args.pop().ok_or(anyhow!("empty stack"))?)?;
+        let delim = try_to_str(&delim)?;
+        let mut parts: Vec<String> = Vec::new();
+        for arg in texts {
             match arg {
-                Arg::Rng(_, v) => { // TODO: support open range
-                    let (start_col, start_row, end_col, end_row) = if v.len() == 1 {
-                        (v[0].col, v[0].row, v[0].col, v[0].row)
-                    } else {
-                        (v[0].col, v[0].row, v[1].col, v[1].row)
-                    };
-                    let st_id = pos_to_id(start_col, start_row);
-                    let en_id = pos_to_id(end_col, end_row);
-                    for (&id, val) in sheet.cells.range((Included(&st_id), Included(&en_id))) {
-                        let (col, _row) = id_to_pos(id);
-                        if col < start_col || col > end_col {
-                            continue;
-                        }
-                        if let Ok(n) = try_to_num(val.calculated.clone()) {
-                            sum += n;
+                Arg::Rng(ref page, ref v) if v.len() > 1 => {
+                    let (start_col, start_row, end_col, end_row) = Expr::range_bounds(v);
+                    for row in start_row..=end_row {
+                        for col in start_col..=end_col {
+                            let val = self.cell_value(book, page, col, row)?;
+                            parts.push(try_to_str(&val)?);
                         }
                     }
                 },
-                _ => if let Ok(n) = try_to_num(arg) {
-                    sum += n;
+                _ => {
+                    let val = self.single_cell(book, arg)?;
+                    parts.push(try_to_str(&val)?);
                 },
             }
         }
-        self.stk.push(Arg::Number(sum));
+        if ignore_empty {
+            parts.retain(|p| !p.is_empty());
+        }
+        self.stk.push(Arg::Str(parts.join(&delim)));
         Ok(())
     }
-}
-
-fn eq_op(a: &Arg, b: &Arg) -> Result<bool> {
-    match (a, b) {
-        (Arg::Number(na), Arg::Number(nb)) => Ok(na == nb),
-        (Arg::Bool(na), Arg::Bool(nb)) => Ok(na == nb),
-        (Arg::Str(s), _) | (_, Arg::Str(s)) => {
-            let sa = try_to_str(a)?;
-            let sb = try_to_str(b)?;
-            Ok(sa == sb)
-        },
-        _ => unreachable!("must be unimplemented for {:?} and {:?}", a, b),
+    // SUBSTITUTE(text, old_text, new_text, [instance_num])
+    fn substitute(&mut self, cnt: usize, book: &mut Book) -> Result<()> {
+        let vals = self.pop_values(cnt, book)?;
+        let s = try_to_str(&vals[0])?;
+        let old = try_to_str(&vals[1])?;
+        let new = try_to_str(&vals[2])?;
+        if old.is_empty() {
+            self.stk.push(Arg::Str(s));
+            return Ok(());
+        }
+        if cnt == 3 {
+            self.stk.push(Arg::Str(s.replace(&old, &new)));
+            return Ok(());
+        }
+        let inst = try_to_count(vals[3].clone())?;
+        if inst == 0 {
+            return Err(anyhow!("SUBSTITUTE instance must be greater than 0"));
+        }
+        let res = match s.match_indices(&old).nth(inst - 1) {
+            None => s,
+            Some((pos, _)) => format!("{}{}{}", &s[..pos], new, &s[pos + old.len()..]),
+        };
+        self.stk.push(Arg::Str(res));
+        Ok(())
     }
-}
-
-fn greater_op(a: &Arg, b: &Arg) -> Result<bool> {
-    match (a, b) {
-        (Arg::Number(na), Arg::Number(nb)) => Ok(na > nb),
-        (Arg::Bool(na), Arg::Bool(nb)) => Ok(na > nb),
-        (Arg::Str(s), _) | (_, Arg::Str(s)) => {
-            let sa = try_to_str(a)?;
-            let sb = try_to_str(b)?;
-            Ok(sa > sb)
-        },
-        _ => unreachable!("must be unimplemented for {:?} and {:?}", a, b),
+    // REPLACE(old_text, start_num, num_chars, new_text)
+    fn replace(&mut self, cnt: usize, book: &mut Book) -> Result<()> {
+        let vals = self.pop_values(cnt, book)?;
+        let s = try_to_str(&vals[0])?;
+        let start = try_to_count(vals[1].clone())?;
+        if start == 0 {
+            return Err(anyhow!("REPLACE start must be greater than 0"));
+        }
+        let n = try_to_count(vals[2].clone())?;
+        let new = try_to_str(&vals[3])?;
+        let res = strs::substr(&s, 0, start - 1) + &new + &strs::substr(&s, start - 1 + n, usize::MAX);
+        self.stk.push(Arg::Str(res));
+        Ok(())
     }
-}
-
-fn less_op(a: &Arg, b: &Arg) -> Result<bool> {
-    match (a, b) {
-        (Arg::Number(na), Arg::Number(nb)) => Ok(na < nb),
-        (Arg::Bool(na), Arg::Bool(nb)) => Ok(na < nb),
-        (Arg::Str(s), _) | (_, Arg::Str(s)) => {
-            let sa = try_to_str(a)?;
-            let sb = try_to_str(b)?;
-            Ok(sa < sb)
-        },
-        _ => unreachable!("must be unimplemented for {:?} and {:?}", a, b),
+    // FIND is case-sensitive, SEARCH is not and supports wildcards
+    fn find(&mut self, cnt: usize, book: &mut Book, ignore_case: bool) -> Result<()> {
+        let vals = self.pop_values(cnt, book)?;
+        let what = try_to_str(&vals[0])?;
+        let s = try_to_str(&vals[1])?;
+        let start = if cnt == 3 { try_to_count(vals[2].clone())? } else { 1 };
+        if start == 0 {
+            return Err(anyhow!("start must be greater than 0"));
+        }
+        let found = if ignore_case { strs::wildcard_find(&s, &what, start - 1) } else { strs::find(&s, &what, start - 1, false) };
+        match found {
+            None => Err(anyhow!("'{}' not found", what)),
+            Some(pos) => {
+                self.stk.push(Arg::Number((pos + 1) as f64));
+                Ok(())
+            },
+        }
+    }
+    fn rept(&mut self, cnt: usize, book: &mut Book) -> Result<()> {
+        let vals = self.pop_values(cnt, book)?;
+        let s = try_to_str(&vals[0])?;
+        let n = try_to_count(vals[1].clone())?;
+        if s.chars().count() * n > MAX_TEXT_LEN {
+            return Err(anyhow!("REPT result is too long"));
+        }
+        self.stk.push(Arg::Str(s.repeat(n)));
+        Ok(())
+    }
+    fn exact(&mut self, cnt: usize, book: &mut Book) -> Result<()> {
+        let vals = self.pop_values(cnt, book)?;
+        let same = try_to_str(&vals[0])? == try_to_str(&vals[1])?;
+        self.stk.push(Arg::Bool(same));
+        Ok(())
+    }
+    fn value(&mut self, cnt: usize, book: &mut Book) -> Result<()> {
+        let vals = self.pop_values(cnt, book)?;
+        let n = match &vals[0] {
+            Arg::Number(n) => *n,
+            Arg::Str(s) => str_to_num(s)?,
+            _ => return Err(anyhow!("VALUE requires text")),
+        };
+        self.stk.push(Arg::Number(n));
+        Ok(())
+    }
+    // TEXT(value, format_text)
+    fn text(&mut self, cnt: usize, book: &mut Book) -> Result<()> {
+        let vals = self.pop_values(cnt, book)?;
+        let mask = try_to_str(&vals[1])?;
+        let res = match &vals[0] {
+            Arg::Str(s) if s.parse::<f64>().is_err() => s.clone(),
+            v => strs::format_number(try_to_num(v.clone())?, &mask),
+        };
+        self.stk.push(Arg::Str(res));
+        Ok(())
     }
-}
 
-fn try_to_num(a: Arg) -> Result<f64> {
-    match a {
-        Arg::End => return Ok(0.0),
-        Arg::Str(s) => {
-            if s.is_empty() {
-                return Ok(0.0);
-            }
-            if let Ok(f) = s.parse::<f64>() {
-                Ok(f)
-            } else {
-                Err(anyhow!("cannot convert {} to a number", s))
+    // Values of a one-row or one-column range, including blank cells. Failed cells are `Err`
+    // values that never match a key
+    fn lookup_vector(&mut self, book: &mut Book, arg: &Arg) -> Result<Vec<Arg>> {
+        let (page, v) = match arg {
+            Arg::Rng(page, v) => (page, v),
+            _ => return Err(anyhow!("lookup requires a range")),
+        };
+        let (start_col, start_row, end_col, end_row) = Expr::range_bounds(v);
+        if start_col != end_col && start_row != end_row {
+            return Err(anyhow!("lookup range must be a single row or column"));
+        }
+        let mut vals = Vec::new();
+        for row in start_row..=end_row {
+            for col in start_col..=end_col {
+                vals.push(error_value(self.cell_value(book, page, col, row))?);
             }
-        },
-        Arg::Number(n) => Ok(n),
-        Arg::Bool(b) => Ok(if b { 1.0 } else { 0.0 }),
-        _ => Err(anyhow!("faled to convert to a number")),
+        }
+        Ok(vals)
     }
-}
-fn try_to_str(a: &Arg) -> Result<String> {
-    match a {
-        Arg::End => Ok(String::new()),
-        Arg::Str(s) => Ok(s.to_string()),
-        Arg::Number(n) => Ok(n.to_string()),
-        Arg::Bool(b) => Ok(if *b { "true".to_string() } else { "false".to_string() }),
-        _ => Err(anyhow!("faled to convert to a string")),
+    // Reference to the `idx`-th cell of a one-row or one-column range
+    fn vector_cell(arg: &Arg, idx: usize) -> Result<Arg> {
+        match arg {
+            Arg::Rng(name, v) => {
+                let (start_col, start_row, end_col, _end_row) = Expr::range_bounds(v);
+                let pos = if start_col == end_col {
+                    Pos::new(start_col, start_row + idx)
+                } else {
+                    Pos::new(start_col + idx, start_row)
+                };
+                Ok(Arg::Rng(name.clone(), vec![pos]))
+            },
+            _ => Err(anyhow!("lookup requires a range")),
+        }
     }
-}
-fn try_to_bool(a: Arg) -> Result<bool> {
-    match a {
-        Arg::End => Ok(false),
-        Arg::Str(s) => Ok(!s.is_empty()),
-        Arg::Number(n) => Ok(n != 0.0),
-        Arg::Bool(b) => Ok(b),
-        _ => Err(anyhow!("faled to convert to a string")),
+    // VLOOKUP(value, table, col_index, [approximate]) and HLOOKUP(value, table, row_index, [approximate])
+    fn vhlookup(&mut self, cnt: usize, book: &mut Book, vertical: bool) -> Result<()> {
+        let mut args = self.pop_args(cnt)?;
+        let approx = if cnt == 4 {
+            let a = self.single_cell(book, args.pop().ok_or(anyhow!("empty stack"))?)?;
+            try_to_bool(a)?
+        } else {
+            true
+        };
+        let idx = self.single_cell(book, args.pop().ok_or(anyhow!("empty stack"))?)?;
+        let idx = try_to_count(idx)?;
+        let table = args.pop().ok_or(anyhow!("empty stack"))?;
+        let key = self.single_cell(book, args.pop().ok_or(anyhow!("empty stack"))?)?;
+        let (name, v) = match table {
+            Arg::Rng(name, v) => (name, v),
+            _ => return Err(anyhow!("lookup requires a range")),
+        };
+        let (start_col, start_row, end_col, end_row) = Expr::range_bounds(&v);
+        let size = if vertical { end_col - start_col + 1 } else { end_row - start_row + 1 };
+        if idx == 0 {
+            return Err(anyhow!("index must be at least 1"));
+        }
+        if idx > size {
+            return Err(calc_err(ErrKind::Ref, format!("index {} is outside of the table", idx)));
+        }
+        let (first, keys) = if vertical {
+            (Pos::new(start_col, start_row), Pos::new(start_col, end_row))
+        } else {
+            (Pos::new(start_col, start_row), Pos::new(end_col, start_row))
+        };
+        let keys = Arg::Rng(name.clone(), vec![first, keys]);
+        let vals = self.lookup_vector(book, &keys)?;
+        let found = if approx { sorted_pos(&vals, &key, false) } else { exact_pos(&vals, &key, false) };
+        let pos = found.ok_or(ErrKind::NA)?;
+        let cell = if vertical {
+            Pos::new(start_col + idx - 1, start_row + pos)
+        } else {
+            Pos::new(start_col + pos, start_row + idx - 1)
+        };
+        self.stk.push(Arg::Rng(name, vec![cell]));
+        Ok(())
+    }
+    // INDEX(range, row, [col]). Zero row or column selects the whole column or row of the range
+    fn index(&mut self, cnt: usize, book: &mut Book) -> Result<()> {
+        let mut args = self.pop_args(cnt)?;
+        let mut nums = Vec::new();
+        for a in args.split_off(1) {
+            let a = self.single_cell(book, a)?;
+            nums.push(try_to_count(a)?);
+        }
+        let (name, v) = match args.pop() {
+            Some(Arg::Rng(name, v)) => (name, v),
+            _ => return Err(anyhow!("INDEX requires a range")),
+        };
+        let (start_col, start_row, end_col, end_row) = Expr::range_bounds(&v);
+        let (row, col) = if nums.len() == 1 && start_row == end_row {
+            (1, nums[0])
+        } else if nums.len() == 1 {
+            (nums[0], if start_col == end_col { 1 } else { 0 })
+        } else {
+            (nums[0], nums[1])
+        };
+        if row > end_row - start_row + 1 || col > end_col - start_col + 1 {
+            return Err(calc_err(ErrKind::Ref, "INDEX is outside of the range"));
+        }
+        let (c1, c2) = if col == 0 { (start_col, end_col) } else { (start_col + col - 1, start_col + col - 1) };
+        let (r1, r2) = if row == 0 { (start_row, end_row) } else { (start_row + row - 1, start_row + row - 1) };
+        let res = if c1 == c2 && r1 == r2 {
+            Arg::Rng(name, vec![Pos::new(c1, r1)])
+        } else {
+            Arg::Rng(name, vec![Pos::new(c1, r1), Pos::new(c2, r2)])
+        };
+        self.stk.push(res);
+        Ok(())
+    }
+    // MATCH(value, range, [type]): 1 - the biggest value that is less or equal (ascending order),
+    // 0 - exact match, -1 - the smallest value that is greater or equal (descending order)
+    fn match_func(&mut self, cnt: usize, book: &mut Book) -> Result<()> {
+        let mut args = self.pop_args(cnt)?;
+        let tp = if cnt == 3 {
+            let a = self.single_cell(book, args.pop().ok_or(anyhow!("empty stack"))?)?;
+            try_to_num(a)?
+        } else {
+            1.0
+        };
+        let rng = args.pop().ok_or(anyhow!("empty stack"))?;
+        let key = self.single_cell(book, args.pop().ok_or(anyhow!("empty stack"))?)?;
+        let vals = self.lookup_vector(book, &rng)?;
+        let found = if tp > 0.0 {
+            sorted_pos(&vals, &key, false)
+        } else if tp < 0.0 {
+            sorted_pos(&vals, &key, true)
+        } else {
+            exact_pos(&vals, &key, false)
+        };
+        let pos = found.ok_or(ErrKind::NA)?;
+        self.stk.push(Arg::Number((pos + 1) as f64));
+        Ok(())
+    }
+    // XLOOKUP(value, lookup_range, return_range, [if_not_found], [match_mode], [search_mode])
+    fn xlookup(&mut self, cnt: usize, book: &mut Book) -> Result<()> {
+        let mut args = self.pop_args(cnt)?;
+        let mut opts = Vec::new();
+        for a in args.split_off(3) {
+            opts.push(self.single_cell(book, a)?);
+        }
+        let match_mode = match opts.get(1) {
+            Some(Arg::End) | None => 0.0,
+            Some(a) => try_to_num(a.clone())?,
+        };
+        let reverse = match opts.get(2) {
+            Some(Arg::End) | None => false,
+            Some(a) => try_to_num(a.clone())? < 0.0,
+        };
+        let ret = args.pop().ok_or(anyhow!("empty stack"))?;
+        let rng = args.pop().ok_or(anyhow!("empty stack"))?;
+        let key = self.single_cell(book, args.pop().ok_or(anyhow!("empty stack"))?)?;
+        let vals = self.lookup_vector(book, &rng)?;
+        let found = if match_mode == 0.0 {
+            exact_pos(&vals, &key, reverse)
+        } else {
+            exact_pos(&vals, &key, reverse).or_else(|| nearest_pos(&vals, &key, match_mode > 0.0))
+        };
+        let pos = match found {
+            Some(p) => p,
+            None => match opts.first() {
+                Some(Arg::End) | None => return Err(ErrKind::NA.into()),
+                Some(a) => {
+                    self.stk.push(a.clone());
+                    return Ok(());
+                },
+            },
+        };
+        let (name, v) = match ret {
+            Arg::Rng(name, v) => (name, v),
+            _ => return Err(anyhow!("XLOOKUP requires a return range")),
+        };
+        let (start_col, start_row, end_col, end_row) = Expr::range_bounds(&v);
+        let vertical = match &rng {
+            Arg::Rng(_, lv) => {
+                let (lc1, _lr1, lc2, _lr2) = Expr::range_bounds(lv);
+                lc1 == lc2 && vals.len() > 1 || start_col == end_col
+            },
+            _ => true,
+        };
+        let res = if vertical {
+            if start_row + pos > end_row {
+                return Err(anyhow!("return range is shorter than lookup range"));
+            }
+            if start_col == end_col {
+                Expr::vector_cell(&Arg::Rng(name, v), pos)?
+            } else {
+                Arg::Rng(name, vec![Pos::new(start_col, start_row + pos), Pos::new(end_col, start_row + pos)])
+            }
+        } else {
+            if start_col + pos > end_col {
+                return Err(anyhow!("return range is shorter than lookup range"));
+            }
+            if start_row == end_row {
+                Expr::vector_cell(&Arg::Rng(name, v), pos)?
+            } else {
+                Arg::Rng(name, vec![Pos::new(start_col + pos, start_row), Pos::new(start_col + pos, end_row)])
+            }
+        };
+        self.stk.push(res);
+        Ok(())
+    }
+
+    // Checks every cell of the range against the criteria. Returns the range bounds and the result per cell
+    fn criteria_mask(&mut self, book: &mut Book, rng: Arg, crit: Arg) -> Result<(Bounds, Vec<bool>)> {
+        let (page, v) = match rng {
+            Arg::Rng(page, v) => (page, v),
+            _ => return Err(anyhow!("criteria range must be a range")),
+        };
+        let crit = Criteria::new(&self.single_cell(book, crit)?);
+        let bounds = Expr::range_bounds(&v);
+        let (start_col, start_row, end_col, end_row) = bounds;
+        let mut mask = Vec::new();
+        for row in start_row..=end_row {
+            for col in start_col..=end_col {
+                let val = error_value(self.cell_value(book, &page, col, row))?;
+                mask.push(crit.matches(&val));
+            }
+        }
+        Ok((bounds, mask))
+    }
+    // Numbers, dates, and times from the cells of the range that correspond to the matched cells of the criteria
+    // range. The range is resized to the size of the criteria range starting from its top left corner
+    fn masked_numbers(&mut self, book: &mut Book, rng: &Arg, bounds: Bounds, mask: &[bool]) -> Result<Vec<Arg>> {
+        let (page, col, row) = match rng {
+            Arg::Rng(page, v) => (page, v[0].col, v[0].row),
+            _ => return Err(anyhow!("aggregate range must be a range")),
+        };
+        let (start_col, _start_row, end_col, _end_row) = bounds;
+        let w = end_col - start_col + 1;
+        let mut nums = Vec::new();
+        for (idx, _) in mask.iter().enumerate().filter(|(_, &m)| m) {
+            let val = self.cell_value(book, page, col + idx % w, row + idx / w)?;
+            if let Arg::Number(_) | Arg::Date(_) | Arg::Time(_) = val {
+                nums.push(val);
+            }
+        }
+        Ok(nums)
+    }
+    // MINIFS and MAXIFS keep the type of the value they pick, so the latest date is a date
+    fn push_aggregate(&mut self, vals: &[Arg], matched: usize, func: &str) -> Result<()> {
+        if matches!(func, "min" | "max") && !vals.is_empty() {
+            let pick = if func == "min" { Ordering::Less } else { Ordering::Greater };
+            let best = vals.iter().skip(1).fold(&vals[0], |best, v| if compare(v, best) == pick { v } else { best });
+            self.stk.push(best.clone());
+            return Ok(());
+        }
+        let nums: Vec<f64> = vals.iter().map(|v| try_to_num(v.clone())).collect::<Result<_>>()?;
+        let res = match func {
+            "sum" => nums.iter().sum(),
+            "count" => matched as f64,
+            "average" => {
+                if nums.is_empty() {
+                    return Err(calc_err(ErrKind::Div0, "division by zero"));
+                }
+                nums.iter().sum::<f64>() / nums.len() as f64
+            },
+            "min" | "max" => 0.0,
+            _ => unreachable!("unknown aggregate {}", func),
+        };
+        self.stk.push(Arg::Number(res));
+        Ok(())
+    }
+    // SUMIF(range, criteria, [sum_range]), COUNTIF(range, criteria), AVERAGEIF(range, criteria, [average_range])
+    fn aggregate_if(&mut self, cnt: usize, book: &mut Book, func: &str) -> Result<()> {
+        let mut args = self.pop_args(cnt)?;
+        let target = if cnt == 3 { args.pop() } else { None };
+        let crit = args.pop().ok_or(anyhow!("empty stack"))?;
+        let rng = args.pop().ok_or(anyhow!("empty stack"))?;
+        let target = match target {
+            Some(Arg::End) | None => rng.clone(),
+            Some(t) => t,
+        };
+        let (bounds, mask) = self.criteria_mask(book, rng, crit)?;
+        let matched = mask.iter().filter(|&&m| m).count();
+        let nums = if func == "count" { Vec::new() } else { self.masked_numbers(book, &target, bounds, &mask)? };
+        self.push_aggregate(&nums, matched, func)
+    }
+    // COUNTIFS(range1, criteria1, ...) and SUMIFS/AVERAGEIFS/MINIFS/MAXIFS(range, range1, criteria1, ...).
+    // A cell is used only if all the criteria match
+    fn aggregate_ifs(&mut self, cnt: usize, book: &mut Book, func: &str) -> Result<()> {
+        let skip = if func == "count" { 0 } else { 1 };
+        if (cnt - skip) % 2 == 1 {
+            return Err(anyhow!("{}IFS requires pairs of ranges and criteria", func.to_uppercase()));
+        }
+        let mut args = self.pop_args(cnt)?;
+        let pairs = args.split_off(skip);
+        let mut bounds = None;
+        let mut mask: Vec<bool> = Vec::new();
+        for pair in pairs.chunks(2) {
+            let (b, m) = self.criteria_mask(book, pair[0].clone(), pair[1].clone())?;
+            match bounds {
+                None => {
+                    bounds = Some(b);
+                    mask = m;
+                },
+                Some(old) => {
+                    if old.2 - old.0 != b.2 - b.0 || old.3 - old.1 != b.3 - b.1 {
+                        return Err(anyhow!("criteria ranges must be the same size"));
+                    }
+                    mask.iter_mut().zip(m).for_each(|(a, b)| *a = *a && b);
+                },
+            }
+        }
+        let bounds = bounds.ok_or(anyhow!("no criteria"))?;
+        let matched = mask.iter().filter(|&&m| m).count();
+        let nums = match args.pop() {
+            Some(target) => {
+                if let Arg::Rng(_, v) = &target {
+                    let (c1, r1, c2, r2) = Expr::range_bounds(v);
+                    if c2 - c1 != bounds.2 - bounds.0 || r2 - r1 != bounds.3 - bounds.1 {
+                        return Err(anyhow!("criteria ranges must be the same size"));
+                    }
+                }
+                self.masked_numbers(book, &target, bounds, &mask)?
+            },
+            None => Vec::new(),
+        };
+        self.push_aggregate(&nums, matched, func)
+    }
+
+    // DATE(year, month, day). Years before 1900 are counted from 1900
+    fn date(&mut self, cnt: usize, book: &mut Book) -> Result<()> {
+        let mut nums = Vec::new();
+        for v in self.pop_values(cnt, book)? {
+            nums.push(try_to_num(v)?.trunc() as i64);
+        }
+        let y = if nums[0] < 1900 { nums[0] + 1900 } else { nums[0] };
+        let d = datetime::date_serial(y, nums[1], nums[2]);
+        if d < 0.0 {
+            return Err(anyhow!("date is out of range"));
+        }
+        self.stk.push(Arg::Date(d));
+        Ok(())
+    }
+    // TIME(hour, minute, second) - time of day, so the result wraps at 24 hours
+    fn time(&mut self, cnt: usize, book: &mut Book) -> Result<()> {
+        let mut secs = 0.0;
+        for (v, mul) in self.pop_values(cnt, book)?.into_iter().zip([3600.0, 60.0, 1.0]) {
+            secs += try_to_num(v)?.trunc() * mul;
+        }
+        if secs < 0.0 {
+            return Err(anyhow!("time must not be negative"));
+        }
+        self.stk.push(Arg::Time((secs / 86400.0).fract()));
+        Ok(())
+    }
+    // TODAY() and NOW() use the local time zone
+    fn now(&mut self, date_only: bool) -> Result<()> {
+        let now = datetime::now_serial(datetime::local_offset());
+        self.stk.push(Arg::Date(if date_only { now.floor() } else { now }));
+        Ok(())
+    }
+    fn date_part(&mut self, name: &str, cnt: usize, book: &mut Book) -> Result<()> {
+        let vals = self.pop_values(cnt, book)?;
+        let d = try_to_num(vals[0].clone())?;
+        if d < 0.0 {
+            return Err(anyhow!("date is out of range"));
+        }
+        let (y, m, day) = datetime::serial_to_ymd(d);
+        let (h, mi, _s) = datetime::serial_to_hms(d);
+        let res = match name {
+            "year" => y as f64,
+            "month" => m as f64,
+            "day" => day as f64,
+            "hour" => h as f64,
+            _ => mi as f64,
+        };
+        self.stk.push(Arg::Number(res));
+        Ok(())
+    }
+    // WEEKDAY(date, [type]): 1 - Sunday is 1 (default), 2 - Monday is 1, 3 - Monday is 0
+    fn weekday(&mut self, cnt: usize, book: &mut Book) -> Result<()> {
+        let vals = self.pop_values(cnt, book)?;
+        let d = try_to_num(vals[0].clone())?.floor() as i64;
+        let tp = if cnt == 2 { try_to_num(vals[1].clone())? as i64 } else { 1 };
+        let sunday_based = (d - 1).rem_euclid(7); // 0 - Sunday
+        let monday_based = (d - 2).rem_euclid(7); // 0 - Monday
+        let res = match tp {
+            1 => sunday_based + 1,
+            2 => monday_based + 1,
+            3 => monday_based,
+            _ => return Err(anyhow!("invalid WEEKDAY type {}", tp)),
+        };
+        self.stk.push(Arg::Number(res as f64));
+        Ok(())
+    }
+    // EDATE(date, months) and EOMONTH(date, months)
+    fn edate(&mut self, cnt: usize, book: &mut Book, end_of_month: bool) -> Result<()> {
+        let vals = self.pop_values(cnt, book)?;
+        let d = try_to_num(vals[0].clone())?;
+        let months = try_to_num(vals[1].clone())?.trunc() as i64;
+        let res = if end_of_month {
+            let (y, m, _) = datetime::serial_to_ymd(datetime::add_months(d, months));
+            datetime::date_serial(y, m as i64 + 1, 0)
+        } else {
+            datetime::add_months(d.floor(), months)
+        };
+        if res < 0.0 {
+            return Err(anyhow!("date is out of range"));
+        }
+        self.stk.push(Arg::Date(res));
+        Ok(())
+    }
+    // DATEDIF(start, end, unit): "Y", "M", "D" - full years, months or days between the dates,
+    // "MD", "YM", "YD" - the difference ignoring months and years, years, or only years
+    fn datedif(&mut self, cnt: usize, book: &mut Book) -> Result<()> {
+        let vals = self.pop_values(cnt, book)?;
+        let start = try_to_num(vals[0].clone())?.floor();
+        let end = try_to_num(vals[1].clone())?.floor();
+        let unit = try_to_str(&vals[2])?.to_uppercase();
+        if start > end {
+            return Err(anyhow!("start date is after end date"));
+        }
+        let (y1, m1, d1) = datetime::serial_to_ymd(start);
+        let (y2, m2, d2) = datetime::serial_to_ymd(end);
+        let mut months = (y2 - y1) * 12 + m2 as i64 - m1 as i64;
+        if d2 < d1 {
+            months -= 1;
+        }
+        let res = match unit.as_str() {
+            "Y" => months / 12,
+            "M" => months,
+            "D" => (end - start) as i64,
+            "YM" => months % 12,
+            "MD" => (end - datetime::add_months(start, months)) as i64,
+            "YD" => (end - datetime::add_months(start, months / 12 * 12)) as i64,
+            _ => return Err(anyhow!("invalid DATEDIF unit {}", unit)),
+        };
+        self.stk.push(Arg::Number(res as f64));
+        Ok(())
+    }
+
+    // Function of one number. Results out of the function domain are #NUM!
+    fn math_func(&mut self, cnt: usize, book: &mut Book, f: fn(f64) -> f64) -> Result<()> {
+        let vals = self.pop_values(cnt, book)?;
+        let x = try_to_num(vals[0].clone())?;
+        self.stk.push(num_result(f(x))?);
+        Ok(())
+    }
+    fn pi(&mut self) -> Result<()> {
+        self.stk.push(Arg::Number(std::f64::consts::PI));
+        Ok(())
+    }
+    // Numbers of a function with optional arguments
+    fn pop_numbers(&mut self, cnt: usize, book: &mut Book) -> Result<Vec<f64>> {
+        let mut nums = Vec::with_capacity(cnt);
+        for v in self.pop_values(cnt, book)? {
+            nums.push(try_to_num(v)?);
+        }
+        Ok(nums)
+    }
+    // ROUND, ROUNDUP, ROUNDDOWN and TRUNC with optional number of digits
+    fn round(&mut self, cnt: usize, book: &mut Book, mode: &str) -> Result<()> {
+        let nums = self.pop_numbers(cnt, book)?;
+        let digits = if cnt == 2 { nums[1].trunc() as i32 } else { 0 };
+        self.stk.push(num_result(round_digits(nums[0], digits, mode))?);
+        Ok(())
+    }
+    // MOD(number, divisor) - the result has the same sign as the divisor
+    fn modulo(&mut self, cnt: usize, book: &mut Book) -> Result<()> {
+        let nums = self.pop_numbers(cnt, book)?;
+        if nums[1] == 0.0 {
+            return Err(calc_err(ErrKind::Div0, "division by zero"));
+        }
+        self.stk.push(num_result(nums[0] - nums[1] * (nums[0] / nums[1]).floor())?);
+        Ok(())
+    }
+    fn power(&mut self, cnt: usize, book: &mut Book) -> Result<()> {
+        let nums = self.pop_numbers(cnt, book)?;
+        self.stk.push(power_of(nums[0], nums[1])?);
+        Ok(())
+    }
+    // LOG(number, [base]), base is 10 by default
+    fn log(&mut self, cnt: usize, book: &mut Book) -> Result<()> {
+        let nums = self.pop_numbers(cnt, book)?;
+        let base = if cnt == 2 { nums[1] } else { 10.0 };
+        if base == 1.0 {
+            return Err(calc_err(ErrKind::Div0, "division by zero"));
+        }
+        if nums[0] <= 0.0 || base <= 0.0 {
+            return Err(ErrKind::Num.into());
+        }
+        let res = match base {
+            10.0 => nums[0].log10(),
+            2.0 => nums[0].log2(),
+            _ => nums[0].ln() / base.ln(),
+        };
+        self.stk.push(num_result(res)?);
+        Ok(())
+    }
+    // ATAN2(x, y) - the angle between X-axis and the line to the point (x, y)
+    fn atan2(&mut self, cnt: usize, book: &mut Book) -> Result<()> {
+        let nums = self.pop_numbers(cnt, book)?;
+        if nums[0] == 0.0 && nums[1] == 0.0 {
+            return Err(calc_err(ErrKind::Div0, "division by zero"));
+        }
+        self.stk.push(num_result(nums[1].atan2(nums[0]))?);
+        Ok(())
+    }
+    // CEILING(number, [significance]) and FLOOR(number, [significance]): round to a multiple of significance
+    fn ceiling_floor(&mut self, cnt: usize, book: &mut Book, is_ceiling: bool) -> Result<()> {
+        let nums = self.pop_numbers(cnt, book)?;
+        let (x, sig) = (nums[0], if cnt == 2 { nums[1] } else { 1.0 });
+        if sig == 0.0 {
+            self.stk.push(Arg::Number(0.0));
+            return Ok(());
+        }
+        if x > 0.0 && sig < 0.0 {
+            return Err(ErrKind::Num.into());
+        }
+        let q = x / sig;
+        let eps = q.abs() * f64::EPSILON * 4.0;
+        let q = if is_ceiling { (q - eps).ceil() } else { (q + eps).floor() };
+        // 15 significant digits hide binary errors, e.g. 3*0.1 = 0.30000000000000004
+        let res = format!("{:.14e}", q * sig).parse::<f64>()?;
+        self.stk.push(num_result(res)?);
+        Ok(())
+    }
+    // GCD and LCM of non-negative integers. Fractions are truncated
+    fn gcd_lcm(&mut self, cnt: usize, book: &mut Book, is_gcd: bool) -> Result<()> {
+        let nums = self.arg_numbers(cnt, book)?;
+        let mut res: Option<u64> = None;
+        for n in nums {
+            if n < 0.0 || n >= 2f64.powi(53) {
+                return Err(ErrKind::Num.into());
+            }
+            let n = n.trunc() as u64;
+            res = Some(match res {
+                None => n,
+                Some(r) if is_gcd => gcd(r, n),
+                Some(r) => {
+                    if r == 0 || n == 0 {
+                        0
+                    } else {
+                        (r / gcd(r, n)).checked_mul(n).ok_or(ErrKind::Num)?
+                    }
+                },
+            });
+        }
+        self.stk.push(Arg::Number(res.unwrap_or(0) as f64));
+        Ok(())
+    }
+
+    // Random number in [0, 1). With a workbook seed, the number depends only on the seed,
+    // the page, the cell, and how many numbers the cell formula has generated before
+    fn random(&mut self, sheet: &Sheet) -> f64 {
+        let bits = match sheet.seed {
+            Some(seed) => {
+                let key = mix(mix(mix(seed, name_hash(&sheet.name)), self.cell), self.draws as u64);
+                self.draws += 1;
+                WyRand::new_seed(key).generate::<u64>()
+            },
+            None => self.rng.get_or_insert_with(WyRand::new).generate::<u64>(),
+        };
+        (bits >> 11) as f64 / (1u64 << 53) as f64
+    }
+    fn rand(&mut self, book: &mut Book) -> Result<()> {
+        let r = self.random(book.sheet());
+        self.stk.push(Arg::Number(r));
+        Ok(())
+    }
+    // Random integer between two numbers, both ends inclusive
+    fn random_int(&mut self, sheet: &Sheet, lo: f64, hi: f64) -> Result<f64> {
+        let (lo, hi) = (lo.ceil(), hi.floor());
+        if lo > hi {
+            return Err(ErrKind::Num.into());
+        }
+        let r = self.random(sheet);
+        Ok((lo + (r * (hi - lo + 1.0)).floor()).min(hi))
+    }
+    // RANDBETWEEN(bottom, top)
+    fn randbetween(&mut self, cnt: usize, book: &mut Book) -> Result<()> {
+        let nums = self.pop_numbers(cnt, book)?;
+        let r = self.random_int(book.sheet(), nums[0], nums[1])?;
+        self.stk.push(Arg::Number(r));
+        Ok(())
+    }
+    // RANDARRAY([rows], [columns], [min], [max], [whole_number])
+    fn randarray(&mut self, cnt: usize, book: &mut Book) -> Result<()> {
+        let vals = self.pop_values(cnt, book)?;
+        let mut opts: Vec<Option<f64>> = Vec::new();
+        for v in vals.iter().take(4) {
+            opts.push(match v {
+                Arg::End => None,
+                _ => Some(try_to_num(v.clone())?),
+            });
+        }
+        opts.resize(4, None);
+        let rows = opts[0].unwrap_or(1.0);
+        let cols = opts[1].unwrap_or(1.0);
+        let lo = opts[2].unwrap_or(0.0);
+        let hi = opts[3].unwrap_or(1.0);
+        let whole = match vals.get(4) {
+            None | Some(Arg::End) => false,
+            Some(v) => try_to_bool(v.clone())?,
+        };
+        if rows < 1.0 || cols < 1.0 || lo > hi {
+            return Err(ErrKind::Num.into());
+        }
+        let (rows, cols) = (rows.trunc() as usize, cols.trunc() as usize);
+        if rows.saturating_mul(cols) > MAX_ARRAY_SIZE {
+            return Err(calc_err(ErrKind::Num, "the array is too big"));
+        }
+        let mut res = Vec::with_capacity(rows);
+        for _ in 0..rows {
+            let mut vals = Vec::with_capacity(cols);
+            for _ in 0..cols {
+                let r = if whole {
+                    self.random_int(book.sheet(), lo, hi)?
+                } else {
+                    lo + self.random(book.sheet()) * (hi - lo)
+                };
+                vals.push(Arg::Number(r));
+            }
+            res.push(vals);
+        }
+        self.push_array(res)
+    }
+
+    // SEQUENCE(rows, [columns], [start], [step])
+    fn sequence(&mut self, cnt: usize, book: &mut Book) -> Result<()> {
+        let vals = self.pop_values(cnt, book)?;
+        let rows = opt_num(&vals, 0, 1.0)?.trunc();
+        let cols = opt_num(&vals, 1, 1.0)?.trunc();
+        let start = opt_num(&vals, 2, 1.0)?;
+        let step = opt_num(&vals, 3, 1.0)?;
+        if rows < 1.0 || cols < 1.0 {
+            return Err(calc_err(ErrKind::Calc, "empty array"));
+        }
+        let (rows, cols) = (rows as usize, cols as usize);
+        if rows.saturating_mul(cols) > MAX_ARRAY_SIZE {
+            return Err(calc_err(ErrKind::Num, "the array is too big"));
+        }
+        let res = (0..rows).map(|r| (0..cols).map(|c| Arg::Number(start + step * (r * cols + c) as f64)).collect()).collect();
+        self.push_array(res)
+    }
+    // SORT(array, [sort_index], [sort_order], [by_col]): order is 1 - ascending, -1 - descending
+    fn sort(&mut self, cnt: usize, book: &mut Book) -> Result<()> {
+        let mut args = self.pop_args(cnt)?;
+        let rows = self.array_values(book, args.remove(0))?;
+        let mut opts = Vec::new();
+        for arg in args {
+            opts.push(self.single_cell(book, arg)?);
+        }
+        let idx = opt_num(&opts, 0, 1.0)?.trunc();
+        let desc = sort_order(opt_num(&opts, 1, 1.0)?)?;
+        let by_col = match opts.get(2) {
+            None | Some(Arg::End) => false,
+            Some(v) => try_to_bool(v.clone())?,
+        };
+        let mut rows = if by_col { transpose(rows) } else { rows };
+        if idx < 1.0 || idx as usize > rows[0].len() {
+            return Err(anyhow!("SORT index is out of range"));
+        }
+        let key = idx as usize - 1;
+        rows.sort_by(|a, b| sort_cmp(&a[key], &b[key], desc));
+        self.push_array(if by_col { transpose(rows) } else { rows })
+    }
+    // SORTBY(array, by_array1, [order1], [by_array2, order2]...): each by_array is a column
+    // with a value for every row of the array
+    fn sortby(&mut self, cnt: usize, book: &mut Book) -> Result<()> {
+        let mut args = self.pop_args(cnt)?;
+        let rows = self.array_values(book, args.remove(0))?;
+        let mut keys: Vec<(Vec<Arg>, bool)> = Vec::new();
+        let mut it = args.into_iter();
+        while let Some(by) = it.next() {
+            let by: Vec<Arg> = self.array_values(book, by)?.into_iter().flatten().collect();
+            if by.len() != rows.len() {
+                return Err(anyhow!("SORTBY arrays must have as many values as the array has rows"));
+            }
+            let desc = match it.next() {
+                None => false,
+                Some(order) => {
+                    let order = self.single_cell(book, order)?;
+                    sort_order(opt_num(&[order], 0, 1.0)?)?
+                },
+            };
+            keys.push((by, desc));
+        }
+        let mut order: Vec<usize> = (0..rows.len()).collect();
+        order.sort_by(|&a, &b| {
+            for (by, desc) in keys.iter() {
+                let ord = sort_cmp(&by[a], &by[b], *desc);
+                if ord != Ordering::Equal {
+                    return ord;
+                }
+            }
+            Ordering::Equal
+        });
+        let res = order.into_iter().map(|i| rows[i].clone()).collect();
+        self.push_array(res)
+    }
+    // FILTER(array, include, [if_empty]): include is a column with a value for every row,
+    // or a row with a value for every column
+    fn filter(&mut self, cnt: usize, book: &mut Book) -> Result<()> {
+        let mut args = self.pop_args(cnt)?;
+        let rows = self.array_values(book, args.remove(0))?;
+        let include = self.array_values(book, args.remove(0))?;
+        let by_row = include.len() == rows.len() && include.iter().all(|r| r.len() == 1);
+        let by_col = include.len() == 1 && include[0].len() == rows[0].len();
+        if !by_row && !by_col {
+            return Err(anyhow!("FILTER include must be as long as the array"));
+        }
+        let mut keep = Vec::new();
+        for val in include.into_iter().flatten() {
+            keep.push(try_to_bool(val)?);
+        }
+        let res: Vec<Vec<Arg>> = if by_row && (!by_col || rows.len() > 1) {
+            rows.into_iter().zip(keep.iter()).filter(|(_, &k)| k).map(|(r, _)| r).collect()
+        } else {
+            rows.into_iter().map(|r| r.into_iter().zip(keep.iter()).filter(|(_, &k)| k).map(|(v, _)| v).collect()).collect()
+        };
+        if res.is_empty() || res[0].is_empty() {
+            return match args.pop() {
+                Some(empty) if empty != Arg::End => {
+                    let val = self.single_cell(book, empty)?;
+                    self.stk.push(val);
+                    Ok(())
+                },
+                _ => Err(calc_err(ErrKind::Calc, "FILTER found nothing")),
+            };
+        }
+        self.push_array(res)
+    }
+    // UNIQUE(array, [by_col], [exactly_once]): rows(or columns) in the order they first appear.
+    // Text is compared ignoring case
+    fn unique(&mut self, cnt: usize, book: &mut Book) -> Result<()> {
+        let mut args = self.pop_args(cnt)?;
+        let rows = self.array_values(book, args.remove(0))?;
+        let mut flags = Vec::new();
+        for arg in args {
+            flags.push(match self.single_cell(book, arg)? {
+                Arg::End => false,
+                v => try_to_bool(v)?,
+            });
+        }
+        let by_col = flags.first().copied().unwrap_or(false);
+        let once = flags.get(1).copied().unwrap_or(false);
+        let rows = if by_col { transpose(rows) } else { rows };
+        let same = |a: &[Arg], b: &[Arg]| a.iter().zip(b.iter()).all(|(x, y)| sort_cmp(x, y, false) == Ordering::Equal);
+        let mut res: Vec<(Vec<Arg>, usize)> = Vec::new();
+        for row in rows {
+            match res.iter_mut().find(|(r, _)| same(r, &row)) {
+                Some((_, n)) => *n += 1,
+                None => res.push((row, 1)),
+            }
+        }
+        let res: Vec<Vec<Arg>> = res.into_iter().filter(|(_, n)| !once || *n == 1).map(|(r, _)| r).collect();
+        if res.is_empty() {
+            return Err(calc_err(ErrKind::Calc, "UNIQUE found nothing"));
+        }
+        self.push_array(if by_col { transpose(res) } else { res })
+    }
+    fn transpose(&mut self, book: &mut Book) -> Result<()> {
+        let arg = self.stk.pop().ok_or(anyhow!("empty stack"))?;
+        let rows = self.array_values(book, arg)?;
+        self.push_array(transpose(rows))
+    }
+
+    // Numbers of a range or a single value for cash flow functions. Blank cells are skipped,
+    // dates are converted to day numbers, and text is an error
+    fn flow_values(&mut self, book: &mut Book, arg: Arg) -> Result<Vec<f64>> {
+        let vals = match arg {
+            Arg::Rng(ref page, ref v) if v.len() > 1 => self.range_values(book, page, v)?,
+            _ => vec![self.single_cell(book, arg)?],
+        };
+        let mut nums = Vec::with_capacity(vals.len());
+        for v in vals {
+            match v {
+                Arg::End => {},
+                Arg::Number(n) | Arg::Date(n) | Arg::Time(n) => nums.push(n),
+                Arg::Err(kind) => return Err(kind.into()),
+                _ => return Err(anyhow!("cash flow must contain only numbers")),
+            }
+        }
+        Ok(nums)
+    }
+    // PMT(rate, nper, pv, [fv], [type]), PV(rate, nper, pmt, [fv], [type]),
+    // FV(rate, nper, pmt, [pv], [type]), NPER(rate, pmt, pv, [fv], [type])
+    fn annuity(&mut self, name: &str, cnt: usize, book: &mut Book) -> Result<()> {
+        let mut nums = self.pop_numbers(cnt, book)?;
+        nums.resize(5, 0.0);
+        let tp = if nums[4] == 0.0 { 0.0 } else { 1.0 };
+        let res = match name {
+            "pmt" => finance::pmt(nums[0], nums[1], nums[2], nums[3], tp),
+            "pv" => finance::pv(nums[0], nums[1], nums[2], nums[3], tp),
+            "fv" => finance::fv(nums[0], nums[1], nums[2], nums[3], tp),
+            _ => finance::nper(nums[0], nums[1], nums[2], nums[3], tp),
+        };
+        self.stk.push(num_result(res)?);
+        Ok(())
+    }
+    // RATE(nper, pmt, pv, [fv], [type], [guess])
+    fn rate(&mut self, cnt: usize, book: &mut Book) -> Result<()> {
+        let mut nums = self.pop_numbers(cnt, book)?;
+        if cnt < 6 {
+            nums.resize(5, 0.0);
+            nums.push(0.1);
+        }
+        let tp = if nums[4] == 0.0 { 0.0 } else { 1.0 };
+        let res = finance::rate(nums[0], nums[1], nums[2], nums[3], tp, nums[5]).ok_or(ErrKind::Num)?;
+        self.stk.push(num_result(res)?);
+        Ok(())
+    }
+    // NPV(rate, value1, ...)
+    fn npv(&mut self, cnt: usize, book: &mut Book) -> Result<()> {
+        let mut args = self.pop_args(cnt)?;
+        let flows = args.split_off(1);
+        let rate = try_to_num(self.single_cell(book, args.remove(0))?)?;
+        let mut values = Vec::new();
+        for arg in flows {
+            values.extend(self.flow_values(book, arg)?);
+        }
+        self.stk.push(num_result(finance::npv(rate, &values))?);
+        Ok(())
+    }
+    // IRR(values, [guess])
+    fn irr(&mut self, cnt: usize, book: &mut Book) -> Result<()> {
+        let mut args = self.pop_args(cnt)?;
+        let guess = if cnt == 2 { try_to_num(self.single_cell(book, args.remove(1))?)? } else { 0.1 };
+        let values = self.flow_values(book, args.remove(0))?;
+        let res = finance::irr(&values, guess).ok_or(ErrKind::Num)?;
+        self.stk.push(num_result(res)?);
+        Ok(())
+    }
+    // Payments and their dates for XNPV and XIRR
+    fn dated_flow(&mut self, book: &mut Book, values: Arg, dates: Arg) -> Result<(Vec<f64>, Vec<f64>)> {
+        let values = self.flow_values(book, values)?;
+        let dates = self.flow_values(book, dates)?;
+        if values.len() != dates.len() || values.is_empty() {
+            return Err(ErrKind::Num.into());
+        }
+        if dates.iter().any(|&d| d < dates[0]) {
+            return Err(ErrKind::Num.into());
+        }
+        Ok((values, dates))
+    }
+    // XNPV(rate, values, dates)
+    fn xnpv(&mut self, cnt: usize, book: &mut Book) -> Result<()> {
+        let mut args = self.pop_args(cnt)?;
+        let dates = args.pop().ok_or(anyhow!("empty stack"))?;
+        let values = args.pop().ok_or(anyhow!("empty stack"))?;
+        let rate = try_to_num(self.single_cell(book, args.remove(0))?)?;
+        let (values, dates) = self.dated_flow(book, values, dates)?;
+        self.stk.push(num_result(finance::xnpv(rate, &values, &dates))?);
+        Ok(())
+    }
+    // XIRR(values, dates, [guess])
+    fn xirr(&mut self, cnt: usize, book: &mut Book) -> Result<()> {
+        let mut args = self.pop_args(cnt)?;
+        let guess = if cnt == 3 { try_to_num(self.single_cell(book, args.remove(2))?)? } else { 0.1 };
+        let dates = args.pop().ok_or(anyhow!("empty stack"))?;
+        let values = args.pop().ok_or(anyhow!("empty stack"))?;
+        let (values, dates) = self.dated_flow(book, values, dates)?;
+        let res = finance::xirr(&values, &dates, guess).ok_or(ErrKind::Num)?;
+        self.stk.push(num_result(res)?);
+        Ok(())
+    }
+}
+
+// A 1x1 array is a single value, so it can be used by any function
+fn array_result(mut rows: Vec<Vec<Arg>>) -> Arg {
+    if rows.len() == 1 && rows[0].len() == 1 {
+        rows[0].remove(0)
+    } else {
+        Arg::Array(rows)
+    }
+}
+
+fn is_array(arg: &Arg) -> bool {
+    matches!(arg, Arg::Array(_)) || matches!(arg, Arg::Rng(_, v) if v.len() > 1)
+}
+
+// Element of an operand: a single row or column repeats, positions outside the operand are #N/A
+fn element(rows: &[Vec<Arg>], row: usize, col: usize) -> Arg {
+    let row = if rows.len() == 1 { 0 } else { row };
+    let col = if rows[0].len() == 1 { 0 } else { col };
+    rows.get(row).and_then(|r| r.get(col)).cloned().unwrap_or(Arg::Err(ErrKind::NA))
+}
+
+fn unary_op(op: &str, a: Arg) -> Result<Arg> {
+    let f = try_to_num(a)?;
+    Ok(Arg::Number(match op {
+        NEG_SIGN => -f,
+        "%" => f / 100.0,
+        _ => f,
+    }))
+}
+
+// `a op b` for single values
+fn binary_op(op: &str, a: Arg, b: Arg) -> Result<Arg> {
+    match op {
+        "+" | "-" => add_sub(a, b, op == "-"),
+        "&" => Ok(Arg::Str(try_to_str(&a)? + &try_to_str(&b)?)),
+        _ => {
+            let f2 = try_to_num(b)?;
+            if op == "/" && f2 == 0.0 {
+                return Err(calc_err(ErrKind::Div0, "division by zero"));
+            }
+            let f1 = try_to_num(a)?;
+            match op {
+                "*" => Ok(Arg::Number(f1 * f2)),
+                "/" => Ok(Arg::Number(f1 / f2)),
+                "^" => power_of(f1, f2),
+                _ => Err(anyhow!("invalid operator {}", op)),
+            }
+        },
+    }
+}
+
+// `a eq b` for single values
+fn compare_op(eq: &str, a: Arg, b: Arg) -> Result<Arg> {
+    for v in [&a, &b] {
+        if let Arg::Err(kind) = v {
+            return Err((*kind).into());
+        }
+    }
+    let ord = compare(&a, &b);
+    let res = match eq {
+        "<>" | "!=" => ord != Ordering::Equal,
+        "=" | "==" => ord == Ordering::Equal,
+        "<" => ord == Ordering::Less,
+        ">=" => ord != Ordering::Less,
+        ">" => ord == Ordering::Greater,
+        "<=" => ord != Ordering::Greater,
+        _ => return Err(anyhow!("invalid equality operator {}", eq)),
+    };
+    Ok(Arg::Bool(res))
+}
+
+// Sort order argument: 1 - ascending, -1 - descending. Returns true for descending
+fn sort_order(order: f64) -> Result<bool> {
+    match order as i64 {
+        1 => Ok(false),
+        -1 => Ok(true),
+        _ => Err(anyhow!("sort order must be 1 or -1")),
+    }
+}
+
+fn transpose(rows: Vec<Vec<Arg>>) -> Vec<Vec<Arg>> {
+    let width = rows.first().map_or(0, |r| r.len());
+    let mut res: Vec<Vec<Arg>> = (0..width).map(|_| Vec::with_capacity(rows.len())).collect();
+    for row in rows {
+        for (col, val) in row.into_iter().enumerate() {
+            res[col].push(val);
+        }
+    }
+    res
+}
+
+// Optional number argument, `def` if it is omitted
+fn opt_num(vals: &[Arg], idx: usize, def: f64) -> Result<f64> {
+    match vals.get(idx) {
+        None | Some(Arg::End) => Ok(def),
+        Some(v) => try_to_num(v.clone()),
+    }
+}
+
+fn exact_pos(vals: &[Arg], key: &Arg, reverse: bool) -> Option<usize> {
+    let same = |v: &Arg| same_type_cmp(v, key) == Some(Ordering::Equal);
+    if reverse {
+        vals.iter().rposition(same)
+    } else {
+        vals.iter().position(same)
+    }
+}
+
+// Approximate match in a sorted list: the last item that is not past the key
+fn sorted_pos(vals: &[Arg], key: &Arg, descending: bool) -> Option<usize> {
+    let mut found = None;
+    for (idx, v) in vals.iter().enumerate() {
+        match same_type_cmp(v, key) {
+            None => continue,
+            Some(Ordering::Equal) => return Some(idx),
+            Some(Ordering::Less) if !descending => found = Some(idx),
+            Some(Ordering::Greater) if descending => found = Some(idx),
+            _ => break,
+        }
+    }
+    found
+}
+
+// The closest item that is greater (or less) than the key in an unsorted list
+fn nearest_pos(vals: &[Arg], key: &Arg, larger: bool) -> Option<usize> {
+    let wanted = if larger { Ordering::Greater } else { Ordering::Less };
+    let mut found: Option<usize> = None;
+    for (idx, v) in vals.iter().enumerate() {
+        if same_type_cmp(v, key) != Some(wanted) {
+            continue;
+        }
+        let better = match found {
+            None => true,
+            Some(f) => same_type_cmp(v, &vals[f]) == Some(wanted.reverse()),
+        };
+        if better {
+            found = Some(idx);
+        }
+    }
+    found
+}
+
+// A failed value as an `Err` value for functions that skip or count errors. A circular
+// reference has no value to skip, so it still fails
+fn error_value(res: Result<Arg>) -> Result<Arg> {
+    match res {
+        Err(e) if err_kind(&e) != ErrKind::Circ => Ok(Arg::Err(err_kind(&e))),
+        res => res,
+    }
+}
+
+// POWER and `^`: zero to a negative power is a division by zero
+fn power_of(x: f64, y: f64) -> Result<Arg> {
+    if x == 0.0 && y < 0.0 {
+        return Err(calc_err(ErrKind::Div0, "division by zero"));
+    }
+    num_result(x.powf(y))
+}
+
+// Infinite and undefined results are reported as #NUM!
+fn num_result(f: f64) -> Result<Arg> {
+    if f.is_finite() {
+        Ok(Arg::Number(f))
+    } else {
+        Err(ErrKind::Num.into())
+    }
+}
+
+// Rounds to the number of digits after the decimal point (before it if `digits` is negative).
+// Mode: "round" - half away from zero, "up" - away from zero, "down" - towards zero
+fn round_digits(x: f64, digits: i32, mode: &str) -> f64 {
+    let scale = 10f64.powi(digits.abs());
+    let v = if digits >= 0 { x.abs() * scale } else { x.abs() / scale };
+    // compensate binary representation errors, e.g. 2.675*100 = 267.49999...
+    let eps = v * f64::EPSILON * 4.0;
+    let v = match mode {
+        "round" => (v + eps).round(),
+        "up" => (v - eps).ceil(),
+        _ => (v + eps).floor(),
+    };
+    let v = if digits >= 0 { v / scale } else { v * scale };
+    if v == 0.0 { 0.0 } else { v.copysign(x) }
+}
+
+fn factorial(n: f64) -> f64 {
+    if n < 0.0 {
+        return f64::NAN;
+    }
+    if n > 170.0 {
+        return f64::INFINITY;
+    }
+    (1..=n.trunc() as u64).fold(1.0, |acc, i| acc * i as f64)
+}
+
+// Combines two numbers into a well-distributed seed (splitmix64 finalizer)
+fn mix(a: u64, b: u64) -> u64 {
+    let mut z = a ^ b.wrapping_add(0x9E37_79B9_7F4A_7C15).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+// FNV-1a hash: stable between runs, unlike the standard library hasher
+fn name_hash(name: &str) -> u64 {
+    name.bytes().fold(0xcbf2_9ce4_8422_2325, |h, b| (h ^ b as u64).wrapping_mul(0x0100_0000_01b3))
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+fn sort_nums(nums: &mut [f64]) {
+    nums.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+}
+
+// Result of adding or subtracting values that may be dates or times:
+// date-date gives a number of days, date+-number or date+-time gives a date,
+// time+-time or time+-number gives a time
+fn add_sub(a: Arg, b: Arg, minus: bool) -> Result<Arg> {
+    let is_date = |v: &Arg| matches!(v, Arg::Date(_));
+    let is_time = |v: &Arg| matches!(v, Arg::Time(_));
+    let (da, db, ta, tb) = (is_date(&a), is_date(&b), is_time(&a), is_time(&b));
+    let f1 = try_to_num(a)?;
+    let f2 = try_to_num(b)?;
+    let res = if minus { f1 - f2 } else { f1 + f2 };
+    Ok(if da && db {
+        Arg::Number(res)
+    } else if da || (db && !minus) {
+        Arg::Date(res)
+    } else if ta || tb {
+        Arg::Time(res)
+    } else {
+        Arg::Number(res)
+    })
+}
+
+// Non-negative integer argument, like a number of characters. Fractions are truncated
+fn try_to_count(a: Arg) -> Result<usize> {
+    let n = try_to_num(a)?;
+    if n < 0.0 {
+        return Err(anyhow!("value must not be negative: {}", n));
+    }
+    Ok(n.trunc() as usize)
+}
+
+#[rustfmt::skip]
+#[cfg(test)]
+mod expr_test {
+    use super::*;
+
+    fn calc(sheet: &mut Sheet, expr: &str) -> Arg {
+        sheet.set_cell_text(20, 20, expr, true);
+        let cell = sheet.cell(20, 20);
+        if let Arg::Err(kind) = cell.calculated {
+            return Arg::Str(kind.to_string());
+        }
+        cell.calculated
+    }
+
+    #[test]
+    fn arity_test() {
+        // a program that skipped the syntax check is checked against the function table
+        let mut pages = vec![Sheet::new(0, 80, 25)];
+        for (expr, msg) in [("MID(\"abc\", 2)", "MID requires 3 arguments"), ("SUM()", "SUM requires at least 1 argument"),
+            ("PI(1)", "PI does not take arguments")] {
+            let prog = crate::stack::expr_to_stack(&crate::stack::str_expr_to_vec(expr).unwrap()).unwrap();
+            let err = Expr::default().calculate(&prog, &mut Book::new(&mut pages, 0)).unwrap_err();
+            assert_eq!(err.to_string(), msg, "{}", expr);
+        }
+    }
+
+    #[test]
+    fn aggregate_test() {
+        let mut sheet = Sheet::new(0, 80, 25);
+        let vals = ["1", "2", "text", "2", "", "TRUE", "10"];
+        for (row, v) in vals.iter().enumerate() {
+            sheet.set_cell_text(0, row, v, true);
+        }
+        sheet.set_cell_text(1, 0, "=A1*3", true);
+        let tests: Vec<(&str, Arg)> = vec![
+            ("=sum(A1:A7)", Arg::Number(15.0)),
+            ("=SUM(A1:B1, 4, \"5\")", Arg::Number(13.0)),
+            ("=average(A1:A7)", Arg::Number(3.75)),
+            ("=count(A1:A7)", Arg::Number(4.0)),
+            ("=count(A1:A7, \"4\", \"four\")", Arg::Number(5.0)),
+            ("=counta(A1:A7)", Arg::Number(6.0)),
+            ("=countblank(A1:A9)", Arg::Number(3.0)),
+            ("=min(A1:A7)", Arg::Number(1.0)),
+            ("=max(A1:B7)", Arg::Number(10.0)),
+            ("=median(A1:A7)", Arg::Number(2.0)),
+            ("=median(A1:A2)", Arg::Number(1.5)),
+            ("=mode(A1:A7)", Arg::Number(2.0)),
+            ("=product(A1:A7)", Arg::Number(40.0)),
+            ("=var.p(A1:A7)", Arg::Number(13.1875)),
+            ("=var(2, 4, 6)", Arg::Number(4.0)),
+            ("=stdev(2, 4, 6)", Arg::Number(2.0)),
+            ("=stdev.p(A2, A4)", Arg::Number(0.0)),
+            ("=large(A1:A7, 1)", Arg::Number(10.0)),
+            ("=small(A1:A7, 2)", Arg::Number(2.0)),
+            ("=large(A1:A7, 5)", Arg::Str("#VALUE!".to_string())),
+            ("=average(A3)", Arg::Str("#DIV/0!".to_string())),
+            ("=mode(1, 2, 3)", Arg::Str("#VALUE!".to_string())),
+        ];
+        for (expr, res) in tests {
+            assert_eq!(calc(&mut sheet, expr), res, "{}", expr);
+        }
+    }
+
+    #[test]
+    fn logical_test() {
+        let mut sheet = Sheet::new(0, 80, 25);
+        sheet.set_cell_text(0, 0, "10", true);
+        sheet.set_cell_text(1, 0, "0", true);
+        sheet.set_cell_text(2, 0, "=A1+B1", true);
+        sheet.set_cell_text(3, 0, "=IFS(B1=1, 2)", true);
+        sheet.set_cell_text(0, 1, "TRUE", true);
+        sheet.set_cell_text(1, 1, "text", true);
+        let tests: Vec<(&str, Arg)> = vec![
+            ("=IF(B1=0, 0, A1/B1)", Arg::Number(0.0)),
+            ("=IF(A1=0, 0, A1/B1)", Arg::Str("#DIV/0!".to_string())),
+            ("=IF(B1, 1)", Arg::Bool(false)),
+            ("=IF(C1>5, \"big\", \"small\")", Arg::Str("big".to_string())),
+            ("=SUM(IF(TRUE, A1:A2, 1)+1)", Arg::Number(13.0)),
+            ("=SUM(IF(TRUE, A1:C1, 1))", Arg::Number(20.0)),
+            ("=IFS(A1>20, 1, A1>5, 2, TRUE, 3)", Arg::Number(2.0)),
+            ("=IFS(A1>20, 1)", Arg::Str("#N/A".to_string())),
+            ("=SWITCH(A1, 1, \"one\", 10, \"ten\")", Arg::Str("ten".to_string())),
+            ("=SWITCH(A1, 1, \"one\", \"other\")", Arg::Str("other".to_string())),
+            ("=SWITCH(A1, 1, \"one\")", Arg::Str("#N/A".to_string())),
+            ("=AND(A1, A2, B2)", Arg::Bool(true)),
+            ("=AND(A1:A2, 1=1)", Arg::Bool(true)),
+            ("=AND(A1:B2)", Arg::Bool(false)),
+            ("=AND(B1, A1/B1)", Arg::Bool(false)),
+            ("=OR(A1, A1/B1)", Arg::Bool(true)),
+            ("=OR(B1, FALSE)", Arg::Bool(false)),
+            ("=OR(B2)", Arg::Str("#VALUE!".to_string())),
+            ("=XOR(TRUE, TRUE, A2)", Arg::Bool(true)),
+            ("=XOR(A1:B2)", Arg::Bool(false)),
+            ("=NOT(B1)", Arg::Bool(true)),
+            ("=IFERROR(A1/B1, -1)", Arg::Number(-1.0)),
+            ("=IFERROR(A1/2, -1)", Arg::Number(5.0)),
+            ("=IFNA(D1, 7)", Arg::Number(7.0)),
+            ("=IFNA(A1/B1, 7)", Arg::Str("#DIV/0!".to_string())),
+            ("=D1", Arg::Str("#N/A".to_string())),
+        ];
+        for (expr, res) in tests {
+            assert_eq!(calc(&mut sheet, expr), res, "{}", expr);
+        }
+    }
+
+    #[test]
+    fn compare_test() {
+        let mut sheet = Sheet::new(0, 80, 25);
+        sheet.set_cell_text(0, 0, "10", true);
+        sheet.set_cell_text(0, 1, "TRUE", true);
+        sheet.set_cell_text(0, 2, "Text", true);
+        let tests: Vec<(&str, &str)> = vec![
+            ("=A1<A2", "TRUE"),
+            ("=A3<A2", "TRUE"),
+            ("=A1<A3", "TRUE"),
+            ("=A1=\"10\"", "FALSE"),
+            ("=A3=\"TEXT\"", "TRUE"),
+            ("=A3<>\"text\"", "FALSE"),
+            ("=A4=0", "TRUE"),
+            ("=A4=\"\"", "TRUE"),
+            ("=A4=FALSE", "TRUE"),
+            ("=A4<A1", "TRUE"),
+            ("=A4>=A2", "FALSE"),
+            ("=A2&\"!\"", "TRUE!"),
+            ("=IF(\"true\", 1, 2)", "1"),
+            ("=IF(A3, 1, 2)", "#VALUE!"),
+            ("=SWITCH(A3, \"text\", 1, 2)", "1"),
+            ("=COUNTIF(A1:A4, \"<>text\")", "3"),
+        ];
+        for (expr, res) in tests {
+            assert_eq!(calc(&mut sheet, expr).title(), res, "{}", expr);
+        }
+        calc(&mut sheet, "=SORT(A1:A4, 1, -1)");
+        let vals: Vec<String> = (20..24).map(|row| sheet.shown_cell(20, row).title()).collect();
+        assert_eq!(vals, vec!["TRUE", "Text", "10", ""]);
+    }
+
+    #[test]
+    fn text_test() {
+        let mut sheet = Sheet::new(0, 80, 25);
+        sheet.set_cell_text(0, 0, "ʃʤaíckəʊ", true);
+        sheet.set_cell_text(0, 1, "  imported   label ", true);
+        sheet.set_cell_text(0, 3, "12", true);
+        let tests: Vec<(&str, Arg)> = vec![
+            ("=LEN(A1)", Arg::Number(8.0)),
+            ("=LEN(123.5)", Arg::Number(5.0)),
+            ("=LEFT(A1, 3)", Arg::Str("ʃʤa".to_string())),
+            ("=LEFT(A1)", Arg::Str("ʃ".to_string())),
+            ("=RIGHT(A1, 2)", Arg::Str("əʊ".to_string())),
+            ("=RIGHT(A1, 20)", Arg::Str("ʃʤaíckəʊ".to_string())),
+            ("=MID(A1, 3, 2)", Arg::Str("aí".to_string())),
+            ("=MID(A1, 0, 2)", Arg::Str("#VALUE!".to_string())),
+            ("=UPPER(\"straße\")", Arg::Str("STRASSE".to_string())),
+            ("=LOWER(\"ÀB\")", Arg::Str("àb".to_string())),
+            ("=PROPER(TRIM(A2))", Arg::Str("Imported Label".to_string())),
+            ("=CONCAT(A4:A5, \"-\", 1)", Arg::Str("12-1".to_string())),
+            ("=TEXTJOIN(\",\", TRUE, A3:A4, \"x\")", Arg::Str("12,x".to_string())),
+            ("=TEXTJOIN(\",\", FALSE, A3:A4, \"x\")", Arg::Str(",12,x".to_string())),
+            ("=SUBSTITUTE(\"a-b-c\", \"-\", \"+\")", Arg::Str("a+b+c".to_string())),
+            ("=SUBSTITUTE(\"a-b-c\", \"-\", \"+\", 2)", Arg::Str("a-b+c".to_string())),
+            ("=REPLACE(A1, 2, 3, \"__\")", Arg::Str("ʃ__ckəʊ".to_string())),
+            ("=FIND(\"c\", A1)", Arg::Number(5.0)),
+            ("=FIND(\"B\", \"abcb\")", Arg::Str("#VALUE!".to_string())),
+            ("=SEARCH(\"B\", \"abcb\", 3)", Arg::Number(4.0)),
+            ("=SEARCH(\"l?o\", \"hello\")", Arg::Number(3.0)),
+            ("=SEARCH(\"L*O\", \"hello\")", Arg::Number(3.0)),
+            ("=SEARCH(\"~?\", \"why? not\")", Arg::Number(4.0)),
+            ("=SEARCH(\"x*\", \"hello\")", Arg::Str("#VALUE!".to_string())),
+            ("=FIND(\"l?o\", \"hello\")", Arg::Str("#VALUE!".to_string())),
+            ("=REPT(\"ab\", 3)", Arg::Str("ababab".to_string())),
+            ("=EXACT(\"ab\", \"AB\")", Arg::Bool(false)),
+            ("=EXACT(A4, 12)", Arg::Bool(true)),
+            ("=VALUE(\" -1.5e2 \")", Arg::Number(-150.0)),
+            ("=VALUE(\"25%\")", Arg::Number(0.25)),
+            ("=VALUE(\"12abc\")", Arg::Str("#VALUE!".to_string())),
+            ("=TEXT(A4/7, \"0.000\")", Arg::Str("1.714".to_string())),
+            ("=TEXT(1234.5, \"#,##0.00\")&\" EUR\"", Arg::Str("1,234.50 EUR".to_string())),
+        ];
+        for (expr, res) in tests {
+            assert_eq!(calc(&mut sheet, expr), res, "{}", expr);
+        }
+    }
+    #[test]
+    fn open_range_test() {
+        let mut sheet = Sheet::new(0, 80, 25);
+        for (row, v) in ["1", "2", "3", "4"].iter().enumerate() {
+            sheet.set_cell_text(1, row, v, true);
+        }
+        sheet.set_cell_text(0, 1, "10", true);
+        sheet.set_cell_text(2, 1, "5", true);
+        sheet.set_cell_text(7, 0, "amount", true);
+        sheet.set_cell_text(7, 1, "7", true);
+        sheet.set_cell_text(7, 2, "8", true);
+        let tests: Vec<(&str, &str)> = vec![
+            ("=SUM(B:B)", "10"),
+            ("=SUM(2:2)", "24"),
+            ("=SUM(H2:H)", "15"),
+            ("=COUNTA(H:H)", "3"),
+            ("=SUM(B3:3)", "11"),
+            ("=SUM(B3:B)", "7"),
+            ("=MATCH(8, H:H, 0)", "3"),
+            ("=SUM(A:C)", "25"),
+            ("=SUM(C:A)", "25"),
+            ("=SUM(1:2)", "25"),
+            ("=COUNT(2:1)", "5"),
+        ];
+        for (expr, res) in tests {
+            assert_eq!(calc(&mut sheet, expr).title(), res, "{}", expr);
+        }
+        sheet.set_cell_text(7, 3, "9", true);
+        assert_eq!(calc(&mut sheet, "=SUM(H2:H)").title(), "24");
+        // a formula reading several full columns is recalculated when one of them changes
+        sheet.set_cell_text(10, 10, "=SUM(A:C)", true);
+        sheet.set_cell_text(2, 5, "100", true);
+        assert_eq!(sheet.cell(10, 10).title(), "125");
+    }
+    #[test]
+    fn error_test() {
+        let mut sheet = Sheet::new(0, 80, 25);
+        sheet.set_cell_text(0, 0, "=1/0", true);
+        sheet.set_cell_text(0, 1, "2", true);
+        sheet.set_cell_text(1, 0, "#N/A", true);
+        sheet.set_cell_text(1, 1, "=A1*2", true);
+        sheet.set_cell_text(2, 0, "1", true);
+        sheet.set_cell_text(2, 1, "=1/0", true);
+        sheet.set_cell_text(2, 2, "x", true);
+        let tests: Vec<(&str, &str)> = vec![
+            ("=A1+1", "#DIV/0!"),
+            ("=SUM(A1:A2)", "#DIV/0!"),
+            ("=AVERAGE(C1:C3)", "#DIV/0!"),
+            ("=AND(C1:C3)", "#DIV/0!"),
+            ("=COUNT(C1:C3)", "1"),
+            ("=COUNT(C2)", "0"),
+            ("=COUNTBLANK(C1:C4)", "1"),
+            ("=B1", "#N/A"),
+            ("=NOSUCHFUNC(1)", "#NAME?"),
+            ("='no page'!A1", "#REF!"),
+            ("=U21+1", "#CIRC!"),
+            ("=1+\"a\"", "#VALUE!"),
+            ("=#NUM!", "#NUM!"),
+            ("=IF(TRUE, 1, #N/A)", "1"),
+            ("=ISERROR(A1)", "TRUE"),
+            ("=ISERROR(A2)", "FALSE"),
+            ("=ISERROR(1/0)", "TRUE"),
+            ("=ISERR(B1)", "FALSE"),
+            ("=ISERR(B2)", "TRUE"),
+            ("=ISNA(B1)", "TRUE"),
+            ("=ISNA(#N/A)", "TRUE"),
+            ("=ISNA(A1)", "FALSE"),
+            ("=ERROR.TYPE(A1)", "2"),
+            ("=ERROR.TYPE(B1)", "7"),
+            ("=ERROR.TYPE(\"a\"+1)", "3"),
+            ("=ERROR.TYPE(#REF!)", "4"),
+            ("=ERROR.TYPE(1)", "#N/A"),
+            ("=IFERROR(#REF!, 5)", "5"),
+        ];
+        for (expr, res) in tests {
+            assert_eq!(calc(&mut sheet, expr).title(), res, "{}", expr);
+        }
+        assert_eq!(sheet.cell(0, 0).reason, "division by zero");
+        assert_eq!(sheet.cell(1, 1).reason, "division by zero");
+        calc(&mut sheet, "=1");
+        assert!(sheet.cell(20, 20).reason.is_empty());
+    }
+    #[test]
+    fn lookup_test() {
+        let mut sheet = Sheet::new(0, 80, 25);
+        let rows = [("10", "apple", "1.5"), ("20", "Pear", "2"), ("30", "plum", "3.25"), ("40", "fig", "4")];
+        for (row, (a, b, c)) in rows.iter().enumerate() {
+            sheet.set_cell_text(0, row, a, true);
+            sheet.set_cell_text(1, row, b, true);
+            sheet.set_cell_text(2, row, c, true);
+            sheet.set_cell_text(7, 3 - row, a, true);
+        }
+        sheet.set_cell_text(4, 0, "5", true);
+        sheet.set_cell_text(5, 0, "6", true);
+        sheet.set_cell_text(6, 0, "7", true);
+        sheet.set_cell_text(4, 1, "a", true);
+        sheet.set_cell_text(5, 1, "b", true);
+        sheet.set_cell_text(6, 1, "c", true);
+        // a table with failed cells in the key and the value columns
+        for (row, (j, k)) in [("1", "=1/0"), ("=1/0", "2"), ("x", "3")].iter().enumerate() {
+            sheet.set_cell_text(9, row, j, true);
+            sheet.set_cell_text(10, row, k, true);
+        }
+        for (col, (top, bottom)) in [("=1/0", "a"), ("y", "=1/0"), ("x", "c")].iter().enumerate() {
+            sheet.set_cell_text(9 + col, 3, top, true);
+            sheet.set_cell_text(9 + col, 4, bottom, true);
+        }
+        let tests: Vec<(&str, Arg)> = vec![
+            ("=VLOOKUP(20, A1:C4, 2, FALSE)", Arg::Str("Pear".to_string())),
+            ("=VLOOKUP(25, A1:C4, 3)", Arg::Number(2.0)),
+            ("=VLOOKUP(5, A1:C4, 3)", Arg::Str("#N/A".to_string())),
+            ("=VLOOKUP(25, A1:C4, 3, FALSE)", Arg::Str("#N/A".to_string())),
+            ("=VLOOKUP(\"pear\", B1:C4, 2, FALSE)", Arg::Number(2.0)),
+            ("=IFNA(VLOOKUP(25, A1:C4, 3, FALSE), \"none\")", Arg::Str("none".to_string())),
+            ("=VLOOKUP(10, A1:C4, 5, FALSE)", Arg::Str("#REF!".to_string())),
+            ("=VLOOKUP(10, A1:C4, 0, FALSE)", Arg::Str("#VALUE!".to_string())),
+            ("=HLOOKUP(5, E1:G2, 3, FALSE)", Arg::Str("#REF!".to_string())),
+            ("=INDEX(A1:A3, 5)", Arg::Str("#REF!".to_string())),
+            ("=INDEX(A1:C4, 2, 4)", Arg::Str("#REF!".to_string())),
+            ("=HLOOKUP(6, E1:G2, 2, FALSE)", Arg::Str("b".to_string())),
+            ("=HLOOKUP(100, E1:G2, 2)", Arg::Str("c".to_string())),
+            ("=INDEX(A1:C4, 3, 2)", Arg::Str("plum".to_string())),
+            ("=INDEX(C1:C4, 4)", Arg::Number(4.0)),
+            ("=INDEX(E1:G1, 2)", Arg::Number(6.0)),
+            ("=SUM(INDEX(A1:C4, 0, 3))", Arg::Number(10.75)),
+            ("=MATCH(\"fig\", B1:B4, 0)", Arg::Number(4.0)),
+            ("=MATCH(35, A1:A4)", Arg::Number(3.0)),
+            ("=MATCH(25, H1:H4, -1)", Arg::Number(2.0)),
+            ("=MATCH(1, A1:A4)", Arg::Str("#N/A".to_string())),
+            ("=XLOOKUP(\"plum\", B1:B4, C1:C4)", Arg::Number(3.25)),
+            ("=XLOOKUP(\"kiwi\", B1:B4, C1:C4, \"no\")", Arg::Str("no".to_string())),
+            ("=XLOOKUP(\"kiwi\", B1:B4, C1:C4)", Arg::Str("#N/A".to_string())),
+            ("=XLOOKUP(25, A1:A4, B1:B4, , -1)", Arg::Str("Pear".to_string())),
+            ("=XLOOKUP(25, A1:A4, B1:B4, , 1)", Arg::Str("plum".to_string())),
+            ("=XLOOKUP(7, E1:G1, E2:G2)", Arg::Str("c".to_string())),
+            ("=SUM(XLOOKUP(30, A1:A4, A1:C4))", Arg::Number(33.25)),
+            ("=VLOOKUP(\"x\", J1:K3, 2, FALSE)", Arg::Number(3.0)),
+            ("=VLOOKUP(5, J1:K3, 2, FALSE)", Arg::Str("#N/A".to_string())),
+            ("=VLOOKUP(\"x\", J1:K3, 2)", Arg::Number(3.0)),
+            ("=VLOOKUP(1, J1:K3, 2, FALSE)", Arg::Str("#DIV/0!".to_string())),
+            ("=HLOOKUP(\"x\", J4:L5, 2, FALSE)", Arg::Str("c".to_string())),
+            ("=MATCH(\"x\", J1:J3, 0)", Arg::Number(3.0)),
+            ("=XLOOKUP(\"x\", J1:J3, J1:J3)", Arg::Str("x".to_string())),
+        ];
+        for (expr, res) in tests {
+            assert_eq!(calc(&mut sheet, expr), res, "{}", expr);
+        }
+    }
+    #[test]
+    fn conditional_test() {
+        let mut sheet = Sheet::new(0, 80, 25);
+        let rows = [("apple", "5", "done"), ("Apples", "12", "open"), ("pear", "20", "done"), ("plum", "text", ""), ("apple pie", "8", "done")];
+        for (row, (a, b, c)) in rows.iter().enumerate() {
+            sheet.set_cell_text(0, row, a, true);
+            sheet.set_cell_text(1, row, b, true);
+            sheet.set_cell_text(2, row, c, true);
+        }
+        sheet.set_cell_text(4, 0, ">=10", true);
+        sheet.set_cell_text(3, 0, "2026-10-17", true);
+        sheet.set_cell_text(3, 2, "5", true);
+        // failed cells in the criteria range and in the range to add up
+        for (row, (f, g)) in [("1", "10"), ("=1/0", "20"), ("x", "=1/0")].iter().enumerate() {
+            sheet.set_cell_text(5, row, f, true);
+            sheet.set_cell_text(6, row, g, true);
+        }
+        let tests: Vec<(&str, Arg)> = vec![
+            ("=SUMIF(B1:B5, \">=10\")", Arg::Number(32.0)),
+            ("=SUMIF(B1:B5, E1)", Arg::Number(32.0)),
+            ("=SUMIF(A1:A5, \"apple*\", B1:B5)", Arg::Number(25.0)),
+            ("=SUMIF(A1:A5, \"apple*\", B1)", Arg::Number(25.0)),
+            ("=SUMIF(B1:B5, 20)", Arg::Number(20.0)),
+            ("=COUNTIF(C1:C5, \"<>done\")", Arg::Number(2.0)),
+            ("=COUNTIF(C1:C5, \"\")", Arg::Number(1.0)),
+            ("=COUNTIF(A1:A5, \"?ear\")", Arg::Number(1.0)),
+            ("=AVERAGEIF(C1:C5, \"done\", B1:B5)", Arg::Number(11.0)),
+            ("=AVERAGEIF(C1:C5, \"none\", B1:B5)", Arg::Str("#DIV/0!".to_string())),
+            ("=SUMIFS(B1:B5, A1:A5, \"apple*\", C1:C5, \"done\")", Arg::Number(13.0)),
+            ("=COUNTIFS(A1:A5, \"p*\", C1:C5, \"done\")", Arg::Number(1.0)),
+            ("=AVERAGEIFS(B1:B5, C1:C5, \"done\", B1:B5, \"<10\")", Arg::Number(6.5)),
+            ("=MINIFS(B1:B5, C1:C5, \"done\")", Arg::Number(5.0)),
+            ("=MAXIFS(B1:B5, C1:C5, \"done\", A1:A5, \"a*\")", Arg::Number(8.0)),
+            ("=MAXIFS(B1:B5, C1:C5, \"closed\")", Arg::Number(0.0)),
+            ("=SUMIFS(B1:B5, A1:A4, \"apple\")", Arg::Str("#VALUE!".to_string())),
+            ("=SUMIF(C1:C5, \"done\", D1:D5)", Arg::Number(46317.0)),
+            ("=MAXIFS(D1:D5, C1:C5, \"done\")", Arg::Date(46312.0)),
+            ("=MINIFS(D1:D5, C1:C5, \"done\")", Arg::Number(5.0)),
+            ("=AVERAGEIFS(D1:D5, C1:C5, \"done\", D1:D5, \">10\")", Arg::Number(46312.0)),
+            ("=COUNTIF(F1:F3, \"x\")", Arg::Number(1.0)),
+            ("=COUNTIF(F1:F3, \"<>x\")", Arg::Number(1.0)),
+            ("=SUMIF(F1:F3, \">0\")", Arg::Number(1.0)),
+            ("=SUMIF(F1:F3, \">0\", G1:G3)", Arg::Number(10.0)),
+            ("=SUMIF(F1:F3, \"x\", G1:G3)", Arg::Str("#DIV/0!".to_string())),
+            ("=COUNTIFS(F1:F3, \"<>\", G1:G3, \">0\")", Arg::Number(1.0)),
+            ("=MAXIFS(G1:G3, F1:F3, \"<>x\")", Arg::Number(10.0)),
+        ];
+        for (expr, res) in tests {
+            assert_eq!(calc(&mut sheet, expr), res, "{}", expr);
+        }
+    }
+    #[test]
+    fn date_test() {
+        let mut sheet = Sheet::new(0, 80, 25);
+        sheet.set_cell_text(0, 0, "2026-10-17", true);
+        sheet.set_cell_text(0, 1, "2024-02-29 18:45", true);
+        sheet.set_cell_text(0, 2, "14:30", true);
+        sheet.set_cell_text(0, 3, "08:15", true);
+        assert_eq!(sheet.cell(0, 0).calculated, Arg::Date(46312.0));
+        assert_eq!(sheet.cell(0, 2).title(), "14:30");
+        let tests: Vec<(&str, &str)> = vec![
+            ("=A1+30", "2026-11-16"),
+            ("=A1-A2", "960.21875"),
+            ("=A3-A4", "06:15"),
+            ("=A1+A3", "2026-10-17 14:30"),
+            ("=A3+A3", "29:00"),
+            ("=A1>A2", "TRUE"),
+            ("=A1=DATE(2026, 10, 17)", "TRUE"),
+            ("=DATE(2026, 14, 0)", "2027-01-31"),
+            ("=DATE(26, 1, 1)", "1926-01-01"),
+            ("=TIME(25, 90, 30)", "02:30:30"),
+            ("=YEAR(A1)", "2026"),
+            ("=MONTH(A1)", "10"),
+            ("=DAY(A1)", "17"),
+            ("=YEAR(A1)&\"/\"&MONTH(A1)&\"/\"&DAY(A1)", "2026/10/17"),
+            ("=HOUR(A2)*60+MINUTE(A2)", "1125"),
+            ("=HOUR(A3)", "14"),
+            ("=YEAR(\"2020-05-06\")", "2020"),
+            ("=WEEKDAY(A1)", "7"),
+            ("=WEEKDAY(A1, 2)", "6"),
+            ("=WEEKDAY(A1, 3)", "5"),
+            ("=EDATE(\"2024-01-31\", 1)", "2024-02-29"),
+            ("=EDATE(A1, -12)", "2025-10-17"),
+            ("=EOMONTH(A1, 0)", "2026-10-31"),
+            ("=EOMONTH(A1, 4)", "2027-02-28"),
+            ("=DATEDIF(A2, A1, \"Y\")", "2"),
+            ("=DATEDIF(A2, A1, \"M\")", "31"),
+            ("=DATEDIF(A2, A1, \"D\")", "961"),
+            ("=DATEDIF(A2, A1, \"YM\")", "7"),
+            ("=DATEDIF(A2, A1, \"MD\")", "18"),
+            ("=DATEDIF(A2, A1, \"YD\")", "231"),
+            ("=DATEDIF(A1, A2, \"D\")", "#VALUE!"),
+            ("=COUNTIF(A1:A2, \">=2025-01-01\")", "1"),
+            ("=MAX(A1:A2)", "46312"),
+            ("=TODAY()<=NOW()", "TRUE"),
+        ];
+        for (expr, res) in tests {
+            assert_eq!(calc(&mut sheet, expr).title(), res, "{}", expr);
+        }
+    }
+    #[test]
+    fn math_test() {
+        let mut sheet = Sheet::new(0, 80, 25);
+        sheet.set_cell_text(0, 0, "12", true);
+        sheet.set_cell_text(0, 1, "18", true);
+        sheet.set_cell_text(0, 2, "=SQRT(-1)", true);
+        let tests: Vec<(&str, &str)> = vec![
+            ("=ABS(-2.5)", "2.5"),
+            ("=ROUND(2.675, 2)", "2.68"),
+            ("=ROUND(-2.5)", "-3"),
+            ("=ROUND(1234.5, -2)", "1200"),
+            ("=ROUND(-0.4)", "0"),
+            ("=ROUNDUP(1.201, 2)", "1.21"),
+            ("=ROUNDUP(-1.201, 1)", "-1.3"),
+            ("=ROUNDDOWN(1.299, 1)", "1.2"),
+            ("=TRUNC(-7.9)", "-7"),
+            ("=INT(-7.9)", "-8"),
+            ("=MOD(-7, 3)", "2"),
+            ("=MOD(7, -3)", "-2"),
+            ("=MOD(7, 0)", "#DIV/0!"),
+            ("=SIGN(-0.1)+SIGN(0)", "-1"),
+            ("=SQRT(16)", "4"),
+            ("=SQRT(-1)", "#NUM!"),
+            ("=A3+1", "#NUM!"),
+            ("=IFERROR(SQRT(-1), 0)", "0"),
+            ("=POWER(2, 10)", "1024"),
+            ("=POWER(-8, 1/3)", "#NUM!"),
+            ("=2^10", "1024"),
+            ("=0^-1", "#DIV/0!"),
+            ("=(-8)^(1/3)", "#NUM!"),
+            ("=10^400", "#NUM!"),
+            ("=EXP(0)+LN(1)", "1"),
+            ("=EXP(1000)", "#NUM!"),
+            ("=LN(0)", "#NUM!"),
+            ("=LOG(1000)", "3"),
+            ("=LOG(8, 2)", "3"),
+            ("=LOG(-1, 2)", "#NUM!"),
+            ("=LOG10(0.01)", "-2"),
+            ("=ROUND(PI(), 4)", "3.1416"),
+            ("=ROUND(SIN(PI()/6), 10)", "0.5"),
+            ("=COS(0)", "1"),
+            ("=ROUND(TAN(PI()/4), 10)", "1"),
+            ("=ASIN(2)", "#NUM!"),
+            ("=DEGREES(ACOS(0))", "90"),
+            ("=DEGREES(ATAN(1))", "45"),
+            ("=DEGREES(ATAN2(-1, 0))", "180"),
+            ("=ATAN2(0, 0)", "#DIV/0!"),
+            ("=RADIANS(180)=PI()", "TRUE"),
+            ("=CEILING(2.1)", "3"),
+            ("=CEILING(7, 5)", "10"),
+            ("=CEILING(-2.5, 2)", "-2"),
+            ("=CEILING(0.3, 0.1)", "0.3"),
+            ("=CEILING(2, -1)", "#NUM!"),
+            ("=FLOOR(7, 5)", "5"),
+            ("=FLOOR(-2.5, 2)", "-4"),
+            ("=GCD(A1:A2, 30)", "6"),
+            ("=LCM(A1:A2)", "36"),
+            ("=GCD(-4)", "#NUM!"),
+            ("=FACT(5)", "120"),
+            ("=FACT(0)", "1"),
+            ("=FACT(-1)", "#NUM!"),
+            ("=FACT(1000)", "#NUM!"),
+        ];
+        for (expr, res) in tests {
+            assert_eq!(calc(&mut sheet, expr).title(), res, "{}", expr);
+        }
+    }
+    #[test]
+    fn random_test() {
+        let mut sheet = Sheet::new(0, 80, 25);
+        for _ in 0..20 {
+            match calc(&mut sheet, "=RAND()") {
+                Arg::Number(n) => assert!((0.0..1.0).contains(&n)),
+                v => panic!("RAND returned {:?}", v),
+            }
+            match calc(&mut sheet, "=RANDBETWEEN(-2, 2.5)") {
+                Arg::Number(n) => assert!((-2.0..=2.0).contains(&n) && n.fract() == 0.0),
+                v => panic!("RANDBETWEEN returned {:?}", v),
+            }
+            match calc(&mut sheet, "=RANDARRAY(1, 1, 10, 20, TRUE)") {
+                Arg::Number(n) => assert!((10.0..=20.0).contains(&n) && n.fract() == 0.0),
+                v => panic!("RANDARRAY returned {:?}", v),
+            }
+        }
+        assert_eq!(calc(&mut sheet, "=RANDBETWEEN(5, 3)").title(), "#NUM!");
+        assert_eq!(calc(&mut sheet, "=RANDARRAY(1, 1, 2, 1)").title(), "#NUM!");
+
+        // the same seed gives the same numbers regardless of the calculation order
+        sheet.seed = Some(42);
+        sheet.set_cell_text(0, 0, "=RAND()", true);
+        sheet.set_cell_text(1, 0, "=RAND()-RAND()", true);
+        sheet.set_cell_text(2, 0, "=A1+RANDBETWEEN(1, 1000000)", true);
+        let first: Vec<Arg> = (0..3).map(|col| sheet.cell(col, 0).calculated).collect();
+        assert_ne!(first[0], sheet.cell(1, 0).calculated);
+        assert_ne!(first[1], Arg::Number(0.0));
+        let mut other = Sheet::new(0, 80, 25);
+        other.seed = Some(42);
+        other.set_cell_text(2, 0, "=A1+RANDBETWEEN(1, 1000000)", true);
+        other.set_cell_text(1, 0, "=RAND()-RAND()", true);
+        other.set_cell_text(0, 0, "=RAND()", true);
+        let second: Vec<Arg> = (0..3).map(|col| other.cell(col, 0).calculated).collect();
+        assert_eq!(first, second);
+        other.seed = Some(43);
+        other.recalc_cells();
+        assert_ne!(other.cell(0, 0).calculated, first[0]);
+    }
+    #[test]
+    fn finance_test() {
+        let mut sheet = Sheet::new(0, 80, 25);
+        let flow = [("-10000", "2008-01-01"), ("2750", "2008-03-01"), ("4250", "2008-10-30"), ("3250", "2009-02-15"), ("2750", "2009-04-01")];
+        for (row, (v, d)) in flow.iter().enumerate() {
+            sheet.set_cell_text(0, row, v, true);
+            sheet.set_cell_text(1, row, d, true);
+        }
+        sheet.set_cell_text(2, 0, "39448", true);
+        sheet.set_cell_text(2, 1, "39508", true);
+        sheet.set_cell_text(2, 2, "39400", true);
+        let tests: Vec<(&str, &str)> = vec![
+            ("=ROUND(PMT(8%/12, 10, 10000), 2)", "-1037.03"),
+            ("=ROUND(PMT(8%/12, 10, 10000, 0, 1), 2)", "-1030.16"),
+            ("=PMT(0, 10, 1000)", "-100"),
+            ("=ROUND(PV(0.08/12, 240, 500), 2)", "-59777.15"),
+            ("=ROUND(FV(0.06/12, 10, -200, -500, 1), 2)", "2581.4"),
+            ("=ROUND(NPER(1%, -100, -1000, 10000, 1), 4)", "59.6739"),
+            ("=NPER(10%, -10, 1000)", "#NUM!"),
+            ("=ROUND(RATE(48, -200, 8000), 6)", "0.007701"),
+            ("=RATE(10, 100, 1000)", "#NUM!"),
+            ("=ROUND(NPV(10%, -10000, 3000, 4200, 6800), 2)", "1188.44"),
+            ("=ROUND(NPV(9%, A2:A5) + A1, 2)", "557.84"),
+            ("=ROUND(IRR(A1:A5), 6)", "0.115413"),
+            ("=IRR(A2:A5)", "#NUM!"),
+            ("=ROUND(XNPV(0.09, A1:A5, B1:B5), 2)", "2086.65"),
+            ("=ROUND(XIRR(A1:A5, B1:B5), 6)", "0.373363"),
+            ("=ROUND(XNPV(0.09, A1:A2, C1:C2), 2)", "-7288.68"),
+            ("=XIRR(A1:A5, B1:B4)", "#NUM!"),
+            ("=XNPV(0.09, A1:A3, C1:C3)", "#NUM!"),
+        ];
+        for (expr, res) in tests {
+            assert_eq!(calc(&mut sheet, expr).title(), res, "{}", expr);
+        }
+    }
+
+    #[test]
+    fn let_lambda_test() {
+        let mut sheet = Sheet::new(0, 80, 25);
+        for (row, v) in ["1", "2", "3"].iter().enumerate() {
+            sheet.set_cell_text(0, row, v, true);
+        }
+        sheet.set_cell_text(23, 0, "100", true);
+        for (name, val) in [("double", "LAMBDA(x, x*2)"), ("fact", "LAMBDA(n, IF(n<=1, 1, n*fact(n-1)))"),
+            ("endless", "LAMBDA(n, endless(n+1))")] {
+            let f = sheet.parse_name_value(val);
+            sheet.names.insert(name.to_string(), f);
+        }
+        assert_eq!(sheet.names["double"].to_expr(), "LAMBDA(x, x*2)");
+        let tests: Vec<(&str, &str)> = vec![
+            ("=LET(x, 2, y, x*3, x+y)", "8"),
+            ("=LET(x, A1:A3, SUM(x)*2)", "12"),
+            ("=LET(x, 1, LET(x, 5, x)+x)", "6"),
+            ("=LET(x, 5, x)+SUM(x:x)", "105"),
+            ("=LET(f, LAMBDA(a, b, a*b), f(3, 4))", "12"),
+            ("=LET(k, 2, f, LAMBDA(n, n*k), f(5))", "10"),
+            ("=LET(k, 2, f, LAMBDA(n, n*k), LET(k, 100, f(5)))", "10"),
+            ("=LET(adder, LAMBDA(a, LAMBDA(b, a+b)), plus, adder(2), plus(3))", "5"),
+            ("=double(A3)+1", "7"),
+            ("=fact(5)", "120"),
+            ("=endless(1)", "#NUM!"),
+            ("=double(1, 2)", "#VALUE!"),
+            ("=LAMBDA(x, x)", "#VALUE!"),
+            ("=LET(x, 1)", "#VALUE!"),
+            ("=LET(A1, 1, A1)", "#VALUE!"),
+            ("=twice(1)", "#NAME?"),
+        ];
+        for (expr, res) in tests {
+            assert_eq!(calc(&mut sheet, expr).title(), res, "{}", expr);
+        }
+    }
+
+    #[test]
+    fn array_test() {
+        // values of the array the formula in U21 spills, by rows
+        fn spill(sheet: &mut Sheet, expr: &str) -> Vec<String> {
+            calc(sheet, expr);
+            let mut rows = Vec::new();
+            for row in 20..30 {
+                let vals: Vec<String> = (20..30).map(|col| sheet.shown_cell(col, row).title())
+                    .take_while(|t| !t.is_empty()).collect();
+                if vals.is_empty() {
+                    break;
+                }
+                rows.push(vals.join(","));
+            }
+            rows
+        }
+        let mut sheet = Sheet::new(0, 80, 25);
+        let data = [("b", "2", "TRUE"), ("A", "3", "FALSE"), ("c", "1", "TRUE"), ("a", "3", "FALSE")];
+        for (row, (a, b, c)) in data.iter().enumerate() {
+            sheet.set_cell_text(0, row, a, true);
+            sheet.set_cell_text(1, row, b, true);
+            sheet.set_cell_text(2, row, c, true);
+        }
+        assert_eq!(spill(&mut sheet, "=SEQUENCE(2, 3, 10, 5)"), vec!["10,15,20", "25,30,35"]);
+        assert_eq!(spill(&mut sheet, "=SEQUENCE(3)"), vec!["1", "2", "3"]);
+        assert_eq!(spill(&mut sheet, "=A1:B2"), vec!["b,2", "A,3"]);
+        assert_eq!(spill(&mut sheet, "=TRANSPOSE(A1:B2)"), vec!["b,A", "2,3"]);
+        assert_eq!(spill(&mut sheet, "=SORT(A1:B4)"), vec!["A,3", "a,3", "b,2", "c,1"]);
+        assert_eq!(spill(&mut sheet, "=SORT(A1:B4, 2, -1)"), vec!["A,3", "a,3", "b,2", "c,1"]);
+        assert_eq!(spill(&mut sheet, "=SORT(B1:B4,,,TRUE)"), vec!["2", "3", "1", "3"]);
+        assert_eq!(spill(&mut sheet, "=SORTBY(A1:A4, B1:B4, 1, A1:A4, -1)"), vec!["c", "b", "A", "a"]);
+        assert_eq!(spill(&mut sheet, "=FILTER(A1:B4, C1:C4)"), vec!["b,2", "c,1"]);
+        assert_eq!(spill(&mut sheet, "=FILTER(A1:C1, C1:E1)"), vec!["b"]);
+        assert_eq!(spill(&mut sheet, "=FILTER(A1:A4, B1:B4>2)"), vec!["A", "a"]);
+        assert_eq!(spill(&mut sheet, "=UNIQUE(A1:A4)"), vec!["b", "A", "c"]);
+        assert_eq!(spill(&mut sheet, "=UNIQUE(B1:B4,,TRUE)"), vec!["2", "1"]);
+        assert_eq!(spill(&mut sheet, "=UNIQUE(A2:B4)"), vec!["A,3", "c,1"]);
+        assert_eq!(spill(&mut sheet, "=RANDARRAY(2, 2, 5, 5)"), vec!["5,5", "5,5"]);
+        let tests: Vec<(&str, &str)> = vec![
+            ("=SUM(SEQUENCE(4))", "10"),
+            ("=COUNTA(UNIQUE(A1:A4))", "3"),
+            ("=SEQUENCE(1, 1, 7)", "7"),
+            ("=SEQUENCE(0)", "#CALC!"),
+            ("=FILTER(A1:A2, D1:D2)", "#CALC!"),
+            ("=FILTER(A1:A2, D1:D2, \"none\")", "none"),
+            ("=FILTER(A1:A4, C1:C2)", "#VALUE!"),
+            ("=SORT(A1:B4, 3)", "#VALUE!"),
+            ("=SORTBY(A1:A4, B1:B3)", "#VALUE!"),
+            ("=ERROR.TYPE(SEQUENCE(0))", "14"),
+        ];
+        for (expr, res) in tests {
+            assert_eq!(calc(&mut sheet, expr).title(), res, "{}", expr);
+        }
+    }
+
+    #[test]
+    fn element_wise_test() {
+        let mut sheet = Sheet::new(0, 80, 25);
+        let data = [("1", "2", "TRUE"), ("2", "3", "x"), ("3", "1", ""), ("4", "3", "=1/0")];
+        for (row, (a, b, c)) in data.iter().enumerate() {
+            sheet.set_cell_text(0, row, a, true);
+            sheet.set_cell_text(1, row, b, true);
+            sheet.set_cell_text(2, row, c, true);
+        }
+        let tests: Vec<(&str, &str)> = vec![
+            ("=SUM((A1:A4>2)*B1:B4)", "4"),
+            ("=SUM(A1:A4*B1:B4)", "23"),
+            ("=SUM(-A1:A4)", "-10"),
+            ("=SUM(A1:A2*B1:C1)", "9"),
+            ("=SUM(A1:A2*C1:C2)", "#VALUE!"),
+            ("=COUNT(A1:A4*B1:B4)", "4"),
+            ("=COUNT(A1:A4>2)", "0"),
+            ("=COUNT(1/(A1:A4-2))", "3"),
+            ("=COUNTA(1/(A1:A4-2))", "4"),
+            ("=COUNTA(1/0, 2)", "2"),
+            ("=COUNT(1/0, 2)", "1"),
+            ("=COUNTA(C1:C4)", "3"),
+            ("=COUNT(A1, C4, 1/0)", "1"),
+            ("=COUNTA(A1:A4&\"!\")", "4"),
+            ("=SUM((C1:C3=\"\")*1)", "1"),
+            ("=A1:A2+10", "11"),
+            ("=SUM(A1:A4^2)", "30"),
+            ("=SUMPRODUCT(A1:A4, B1:B4)", "23"),
+            ("=SUMPRODUCT((A1:A4>1)*(B1:B4=3), A1:A4)", "6"),
+            ("=SUMPRODUCT(A1:A3, C1:C3)", "0"),
+            ("=SUMPRODUCT(A1:A4)", "10"),
+            ("=SUMPRODUCT(A1:A4, B1:B3)", "#VALUE!"),
+            ("=SUMPRODUCT(A1:A4, C1:C4)", "#DIV/0!"),
+        ];
+        for (expr, res) in tests {
+            assert_eq!(calc(&mut sheet, expr).title(), res, "{}", expr);
+        }
+        calc(&mut sheet, "=B1:B4*A1:A2");
+        let vals: Vec<String> = (20..24).map(|row| sheet.shown_cell(20, row).title()).collect();
+        assert_eq!(vals, vec!["2", "6", "#N/A", "#N/A"]);
     }
 }
@@ -0,0 +1,137 @@
+// Time value of money. Cash paid out is negative, cash received is positive.
+// `tp` is the payment type: 0 - at the end of a period, 1 - at the beginning
+
+const MAX_ITERATIONS: usize = 100;
+const PRECISION: f64 = 1e-10;
+
+// Future value of the loan or investment combined with the value of all payments
+fn balance(rate: f64, nper: f64, pmt: f64, pv: f64, fv: f64, tp: f64) -> f64 {
+    if rate == 0.0 {
+        return pv + pmt * nper + fv;
+    }
+    let f = (1.0 + rate).powf(nper);
+    pv * f + pmt * (1.0 + rate * tp) * (f - 1.0) / rate + fv
+}
+
+pub fn pmt(rate: f64, nper: f64, pv: f64, fv: f64, tp: f64) -> f64 {
+    if rate == 0.0 {
+        return -(pv + fv) / nper;
+    }
+    let f = (1.0 + rate).powf(nper);
+    -(rate * (fv + pv * f)) / ((1.0 + rate * tp) * (f - 1.0))
+}
+
+pub fn pv(rate: f64, nper: f64, pmt: f64, fv: f64, tp: f64) -> f64 {
+    if rate == 0.0 {
+        return -(fv + pmt * nper);
+    }
+    let f = (1.0 + rate).powf(nper);
+    -(fv + pmt * (1.0 + rate * tp) * (f - 1.0) / rate) / f
+}
+
+pub fn fv(rate: f64, nper: f64, pmt: f64, pv: f64, tp: f64) -> f64 {
+    -balance(rate, nper, pmt, pv, 0.0, tp)
+}
+
+// Number of periods. NaN if the payments never cover the loan
+pub fn nper(rate: f64, pmt: f64, pv: f64, fv: f64, tp: f64) -> f64 {
+    if rate == 0.0 {
+        return -(pv + fv) / pmt;
+    }
+    let p = pmt * (1.0 + rate * tp);
+    ((p - fv * rate) / (p + pv * rate)).ln() / (1.0 + rate).ln()
+}
+
+// Finds a root of the function with Newton's method. None if it does not converge
+fn solve<F: Fn(f64) -> f64>(f: F, guess: f64) -> Option<f64> {
+    let mut x = guess;
+    for _ in 0..MAX_ITERATIONS {
+        let y = f(x);
+        let h = (x.abs() * 1e-6).max(1e-8);
+        let dy = (f(x + h) - f(x - h)) / (2.0 * h);
+        if !y.is_finite() || !dy.is_finite() || dy == 0.0 {
+            return None;
+        }
+        let next = x - y / dy;
+        if !next.is_finite() || next <= -1.0 {
+            return None;
+        }
+        if (next - x).abs() < PRECISION {
+            return Some(next);
+        }
+        x = next;
+    }
+    None
+}
+
+pub fn rate(nper: f64, pmt: f64, pv: f64, fv: f64, tp: f64, guess: f64) -> Option<f64> {
+    solve(|r| balance(r, nper, pmt, pv, fv, tp), guess)
+}
+
+// Net present value of payments at the end of periods 1, 2, ...
+pub fn npv(rate: f64, values: &[f64]) -> f64 {
+    values.iter().enumerate().map(|(i, v)| v / (1.0 + rate).powi(i as i32 + 1)).sum()
+}
+
+fn has_both_signs(values: &[f64]) -> bool {
+    values.iter().any(|&v| v > 0.0) && values.iter().any(|&v| v < 0.0)
+}
+
+// Internal rate of return: the rate at which the net present value of the cash flow is zero.
+// The first value is at the start of the first period
+pub fn irr(values: &[f64], guess: f64) -> Option<f64> {
+    if !has_both_signs(values) {
+        return None;
+    }
+    solve(|r| values.iter().enumerate().map(|(i, v)| v / (1.0 + r).powi(i as i32)).sum(), guess)
+}
+
+// Net present value of payments at arbitrary dates. Dates are day numbers
+pub fn xnpv(rate: f64, values: &[f64], dates: &[f64]) -> f64 {
+    let start = dates.first().copied().unwrap_or(0.0);
+    values.iter().zip(dates).map(|(v, d)| v / (1.0 + rate).powf((d - start) / 365.0)).sum()
+}
+
+pub fn xirr(values: &[f64], dates: &[f64], guess: f64) -> Option<f64> {
+    if !has_both_signs(values) {
+        return None;
+    }
+    solve(|r| xnpv(r, values, dates), guess)
+}
+
+#[rustfmt::skip]
+#[cfg(test)]
+mod finance_test {
+    use super::*;
+
+    fn near(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-6
+    }
+
+    #[test]
+    fn annuity_test() {
+        assert!(near(pmt(0.08 / 12.0, 10.0, 10000.0, 0.0, 0.0), -1037.0320893591));
+        assert!(near(pmt(0.08 / 12.0, 10.0, 10000.0, 0.0, 1.0), -1030.1643271779));
+        assert!(near(pmt(0.0, 10.0, 1000.0, 0.0, 0.0), -100.0));
+        assert!(near(pv(0.08 / 12.0, 240.0, 500.0, 0.0, 0.0), -59777.1458511878));
+        assert!(near(fv(0.06 / 12.0, 10.0, -200.0, -500.0, 1.0), 2581.4033740601));
+        assert!(near(fv(0.0, 12.0, -100.0, -1000.0, 0.0), 2200.0));
+        assert!(near(nper(0.01, -100.0, -1000.0, 10000.0, 1.0), 59.6738656742));
+        assert!(nper(0.1, -10.0, 1000.0, 0.0, 0.0).is_nan());
+    }
+
+    #[test]
+    fn solver_test() {
+        let r = rate(48.0, -200.0, 8000.0, 0.0, 0.0, 0.1).unwrap();
+        assert!(near(r, 0.0077014724));
+        let vals = [-70000.0, 12000.0, 15000.0, 18000.0, 21000.0, 26000.0];
+        assert!(near(irr(&vals, 0.1).unwrap(), 0.0866309480));
+        assert!(near(irr(&vals[..5], 0.1).unwrap(), -0.0212448362));
+        assert_eq!(irr(&[100.0, 200.0], 0.1), None);
+        assert!(near(npv(0.1, &[-10000.0, 3000.0, 4200.0, 6800.0]), 1188.4434123352));
+        let vals = [-10000.0, 2750.0, 4250.0, 3250.0, 2750.0];
+        let dates = [39448.0, 39508.0, 39751.0, 39859.0, 39904.0];
+        assert!(near(xnpv(0.09, &vals, &dates), 2086.6476020315));
+        assert!(near(xirr(&vals, &dates, 0.1).unwrap(), 0.3733625335));
+    }
+}
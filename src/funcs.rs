@@ -0,0 +1,247 @@
+// Built-in functions and their parameters. Optional parameters are in brackets, "..." means
+// that the previous ones may repeat. The parameters give the number of arguments a function accepts
+// and the signature shown while a formula is edited. Functions with pairs of arguments(IFS, SUMIFS,
+// LET) check the pairs when they are calculated
+
+const MANY: usize = usize::MAX;
+
+pub struct FuncInfo {
+    pub name: &'static str,
+    params: &'static str,
+}
+
+const fn func(name: &'static str, params: &'static str) -> FuncInfo {
+    FuncInfo { name, params }
+}
+
+impl FuncInfo {
+    // Parameters split at commas that are not inside brackets: `[parameter1, ...]` is one parameter
+    pub fn params(&self) -> Vec<&'static str> {
+        let mut res = Vec::new();
+        let (mut depth, mut start) = (0, 0);
+        for (idx, c) in self.params.char_indices() {
+            match c {
+                '[' => depth += 1,
+                ']' => depth -= 1,
+                ',' if depth == 0 => {
+                    res.push(self.params[start..idx].trim());
+                    start = idx + 1;
+                },
+                _ => {},
+            }
+        }
+        if !self.params[start..].trim().is_empty() {
+            res.push(self.params[start..].trim());
+        }
+        res
+    }
+    pub fn min_args(&self) -> usize {
+        self.params().iter().filter(|p| !p.starts_with('[') && !p.contains("...")).count()
+    }
+    pub fn max_args(&self) -> usize {
+        let params = self.params();
+        if params.iter().any(|p| p.contains("...")) { MANY } else { params.len() }
+    }
+}
+
+const FUNCS: &[FuncInfo] = &[
+    func("ABS", "number"),
+    func("ACOS", "number"),
+    func("AND", "logical1, [logical2], ..."),
+    func("ASIN", "number"),
+    func("ATAN", "number"),
+    func("ATAN2", "x_num, y_num"),
+    func("AVERAGE", "number1, [number2], ..."),
+    func("AVERAGEIF", "range, criteria, [average_range]"),
+    func("AVERAGEIFS", "average_range, criteria_range1, criteria1, ..."),
+    func("CEILING", "number, [significance]"),
+    func("CONCAT", "text1, [text2], ..."),
+    func("CONCATENATE", "text1, [text2], ..."),
+    func("COS", "number"),
+    func("COUNT", "value1, [value2], ..."),
+    func("COUNTA", "value1, [value2], ..."),
+    func("COUNTBLANK", "range"),
+    func("COUNTIF", "range, criteria"),
+    func("COUNTIFS", "criteria_range1, criteria1, ..."),
+    func("DATE", "year, month, day"),
+    func("DATEDIF", "start_date, end_date, unit"),
+    func("DAY", "date"),
+    func("DEGREES", "angle"),
+    func("EDATE", "start_date, months"),
+    func("EOMONTH", "start_date, months"),
+    func("ERROR.TYPE", "error_val"),
+    func("EXACT", "text1, text2"),
+    func("EXP", "number"),
+    func("FACT", "number"),
+    func("FILTER", "array, include, [if_empty]"),
+    func("FIND", "find_text, within_text, [start_num]"),
+    func("FLOOR", "number, [significance]"),
+    func("FV", "rate, nper, pmt, [pv], [type]"),
+    func("GCD", "number1, [number2], ..."),
+    func("HLOOKUP", "lookup_value, table_array, row_index_num, [range_lookup]"),
+    func("HOUR", "time"),
+    func("IF", "logical_test, value_if_true, [value_if_false]"),
+    func("IFERROR", "value, value_if_error"),
+    func("IFNA", "value, value_if_na"),
+    func("IFS", "logical_test1, value_if_true1, ..."),
+    func("INDEX", "array, row_num, [column_num]"),
+    func("INT", "number"),
+    func("IRR", "values, [guess]"),
+    func("ISERR", "value"),
+    func("ISERROR", "value"),
+    func("ISNA", "value"),
+    func("LAMBDA", "[parameter1, ...], calculation"),
+    func("LARGE", "array, k"),
+    func("LCM", "number1, [number2], ..."),
+    func("LEFT", "text, [num_chars]"),
+    func("LEN", "text"),
+    func("LET", "name1, name_value1, calculation_or_name2, ..."),
+    func("LN", "number"),
+    func("LOG", "number, [base]"),
+    func("LOG10", "number"),
+    func("LOWER", "text"),
+    func("MATCH", "lookup_value, lookup_array, [match_type]"),
+    func("MAX", "number1, [number2], ..."),
+    func("MAXIFS", "max_range, criteria_range1, criteria1, ..."),
+    func("MEDIAN", "number1, [number2], ..."),
+    func("MID", "text, start_num, num_chars"),
+    func("MIN", "number1, [number2], ..."),
+    func("MINIFS", "min_range, criteria_range1, criteria1, ..."),
+    func("MINUTE", "time"),
+    func("MOD", "number, divisor"),
+    func("MODE", "number1, [number2], ..."),
+    func("MONTH", "date"),
+    func("NOT", "logical"),
+    func("NOW", ""),
+    func("NPER", "rate, pmt, pv, [fv], [type]"),
+    func("NPV", "rate, value1, [value2], ..."),
+    func("OR", "logical1, [logical2], ..."),
+    func("PI", ""),
+    func("PMT", "rate, nper, pv, [fv], [type]"),
+    func("POWER", "number, power"),
+    func("PRODUCT", "number1, [number2], ..."),
+    func("PROPER", "text"),
+    func("PV", "rate, nper, pmt, [fv], [type]"),
+    func("RADIANS", "angle"),
+    func("RAND", ""),
+    func("RANDARRAY", "[rows], [columns], [min], [max], [whole_number]"),
+    func("RANDBETWEEN", "bottom, top"),
+    func("RATE", "nper, pmt, pv, [fv], [type], [guess]"),
+    func("REPLACE", "old_text, start_num, num_chars, new_text"),
+    func("REPT", "text, number_times"),
+    func("RIGHT", "text, [num_chars]"),
+    func("ROUND", "number, [num_digits]"),
+    func("ROUNDDOWN", "number, [num_digits]"),
+    func("ROUNDUP", "number, [num_digits]"),
+    func("SEARCH", "find_text, within_text, [start_num]"),
+    func("SEQUENCE", "rows, [columns], [start], [step]"),
+    func("SIGN", "number"),
+    func("SIN", "number"),
+    func("SMALL", "array, k"),
+    func("SORT", "array, [sort_index], [sort_order], [by_col]"),
+    func("SORTBY", "array, by_array1, [sort_order1], ..."),
+    func("SQRT", "number"),
+    func("STDEV", "number1, [number2], ..."),
+    func("STDEV.P", "number1, [number2], ..."),
+    func("STDEV.S", "number1, [number2], ..."),
+    func("SUBSTITUTE", "text, old_text, new_text, [instance_num]"),
+    func("SUM", "number1, [number2], ..."),
+    func("SUMIF", "range, criteria, [sum_range]"),
+    func("SUMIFS", "sum_range, criteria_range1, criteria1, ..."),
+    func("SUMPRODUCT", "array1, [array2], ..."),
+    func("SWITCH", "expression, value1, result1, ..."),
+    func("TAN", "number"),
+    func("TEXT", "value, format_text"),
+    func("TEXTJOIN", "delimiter, ignore_empty, text1, ..."),
+    func("TIME", "hour, minute, second"),
+    func("TODAY", ""),
+    func("TRANSPOSE", "array"),
+    func("TRIM", "text"),
+    func("TRUNC", "number, [num_digits]"),
+    func("UNIQUE", "array, [by_col], [exactly_once]"),
+    func("UPPER", "text"),
+    func("VALUE", "text"),
+    func("VAR", "number1, [number2], ..."),
+    func("VAR.P", "number1, [number2], ..."),
+    func("VAR.S", "number1, [number2], ..."),
+    func("VLOOKUP", "lookup_value, table_array, col_index_num, [range_lookup]"),
+    func("WEEKDAY", "serial_number, [return_type]"),
+    func("XIRR", "values, dates, [guess]"),
+    func("XLOOKUP", "lookup_value, lookup_array, return_array, [if_not_found], [match_mode], [search_mode]"),
+    func("XNPV", "rate, values, dates"),
+    func("XOR", "logical1, [logical2], ..."),
+    func("YEAR", "date"),
+];
+
+pub fn names() -> impl Iterator<Item = &'static str> {
+    FUNCS.iter().map(|f| f.name)
+}
+
+pub fn find(name: &str) -> Option<&'static FuncInfo> {
+    FUNCS.binary_search_by(|f| f.name.cmp(name.to_uppercase().as_str())).ok().map(|idx| &FUNCS[idx])
+}
+
+// Signature with the parameter of the argument `arg` in backticks: SUMIF(range, `criteria`, [sum_range]).
+// Arguments after the listed parameters highlight "..."
+pub fn signature(name: &str, params: &[&str], arg: usize) -> String {
+    let current = if arg < params.len() && !params[arg].contains("...") {
+        Some(arg)
+    } else {
+        params.iter().position(|p| p.contains("..."))
+    };
+    let params: Vec<String> = params.iter().enumerate()
+        .map(|(idx, p)| if Some(idx) == current { format!("`{}`", p) } else { p.to_string() })
+        .collect();
+    format!("{}({})", name, params.join(", "))
+}
+
+// Checks the number of arguments of a built-in function. Other functions are checked when they are called
+pub fn check_arity(name: &str, cnt: usize) -> Result<(), String> {
+    let f = match find(name) {
+        None => return Ok(()),
+        Some(f) => f,
+    };
+    let (min_args, max_args) = (f.min_args(), f.max_args());
+    if (min_args..=max_args).contains(&cnt) {
+        return Ok(());
+    }
+    let plural = |n: usize| if n == 1 { "argument" } else { "arguments" };
+    Err(if max_args == 0 {
+        format!("{} does not take arguments", f.name)
+    } else if max_args == MANY {
+        format!("{} requires at least {} {}", f.name, min_args, plural(min_args))
+    } else if min_args == max_args {
+        format!("{} requires {} {}", f.name, min_args, plural(min_args))
+    } else {
+        format!("{} requires from {} to {} arguments", f.name, min_args, max_args)
+    })
+}
+
+#[rustfmt::skip]
+#[cfg(test)]
+mod funcs_test {
+    use super::*;
+
+    #[test]
+    fn arity_test() {
+        assert!(FUNCS.windows(2).all(|w| w[0].name < w[1].name), "the table must be sorted");
+        assert_eq!(find("sumif").map(|f| f.name), Some("SUMIF"));
+        assert!(find("margin").is_none());
+        assert_eq!(check_arity("Sum", 3), Ok(()));
+        assert_eq!(check_arity("margin", 9), Ok(()));
+        assert_eq!(check_arity("sum", 0), Err("SUM requires at least 1 argument".to_string()));
+        assert_eq!(check_arity("pi", 1), Err("PI does not take arguments".to_string()));
+        assert_eq!(check_arity("mid", 2), Err("MID requires 3 arguments".to_string()));
+        assert_eq!(check_arity("if", 4), Err("IF requires from 2 to 3 arguments".to_string()));
+        let f = find("sumif").unwrap();
+        assert_eq!(f.params(), vec!["range", "criteria", "[sum_range]"]);
+        assert_eq!(signature(f.name, &f.params(), 1), "SUMIF(range, `criteria`, [sum_range])");
+        let f = find("lambda").unwrap();
+        assert_eq!(f.params(), vec!["[parameter1, ...]", "calculation"]);
+        assert_eq!((f.min_args(), f.max_args()), (1, MANY));
+        let f = find("sum").unwrap();
+        assert_eq!(signature(f.name, &f.params(), 4), "SUM(number1, [number2], `...`)");
+        assert_eq!(signature("PI", &find("pi").unwrap().params(), 0), "PI()");
+        assert_eq!(signature("MARGIN", &["p", "c"], 2), "MARGIN(p, c)");
+    }
+}
@@ -14,10 +14,19 @@ mod listbox;
 mod edit;
 mod calc;
 mod sheet;
+mod book;
+mod deps;
 mod parse;
 mod ops;
 mod stack;
 mod expr;
+mod criteria;
+mod coerce;
+mod funcs;
+mod complete;
+mod datetime;
+mod finance;
+mod udf;
 
 use std::fs::File;
 use std::io::{stdin, stdout, Write};
@@ -1,13 +1,14 @@
 
 use anyhow::{anyhow, Result};
+use thiserror::Error;
 
 use crate::parse::{idx_to_name};
+use crate::datetime::{format_date, format_time};
 
 pub const UNINIT: usize = -1i64 as usize;
 pub const NEG_SIGN: &str = "----";
 pub const POS_SIGN: &str = "++++";
 
-// TODO: date and time types
 #[derive(Debug,Clone,PartialEq)]
 pub enum Arg {
     End,
@@ -20,11 +21,20 @@ pub enum Arg {
     CBracket(String),
     // CBracket, CSqBracket,
     Str(String),
-    Rng(Option<String>, Vec<Pos>), // TODO: support sheet_name in expressions
+    Rng(Option<String>, Vec<Pos>), // page name(None - the page of the formula) and range corners
     Number(f64),
     Func(String, usize), // Name, number or arguments
     Bool(bool),
     Comma,
+    Lazy(Vec<Arg>), // function argument that is calculated only when the function needs it
+    Date(f64), // days since 1899-12-30, the fractional part is the time of day
+    Time(f64), // time of day or duration in days
+    Err(ErrKind), // error value of a failed formula or an error typed in a cell
+    Name(String), // named range or constant
+    // source text(empty if built by a formula), parameters, body, and the LET names and parameters
+    // visible where LAMBDA was made
+    Lambda(String, Vec<String>, Vec<Arg>, Vec<(String, Arg)>),
+    Array(Vec<Vec<Arg>>), // rows of values a formula spills into the cells below and to the right
 }
 
 impl Arg {
@@ -62,32 +72,24 @@ impl Arg {
         match self {
             Arg::End => String::new(),
             Arg::Op(s)| Arg::Eq(s)| Arg::OBracket(s)| Arg::CBracket(s)| Arg::Str(s) => s.to_string(),
-            Arg::Rng(_, v) => if v.len() == 1 {
-                let col_fixed = if v[0].fixed_col { "$" } else { "" };
-                let row_fixed = if v[0].fixed_row { "$" } else { "" };
-                format!("{}{}{}{}", col_fixed, idx_to_name(v[0].col), row_fixed, v[0].row+1)
+            Arg::Rng(Some(name), v) => format!("{}!{}", page_ref(name), Arg::Rng(None, v.clone()).title()),
+            Arg::Rng(None, v) => if v.len() == 1 {
+                v[0].title()
             } else if v.len() == 2 {
-                let col0_fixed = if v[0].fixed_col { "$" } else { "" };
-                let row0_fixed = if v[0].fixed_row { "$" } else { "" };
-                if v[0].full_col {
-                    format!("{}{}:{}{}", col0_fixed, idx_to_name(v[0].col), col0_fixed, idx_to_name(v[0].col))
-                } else if v[0].full_row {
-                    format!("{}{}:{}{}", row0_fixed, v[0].row+1, row0_fixed, v[0].row+1)
-                } else {
-                    let col1_fixed = if v[1].fixed_col { "$" } else { "" };
-                    let row1_fixed = if v[1].fixed_row { "$" } else { "" };
-                    format!("{}{}{}{}:{}{}{}{}",
-                        col0_fixed, idx_to_name(v[0].col), row0_fixed, v[0].row+1,
-                        col1_fixed, idx_to_name(v[1].col), row1_fixed, v[1].row+1
-                    )
-                }
+                format!("{}:{}", v[0].title(), v[1].title())
             } else {
                 String::from("#VALUE!")
             },
             Arg::Number(f) => format!("{}", f), // TODO: format?
-            Arg::Func(name, _) => name.to_string(),
+            Arg::Func(name, _) | Arg::Name(name) => name.to_string(),
             Arg::Bool(b) => if *b {String::from("TRUE") } else { String::from("FALSE") },
             Arg::Comma => String::from(","),
+            Arg::Lazy(_) => String::new(),
+            Arg::Date(d) => format_date(*d),
+            Arg::Time(t) => format_time(*t),
+            Arg::Err(kind) => kind.to_string(),
+            Arg::Lambda(src, ..) => if src.is_empty() { String::from("LAMBDA") } else { src.to_string() },
+            Arg::Array(rows) => rows.first().and_then(|r| r.first()).map(|a| a.title()).unwrap_or_default(),
         }
     }
     // Like `title` but returns parsable string
@@ -108,44 +110,67 @@ impl Arg {
     //         _ => false,
     //     }
     // }
+    // Moves columns from `bcol` by `dcol` and rows from `brow` by `drow`. A negative shift deletes
+    // the columns or rows just before `bcol` or `brow`: a reference that lies entirely in the deleted
+    // cells becomes #REF!, and a range that only overlaps them loses the deleted part
     pub fn move_by(&mut self, dcol: isize, drow: isize, bcol: usize, brow: usize) {
         if dcol == 0 && drow == 0 {
             return;
         }
-        match self {
-            Arg::Rng(_, v) => {
-                let mut vnew: Vec<Pos> = Vec::new();
-                let mut changed = false;
-                for mut pos in v.iter().cloned() {
-                    if !pos.fixed_col && dcol != 0 && pos.col >= bcol {
-                        let newcol = pos.col as isize + dcol;
-                        pos.col = if newcol < 0 { 0 } else { newcol as usize};
-                        changed = true;
-                    }
-                    if !pos.fixed_row && drow != 0 && pos.row >= brow {
-                        let newrow = pos.row as isize + drow;
-                        pos.row = if newrow < 0 { 0 } else { newrow as usize};
-                        changed = true;
-                    }
-                    vnew.push(pos);
-                }
-                if changed {
-                    std::mem::swap(self, &mut Arg::Rng(None, vnew));
-                }
-            },
-            _ => {},
+        if let Arg::Rng(_, v) = self {
+            let (open_row, open_col) = (v.iter().any(|p| p.full_row), v.iter().any(|p| p.full_col));
+            if !shift_coord(v, dcol, bcol, open_row, Pos::movable_col) || !shift_coord(v, drow, brow, open_col, Pos::movable_row) {
+                *self = Arg::Err(ErrKind::Ref);
+            }
         }
     }
 }
 
+// Shifts the coordinates of a reference's corners that are at `base` or after it. The coordinates
+// from `base+d` to `base` are deleted when `d` is negative: the lower corner in them moves to the
+// first one after the deleted cells, the upper one to the last one before them.
+// Returns false if the whole reference is deleted. A range with an `open` end is never deleted
+fn shift_coord(v: &mut [Pos], d: isize, base: usize, open: bool, coord: fn(&mut Pos) -> Option<&mut usize>) -> bool {
+    if d == 0 {
+        return true;
+    }
+    let from = if d < 0 { base.saturating_sub(d.unsigned_abs()) } else { base };
+    let deleted = |c: usize| c >= from && c < base;
+    let coords: Vec<usize> = v.iter_mut().filter_map(|p| coord(p).map(|c| *c)).collect();
+    if !open && !coords.is_empty() && coords.iter().all(|&c| deleted(c)) {
+        return false;
+    }
+    let lower = coords.iter().min().copied().unwrap_or_default();
+    for p in v.iter_mut() {
+        if let Some(c) = coord(p) {
+            if *c >= base {
+                *c = (*c as isize + d).max(0) as usize;
+            } else if deleted(*c) {
+                *c = if *c == lower { from } else { from - 1 };
+            }
+        }
+    }
+    true
+}
+
+// Page name as it is written in a reference. Names that may be misread are quoted
+fn page_ref(name: &str) -> String {
+    let plain = name.starts_with(|c: char| c.is_alphabetic()) && name.chars().all(|c| c.is_alphanumeric() || c == '_');
+    if plain {
+        name.to_string()
+    } else {
+        format!("'{}'", name)
+    }
+}
+
 #[derive(Debug,Copy,Clone, PartialEq)]
 pub struct Pos {
     pub col: usize,
     pub row: usize,
     pub fixed_col: bool,
     pub fixed_row: bool,
-    pub full_col: bool, // TODO: support in formulae
-    pub full_row: bool, // TODO: support in formulae
+    pub full_col: bool, // column without a row: A:A or the open end of H2:H
+    pub full_row: bool, // row without a column: 2:2 or the open end of B2:2
 }
 
 impl Default for Pos {
@@ -161,15 +186,81 @@ impl Pos {
     pub fn new(col: usize, row: usize) -> Pos {
         Pos{col, row, ..Pos::default()}
     }
+    // An open end of a range(A:A, 2:2, H2:H) does not have a row or column to move
+    fn movable_col(&mut self) -> Option<&mut usize> {
+        if self.fixed_col || self.full_row { None } else { Some(&mut self.col) }
+    }
+    fn movable_row(&mut self) -> Option<&mut usize> {
+        if self.fixed_row || self.full_col { None } else { Some(&mut self.row) }
+    }
+    pub fn title(&self) -> String {
+        let col_fixed = if self.fixed_col { "$" } else { "" };
+        let row_fixed = if self.fixed_row { "$" } else { "" };
+        if self.full_col {
+            format!("{}{}", col_fixed, idx_to_name(self.col))
+        } else if self.full_row {
+            format!("{}{}", row_fixed, self.row+1)
+        } else {
+            format!("{}{}{}{}", col_fixed, idx_to_name(self.col), row_fixed, self.row+1)
+        }
+    }
 }
 
-pub fn err_msg(errcode: u16) -> &'static str {
-    match errcode {
-        0 => "",
-        2 => "#RECURSE",
-        3 => "#DIV/0",
-        _ => "#VALUE!",
+// Errors that formulas can detect and handle
+#[derive(Debug,Copy,Clone,PartialEq,Error)]
+pub enum ErrKind {
+    #[error("#DIV/0!")]
+    Div0,
+    #[error("#REF!")]
+    Ref,
+    #[error("#NAME?")]
+    Name,
+    #[error("#N/A")]
+    NA,
+    #[error("#NUM!")]
+    Num,
+    #[error("#VALUE!")]
+    Value,
+    #[error("#CIRC!")]
+    Circ,
+    #[error("#SPILL!")]
+    Spill,
+    #[error("#CALC!")]
+    Calc,
+}
+
+const ERR_KINDS: [ErrKind; 9] = [ErrKind::Div0, ErrKind::Ref, ErrKind::Name, ErrKind::NA, ErrKind::Num, ErrKind::Value, ErrKind::Circ,
+    ErrKind::Spill, ErrKind::Calc];
+
+impl ErrKind {
+    // Error by its title: #N/A, #DIV/0!, etc
+    pub fn parse(s: &str) -> Option<ErrKind> {
+        ERR_KINDS.iter().find(|k| k.to_string().eq_ignore_ascii_case(s)).copied()
     }
+    // The number ERROR.TYPE returns. #CIRC! does not have a standard number
+    pub fn number(self) -> usize {
+        match self {
+            ErrKind::Div0 => 2,
+            ErrKind::Value => 3,
+            ErrKind::Ref => 4,
+            ErrKind::Name => 5,
+            ErrKind::Num => 6,
+            ErrKind::NA => 7,
+            ErrKind::Circ => 8,
+            ErrKind::Spill => 9,
+            ErrKind::Calc => 14,
+        }
+    }
+}
+
+// Error of the given kind with a reason that is shown in the status line
+pub fn calc_err<R: std::fmt::Display + Send + Sync + 'static>(kind: ErrKind, reason: R) -> anyhow::Error {
+    anyhow::Error::new(kind).context(reason)
+}
+
+// Kind of a failed calculation. Errors without a kind(e.g., a text instead of a number) are #VALUE!
+pub fn err_kind(e: &anyhow::Error) -> ErrKind {
+    e.downcast_ref::<ErrKind>().copied().unwrap_or(ErrKind::Value)
 }
 
 const COL_SHIFT: u64 = 100000; // max number of columns 18000+, take next 10th power
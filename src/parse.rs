@@ -5,7 +5,7 @@ use std::fmt;
 
 use anyhow::{anyhow, Result};
 
-use crate::ops::{Pos,Arg, UNINIT};
+use crate::ops::{Pos,Arg, ErrKind, UNINIT};
 
 const NUM_LETTERS: usize = 26;
 const TWO_LETTERS: usize = NUM_LETTERS * NUM_LETTERS;
@@ -43,7 +43,8 @@ impl Range {
             Range::Multi(c1, c2) => {
                 (c1.col, c1.row, c2.col, c2.row)
             },
-            _ => unimplemented!(),
+            Range::Col(c) => (*c, 0, *c, MAX_ROWS-1),
+            Range::Row(r) => (0, *r, MAX_COLS-1, *r),
         }
     }
 }
@@ -105,22 +106,36 @@ pub fn is_white(c: char) -> bool {
     c.is_ascii_whitespace()
 }
 
-fn is_ident_start(c: char) -> bool {
+pub fn is_ident_start(c: char) -> bool {
     // ('a'..='z').contains(&c) || ('A'..='Z').contains(&c) ||
     // c == '_'
     c.is_alphabetic() || c == '_'
 }
 
-fn is_ident_cont(c: char) -> bool {
+pub fn is_ident_cont(c: char) -> bool {
     // ('a'..='z').contains(&c) || ('A'..='Z').contains(&c) ||
     // ('0'..'9').contains(&c) ||
     // c == '_' || c == '.'
     c.is_alphabetic() || c.is_ascii_digit() || c == '_' || c == '.'
 }
 
-fn is_sheet_name(c: char) -> bool {
-    c.is_alphabetic() || c.is_ascii_digit() ||
-        c == '-' || c == '_' || c == ':' || c == '/' || c == '(' || c == ')' || c == '+'
+// Characters of an unquoted page name. Other names must be quoted: 'page-2'!A1
+pub fn is_sheet_name(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+// Names of ranges and constants are identifiers that are not cell or column addresses:
+// TaxRate, but neither Rev2 nor Tax
+pub fn is_name(id: &str) -> bool {
+    let (rest, ident) = parse_ident(id);
+    if ident.is_empty() || !rest.is_empty() {
+        return false;
+    }
+    let low = ident.to_lowercase();
+    if low == "true" || low == "false" {
+        return false;
+    }
+    !matches!(parse_coord(id), Ok((rest, _)) if rest.is_empty())
 }
 
 pub fn skip_white(s: &str) -> &str {
@@ -245,8 +260,8 @@ fn parse_coord(s: &str) -> Result<(&str, Pos)> {
 }
 
 // - Full rectangle format: $A$1:$c$8
-// - Full column: A:A
-// - Full row: 2:2
+// - Full columns: A:A, A:C
+// - Full rows: 2:2, 1:3
 // - All '$' are optional
 // - Full column and full row cannot contain '$'
 // - Only characters: $,:,a-z,A-Z,0-1
@@ -271,11 +286,11 @@ pub fn parse_range(s: &str) -> Result<(&str, Vec<Pos>)> {
     //     return Err(anyhow!("Invalid cell address {}", s));
     // }
     // Sort and test
-    if c1.full_col && c1.col != UNINIT && (!c2.full_col || c2.col != c1.col) {
-        return Err(anyhow!("Invalid cell address {}: full column names differ", s));
+    if c1.full_col && c1.col != UNINIT && !c2.full_col {
+        return Err(anyhow!("Invalid cell address {}: a full column must end with a column", s));
     }
-    if c1.full_row && c1.row != UNINIT && (!c2.full_row || c2.row != c1.row) {
-        return Err(anyhow!("Invalid cell address {}: full row indices differ", s));
+    if c1.full_row && c1.row != UNINIT && !c2.full_row {
+        return Err(anyhow!("Invalid cell address {}: a full row must end with a row", s));
     }
     if ((c1.row == UNINIT && c2.row != UNINIT) || (c1.row != UNINIT && c2.row == UNINIT)) && (c1.col != c2.col) {
         return Err(anyhow!("Invalid range address {}: half-opened column with different column names", s));
@@ -320,7 +335,6 @@ fn parse_sheet_name(s: &str) -> Result<(&str, String)> {
     }
 }
 
-// TODO: support half-opened range H2:H = all items in H column starting from row 2
 pub fn parse_full_range(s: &str) -> Result<(&str, Vec<Pos>, String)> {
     let (st, sheet) = parse_sheet_name(s)?;
     let (st, coords) = parse_range(st)?;
@@ -417,7 +431,6 @@ pub fn parse_func(s: &str) -> (&str, String) {
     (s, String::new())
 }
 
-// TODO: "10:10" is parsed as Float(10.0)
 pub fn parse_arg(s: &str) -> Result<(&str, Arg)> {
     if s.is_empty() {
         return Ok((s, Arg::End));
@@ -431,15 +444,39 @@ pub fn parse_arg(s: &str) -> Result<(&str, Arg)> {
         let (st, val) = parse_string(s)?;
         return Ok((st, Arg::Str(val)));
     }
+    if let Some(rest) = s.strip_prefix('#') {
+        let (st, name) = parse_while(rest, |c| c.is_ascii_alphanumeric() || c == '/' || c == '!' || c == '?');
+        return match ErrKind::parse(&format!("#{}", name)) {
+            Some(kind) => Ok((st, Arg::Err(kind))),
+            None => Err(anyhow!("Invalid error value: {}", s)),
+        };
+    }
     let (st, fn_name) = parse_func(s);
     if !fn_name.is_empty() {
         return Ok((st, Arg::Func(fn_name, 0)));
     }
+    let (st, id) = parse_ident(s);
+    if !st.starts_with('!') {
+        match id.to_lowercase().as_str() {
+            "true" => return Ok((st, Arg::Bool(true))),
+            "false" => return Ok((st, Arg::Bool(false))),
+            _ => {},
+        }
+    }
+    if !st.starts_with('!') && !st.starts_with(':') && is_name(&id) {
+        return Ok((st, Arg::Name(id)));
+    }
+    // Full row: 10:10
+    let (st, row) = parse_while(s, |c| c.is_ascii_digit());
+    if !row.is_empty() && st.starts_with(':') {
+        let (st, rng) = parse_range(s)?;
+        return Ok((st, Arg::Rng(None, rng)));
+    }
     if let Ok((st, f)) = parse_float(s) {
         return Ok((st, Arg::Number(f)));
     }
     let (st, id) = parse_while(s, |c| ('a'..='z').contains(&c) || ('A'..='Z').contains(&c) || c == '$');
-    if !id.is_empty() {
+    if !id.is_empty() || s.starts_with('\'') {
         let (st, rng, sheet_name) = parse_full_range(s)?;
         if sheet_name.is_empty() {
             return Ok((st, Arg::Rng(None, rng)));
@@ -540,6 +577,16 @@ mod parse_test {
                 r1: Pos{col: 1, row: 1, ..Pos::default() },
                 r2: Pos{col: 1, full_col: true, ..Pos::default() },
             },
+            Tst {
+                val: "C:A", len: 2,
+                r1: Pos{col: 0, full_col: true, ..Pos::default() },
+                r2: Pos{col: 2, full_col: true, ..Pos::default() },
+            },
+            Tst {
+                val: "1:3", len: 2,
+                r1: Pos{row: 0, full_row: true, ..Pos::default() },
+                r2: Pos{row: 2, full_row: true, ..Pos::default() },
+            },
         ];
         for t in tests {
             let (_s, r) = parse_range(t.val).unwrap();
@@ -552,7 +599,7 @@ mod parse_test {
     }
     #[test]
     fn coord_parse_fail() {
-        let v: Vec<&str> = vec![ ":A1", "", "ZXCD:89", "A:B", "1:D", "$A:A", "A:$A", "1:$1" ];
+        let v: Vec<&str> = vec![ ":A1", "", "ZXCD:89", "A:B5", "1:D", "$A:A", "A:$A", "1:$1" ];
         for s in v {
             let r = parse_range(s);
             assert!(r.is_err(), "{} = {:?}", s, r);
@@ -601,12 +648,12 @@ mod parse_test {
                 ],
             },
             Tst{
-                val: "1/ac!c4+d5",
+                val: "'1/ac'!c4+d5",
                 sheet: String::from("1/ac"),
                 coords: vec![Pos{col: 2, row: 3, ..Pos::default()}],
             },
             Tst{
-                val: "sheet-name3!a2:b1",
+                val: "'sheet-name3'!a2:b1",
                 sheet: String::from("sheet-name3"),
                 coords: vec![Pos{col: 0, row: 0, ..Pos::default()},
                             Pos{col: 1, row: 1, ..Pos::default()},
@@ -786,6 +833,21 @@ mod parse_test {
             Tst{st: "3.6e5abc.tr(jf)", rs: Arg::Number(3.6e5), err: false},
             Tst{st: "\"3.6e5\"\"abc.t\"r(jf)", rs: Arg::Str("3.6e5\"abc.t".to_string()), err: false},
             Tst{st: ",(jf)", rs: Arg::Comma, err: false},
+            Tst{st: "True,1", rs: Arg::Bool(true), err: false},
+            Tst{st: "FALSE)", rs: Arg::Bool(false), err: false},
+            Tst{st: "truex", rs: Arg::Name("truex".to_string()), err: false},
+            Tst{st: "TaxRate*2", rs: Arg::Name("TaxRate".to_string()), err: false},
+            Tst{st: "_rate", rs: Arg::Name("_rate".to_string()), err: false},
+            Tst{st: "rev2", rs: Arg::Rng(None, vec![Pos{col: 12319, row: 1, ..Pos::default()}]), err: false},
+            Tst{st: "page2!b2", rs: Arg::Rng(Some("page2".to_string()), vec![Pos{col:1,row:1, ..Pos::default()}]), err: false},
+            Tst{st: "'my page'!$b2+1", rs: Arg::Rng(Some("my page".to_string()), vec![Pos{fixed_col: true,col:1,row:1, ..Pos::default()}]), err: false},
+            Tst{st: "'my page!b2", rs: Arg::Number(0.0), err: true},
+            Tst{st: "#N/A)", rs: Arg::Err(ErrKind::NA), err: false},
+            Tst{st: "#div/0!+1", rs: Arg::Err(ErrKind::Div0), err: false},
+            Tst{st: "#NAME", rs: Arg::Number(0.0), err: true},
+            Tst{st: "10:10)", rs: Arg::Rng(None, vec![Pos{full_row: true, row: 9, ..Pos::default()}, Pos{full_row: true, row: 9, ..Pos::default()}]), err: false},
+            Tst{st: "10:b)", rs: Arg::Number(0.0), err: true},
+            Tst{st: "b2+page2!a1", rs: Arg::Rng(None, vec![Pos{col:1,row:1, ..Pos::default()}]), err: false},
         ];
         for test in tests {
             let r = parse_arg(test.st);
@@ -845,7 +907,8 @@ mod parse_test {
     #[test]
     fn range_title() {
         let tests: Vec<&'static str> = vec![
-            "C:C", "A1:D3", /*"10:10",*/ "$A3:G$7", "$H$6:$I$9", "GH345:XZU98100",
+            "C:C", "A1:D3", "10:10", "H2:H", "B2:2", "$A3:G$7", "$H$6:$I$9", "GH345:XZU98100",
+            "page2!A1", "'my page'!B2:C3", "'2020'!A1",
         ];
         for test in tests {
             let r = parse_arg(test);
@@ -869,6 +932,10 @@ mod parse_test {
             Tst{ base: "C:C", dcol: -1, drow: -2, res: "B:B" },
             Tst{ base: "BBB1000", dcol: 2, drow: 3, res: "BBD1003" },
             Tst{ base: "A2:$B4", dcol: -2, drow: -3, res: "A1:$B1" },
+            Tst{ base: "'my page'!A2:$B4", dcol: 2, drow: 3, res: "'my page'!C5:$B7" },
+            Tst{ base: "10:10", dcol: 2, drow: 3, res: "13:13" },
+            Tst{ base: "H2:H", dcol: 2, drow: 3, res: "J5:J" },
+            Tst{ base: "B2:2", dcol: 2, drow: 3, res: "D5:5" },
         ];
         for test in tests {
             let r = parse_arg(test.base);
@@ -896,6 +963,26 @@ mod parse_test {
             Tst{ st: "A2:E9", bcol: 4, brow: 5, dcol: 2, drow: 2, res: "A2:G11" },
             Tst{ st: "A2:E9", bcol: 4, brow: 5, dcol: -2, drow: -2, res: "A2:C7" },
             Tst{ st: "F12:I19", bcol: 4, brow: 5, dcol: 2, drow: 1, res: "H13:K20" },
+            Tst{ st: "H2:H", bcol: MAX_COLS, brow: 0, dcol: 0, drow: 2, res: "H4:H" },
+            Tst{ st: "H2:H", bcol: MAX_COLS, brow: 5, dcol: 0, drow: 2, res: "H2:H" },
+            Tst{ st: "C:C", bcol: 1, brow: MAX_ROWS, dcol: 1, drow: 0, res: "D:D" },
+            Tst{ st: "3:3", bcol: MAX_COLS, brow: 1, dcol: 0, drow: -1, res: "2:2" },
+            Tst{ st: "A:C", bcol: 1, brow: MAX_ROWS, dcol: 1, drow: 0, res: "A:D" },
+            Tst{ st: "2:3", bcol: MAX_COLS, brow: 0, dcol: 0, drow: 2, res: "4:5" },
+            // deleting column B, row 5, and rows 3-5
+            Tst{ st: "B:B", bcol: 2, brow: MAX_ROWS, dcol: -1, drow: 0, res: "#REF!" },
+            Tst{ st: "B4:B10", bcol: 2, brow: MAX_ROWS, dcol: -1, drow: 0, res: "#REF!" },
+            Tst{ st: "A1:C3", bcol: 2, brow: MAX_ROWS, dcol: -1, drow: 0, res: "A1:B3" },
+            Tst{ st: "page2!B2:B3", bcol: 2, brow: MAX_ROWS, dcol: -1, drow: 0, res: "#REF!" },
+            Tst{ st: "A5", bcol: MAX_COLS, brow: 5, dcol: 0, drow: -1, res: "#REF!" },
+            Tst{ st: "A6", bcol: MAX_COLS, brow: 5, dcol: 0, drow: -1, res: "A5" },
+            Tst{ st: "5:5", bcol: MAX_COLS, brow: 5, dcol: 0, drow: -1, res: "#REF!" },
+            Tst{ st: "B4:B10", bcol: MAX_COLS, brow: 5, dcol: 0, drow: -3, res: "B3:B7" },
+            Tst{ st: "B1:B4", bcol: MAX_COLS, brow: 5, dcol: 0, drow: -3, res: "B1:B2" },
+            Tst{ st: "A:C", bcol: 2, brow: MAX_ROWS, dcol: -1, drow: 0, res: "A:B" },
+            Tst{ st: "1:3", bcol: MAX_COLS, brow: 3, dcol: 0, drow: -2, res: "1:1" },
+            Tst{ st: "B3:B5", bcol: MAX_COLS, brow: 5, dcol: 0, drow: -3, res: "#REF!" },
+            Tst{ st: "H4:H", bcol: MAX_COLS, brow: 5, dcol: 0, drow: -3, res: "H3:H" },
         ];
         for test in tests {
             let r = parse_arg(test.st);
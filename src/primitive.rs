@@ -71,7 +71,6 @@ impl Screen {
     }
 
     // display text in backticks in different color
-    /*
     pub fn write_string_highlight(&mut self, s: &str, col: u16, row: u16, ext_color: Color) {
         if s.is_empty() {
             return;
@@ -94,7 +93,6 @@ impl Screen {
         }
         self.fg = save_color;
     }
-    */
 
     pub fn write_char(&mut self, ch: char, col: u16, row: u16) {
         self.buf.write_char(ch, col, row, self.fg, self.bg);
@@ -3,6 +3,7 @@ use std::io::{Write,Read};
 use std::fs::File;
 use std::collections::HashMap;
 use std::collections::BTreeMap;
+use std::rc::Rc;
 
 use anyhow::{anyhow, Result};
 use crossterm::{ style::{ Color} };
@@ -14,10 +15,12 @@ use crate::primitive::Screen;
 use crate::ui::{Widget,Context,Transition,NOTHING};
 use crate::edit::Edit;
 use crate::strs;
-use crate::parse::{idx_to_name, MAX_COLS, MAX_ROWS, DEF_NUM_WIDTH, Range, parse_float, parse_while, parse_arg,is_white};
-use crate::ops::{Arg,Pos, err_msg, pos_to_id, id_to_pos};
-use crate::stack::{str_expr_to_vec, expr_to_stack};
-use crate::expr::{Expr};
+use crate::parse::{idx_to_name, MAX_COLS, MAX_ROWS, DEF_NUM_WIDTH, Range, parse_float, parse_while, parse_arg, parse_func, is_white};
+use crate::ops::{Arg,Pos, ErrKind, calc_err, err_kind, pos_to_id, id_to_pos};
+use crate::book::{Book, Iteration, Names, shift_names, save_names, load_names};
+use crate::stack::{compile, parse_lambda};
+use crate::deps::Graph;
+use crate::datetime::{parse_date, parse_time};
 
 const MIN_COL_WIDTH: u16 = 5;
 const MAX_COL_WIDTH: u16 = 100; // TODO:
@@ -27,7 +30,7 @@ const NO_VALUE: u8 = 0xFF;
 const CLR_8: u8 = 0x00;
 const CLR_ANSI: u8 = 0x01;
 const CLR_RGB: u8 = 0x02;
-pub const VERSION: u16 = 1;
+pub const VERSION: u16 = 4; // 2 - workbook random seed, 3 - iterative calculation, 4 - names
 
 #[derive(Debug,Copy,Clone)]
 pub enum CalcMode {
@@ -215,23 +218,41 @@ fn load_align<R:Read+Copy>(f: R) -> Result<Option<Align>> {
     }
 }
 
+// Compiled formula: the program for Expr or the reason why the formula cannot be parsed
+pub type Program = Rc<Result<Vec<Arg>>>;
+
 #[derive(Clone,Debug)]
 pub struct Cell {
-    pub val: String, // user input
+    pub val: String, // user input, change it with `set_val`
     pub calculated: Arg, // calculated user input
     pub attr: OptionAttr,
-    pub err: u16,
+    pub reason: String, // why the formula failed
+    prog: Option<Program>, // compiled formula, it is built on the first calculation
 }
 impl Default for Cell {
     fn default() -> Cell {
-        Cell { val: String::new(), calculated: Arg::End, attr: Default::default(), err: 0, }
+        Cell { val: String::new(), calculated: Arg::End, attr: Default::default(), reason: String::new(), prog: None, }
     }
 }
 
 impl Cell {
     pub fn is_expr(&self) -> bool { self.val.starts_with('=') }
+    pub fn set_val(&mut self, val: &str) {
+        self.val = val.to_string();
+        self.prog = None;
+    }
+    fn program(&mut self) -> Program {
+        match &self.prog {
+            Some(prog) => prog.clone(),
+            None => {
+                let prog = Rc::new(compile(&self.val));
+                self.prog = Some(prog.clone());
+                prog
+            },
+        }
+    }
     pub fn is_number(&self) -> bool {
-        if let Arg::Number(_) = self.calculated { true } else { false }
+        matches!(self.calculated, Arg::Number(_) | Arg::Date(_) | Arg::Time(_))
     }
     pub fn align(&self) -> Align {
         match self.attr.align {
@@ -244,11 +265,11 @@ impl Cell {
         }
     }
     pub fn title(&self) -> String {
-        if self.err != 0 {
-            return err_msg(self.err).to_string();
-        }
         self.calculated.title()
     }
+    pub fn is_err(&self) -> bool {
+        matches!(self.calculated, Arg::Err(_))
+    }
     fn is_default(&self) -> bool {
         self.val.is_empty() && self.attr.is_default()
     }
@@ -263,7 +284,7 @@ impl Cell {
         Ok(())
     }
     fn load<R:Read+Copy>(f: R, version: u16) -> Result<Cell> {
-        if version != VERSION {
+        if version == 0 || version > VERSION {
             return Err(anyhow!("unsupported version {}", version)); // TODO:
         }
         let mut cell = Cell::default();
@@ -280,6 +301,35 @@ impl Cell {
     }
 }
 
+// Rebuilds the formula changing its arguments with `f`. An invalid formula is returned as is
+fn map_expression<F>(expr: &str, mut f: F) -> String
+    where F: FnMut(&mut Arg)
+{
+    let mut ex: &str = &expr["=".len()..];
+    let mut output: String = String::from("=");
+    loop {
+        if ex.is_empty() {
+            break;
+        }
+        let (st, spaces) = parse_while(ex, |c| is_white(c));
+        if !spaces.is_empty() {
+            output += &spaces;
+        }
+        match parse_arg(st) {
+            Err(_) => return expr.to_string(),
+            Ok((s, mut a)) => {
+                ex = s;
+                if let Arg::End = a {
+                    break;
+                }
+                f(&mut a);
+                output += &a.to_expr();
+            },
+        }
+    }
+    output
+}
+
 struct SubRange {
     rng: Range,
     values: BTreeMap<u64, Cell>,
@@ -307,6 +357,13 @@ pub struct Sheet {
     pub max_row: usize, // maximum used column number
     pub max_col: usize, // maximum used row number
     yanked: Option<SubRange>,
+    pub seed: Option<u64>, // workbook random seed, it is stored by Calc
+    pub iteration: Option<Iteration>, // workbook iterative calculation, it is stored by Calc
+    pub names: Names, // names visible only to formulas of the page
+    pub deps: Graph, // cells the formulas of the page read
+    pub changed: Vec<u64>, // cells changed since the last recalculation of the workbook
+    spills: HashMap<u64, (usize, usize)>, // formula => columns and rows of its array, kept even if the spill is blocked
+    pub respilled: Vec<u64>, // cells a spill covered or uncovered, formulas reading them are outdated
 }
 
 impl Sheet {
@@ -329,6 +386,13 @@ impl Sheet {
             max_row: 0,
             max_col: 0,
             yanked: None,
+            seed: None,
+            iteration: None,
+            names: Names::new(),
+            deps: Graph::default(),
+            changed: Vec::new(),
+            spills: HashMap::new(),
+            respilled: Vec::new(),
         }
     }
     pub fn col_width(&self, col: usize) -> u16 {
@@ -441,6 +505,10 @@ impl Sheet {
         }
         self.first_row = row - self.fixed_rows;
     }
+    // Compiled formula of the cell, None if the cell does not contain a formula
+    pub fn program(&mut self, col: usize, row: usize) -> Option<Program> {
+        self.cells.get_mut(&pos_to_id(col, row)).filter(|c| c.is_expr()).map(|c| c.program())
+    }
     pub fn cell(&self, col: usize, row: usize) -> Cell {
         let id = pos_to_id(col, row);
         match self.cells.get(&id) {
@@ -448,6 +516,26 @@ impl Sheet {
             Some(v) => v.clone(),
         }
     }
+    // Value of a name: a range, which page is this one if the range does not have it, a constant, or LAMBDA
+    pub fn parse_name_value(&self, text: &str) -> Arg {
+        if parse_func(text).1.eq_ignore_ascii_case("lambda") {
+            if let Ok(f) = parse_lambda(text.trim()) {
+                return f;
+            }
+        }
+        match parse_arg(text) {
+            Ok((rest, Arg::Rng(page, v))) if rest.trim().is_empty() => {
+                let v = v.into_iter().map(|mut p| {
+                    p.fixed_col = false;
+                    p.fixed_row = false;
+                    p
+                }).collect();
+                Arg::Rng(Some(page.unwrap_or_else(|| self.name.clone())), v)
+            },
+            Ok((rest, a @ Arg::Str(_))) | Ok((rest, a @ Arg::Bool(_))) if rest.trim().is_empty() => a,
+            _ => self.parse_value(text),
+        }
+    }
     fn parse_value(&self, text: &str) -> Arg {
         if text.is_empty() {
             return Arg::End;
@@ -458,21 +546,33 @@ impl Sheet {
         } else if caps.as_str() == "FALSE" {
             return Arg::Bool(false);
         }
-        match parse_float(text) {
+        if let Some(d) = parse_date(text) {
+            return Arg::Date(d);
+        }
+        if let Some(t) = parse_time(text) {
+            return Arg::Time(t);
+        }
+        if let Some(kind) = ErrKind::parse(text) {
+            return Arg::Err(kind);
+        }
+        let (sign, digits) = match text.strip_prefix('-') {
+            Some(rest) => (-1.0, rest),
+            None => (1.0, text.strip_prefix('+').unwrap_or(text)),
+        };
+        match parse_float(digits) {
             Err(_) => Arg::Str(text.to_string()),
             Ok((rest, val)) => if rest.is_empty() {
-                Arg::Number(val)
+                Arg::Number(sign * val)
             } else {
                 Arg::Str(text.to_string())
             }
         }
     }
-    fn calc_expr(&mut self, expr: &str, uid: u64) -> Result<Arg> {
-        let args = str_expr_to_vec(expr)?;
-        let args = expr_to_stack(&args)?;
-        let mut expr = Expr::default(); // TODO: must be a member of Sheet
-        expr.cache.insert(uid, 1);
-        expr.calculate(&args, self)
+    // Recalculates formulas of the page that depend on the cells. A standalone page cannot see
+    // other pages: Calc recalculates the rest of the workbook using `changed`
+    fn recalc_from(&mut self, ids: &[u64]) {
+        let cells: Vec<(usize, u64)> = ids.iter().map(|id| (0, *id)).collect();
+        Book::new(std::slice::from_mut(self), 0).recalc_cells(&cells);
     }
     pub fn set_cell_calc_value(&mut self, col: usize, row: usize, val: Result<Arg>) {
         if col > self.max_col {
@@ -482,16 +582,116 @@ impl Sheet {
             self.max_row = row;
         }
         let id = pos_to_id(col, row);
+        if !self.cells.contains_key(&id) {
+            return;
+        }
+        let old = self.spill_area(id);
+        self.spills.remove(&id);
+        let val = match val {
+            Ok(Arg::Array(rows)) => self.spill(id, rows),
+            v => v,
+        };
         if let Some(cell) = self.cells.get_mut(&id) {
             match val {
-                Ok(v) => cell.calculated = v,
+                Ok(v) => {
+                    cell.reason.clear();
+                    cell.calculated = v;
+                },
                 Err(e) => {
                     info!("{:?}", e);
-                    cell.err = 1;
-                    cell.calculated = Arg::End;
+                    cell.reason = e.to_string();
+                    cell.calculated = Arg::Err(err_kind(&e));
                 },
             }
         }
+        let new = self.spill_area(id);
+        if old != new {
+            self.respilled.extend(old.into_iter().chain(new));
+        }
+    }
+    // Checks that the array of the formula fits the page and the cells it spills into are blank
+    fn spill(&mut self, id: u64, rows: Vec<Vec<Arg>>) -> Result<Arg> {
+        let (col, row) = id_to_pos(id);
+        let (w, h) = (rows.first().map_or(0, |r| r.len()), rows.len());
+        if w == 0 || h == 0 {
+            return Err(calc_err(ErrKind::Calc, "empty array"));
+        }
+        if w == 1 && h == 1 {
+            return Ok(rows[0][0].clone());
+        }
+        self.spills.insert(id, (w, h));
+        if col + w > MAX_COLS || row + h > MAX_ROWS {
+            return Err(calc_err(ErrKind::Spill, "the array does not fit the page"));
+        }
+        for r in row..row + h {
+            for c in col..col + w {
+                if (c, r) == (col, row) {
+                    continue;
+                }
+                let text = self.cells.get(&pos_to_id(c, r)).is_some_and(|cell| !cell.val.is_empty());
+                if text || self.spill_owner(c, r).is_some_and(|a| a != id) {
+                    return Err(calc_err(ErrKind::Spill, format!("spill range is not blank: {}", Pos::new(c, r).title())));
+                }
+            }
+        }
+        self.max_col = self.max_col.max(col + w - 1);
+        self.max_row = self.max_row.max(row + h - 1);
+        Ok(Arg::Array(rows))
+    }
+    // The formula which array covers the cell. The formula's own cell is not covered
+    fn spill_owner(&self, col: usize, row: usize) -> Option<u64> {
+        self.spills.iter().find(|(&a, &(w, h))| {
+            let (ac, ar) = id_to_pos(a);
+            (ac, ar) != (col, row) && col >= ac && col < ac + w && row >= ar && row < ar + h &&
+                self.cells.get(&a).is_some_and(|c| matches!(c.calculated, Arg::Array(_)))
+        }).map(|(a, _)| *a)
+    }
+    // Value a formula spilled into the cell
+    pub fn spilled_value(&self, col: usize, row: usize) -> Option<Arg> {
+        let anchor = self.spill_owner(col, row)?;
+        let (ac, ar) = id_to_pos(anchor);
+        match self.cells.get(&anchor).map(|c| &c.calculated) {
+            Some(Arg::Array(rows)) => rows.get(row - ar).and_then(|r| r.get(col - ac)).cloned(),
+            _ => None,
+        }
+    }
+    // Cells the array of the formula covers, except the formula's own cell
+    pub fn spill_area(&self, id: u64) -> Vec<u64> {
+        let spilled = self.cells.get(&id).is_some_and(|c| matches!(c.calculated, Arg::Array(_)));
+        let (w, h) = match self.spills.get(&id) {
+            Some(&size) if spilled => size,
+            _ => return Vec::new(),
+        };
+        let (col, row) = id_to_pos(id);
+        let mut ids = Vec::new();
+        for r in row..row + h {
+            for c in col..col + w {
+                if (c, r) != (col, row) {
+                    ids.push(pos_to_id(c, r));
+                }
+            }
+        }
+        ids
+    }
+    // Formulas which arrays would cover any cell of the range, whether their spills are blocked or not
+    pub fn spill_anchors(&self, start_col: usize, start_row: usize, end_col: usize, end_row: usize) -> Vec<u64> {
+        self.spills.iter().filter(|(&a, &(w, h))| {
+            let (ac, ar) = id_to_pos(a);
+            ac <= end_col && ac + w > start_col && ar <= end_row && ar + h > start_row
+        }).map(|(a, _)| *a).collect()
+    }
+    // Cell as it is displayed: a formula shows the first value of its array, a blank cell
+    // shows the value spilled into it. Editing and saving use `cell`
+    pub fn shown_cell(&self, col: usize, row: usize) -> Cell {
+        let mut cell = self.cell(col, row);
+        if let Arg::Array(rows) = &cell.calculated {
+            cell.calculated = rows[0][0].clone();
+        } else if cell.val.is_empty() {
+            if let Some(val) = self.spilled_value(col, row) {
+                cell.calculated = val;
+            }
+        }
+        cell
     }
     pub fn set_cell_text(&mut self, col: usize, row: usize, text: &str, recalc: bool) {
         if col > self.max_col {
@@ -508,28 +708,26 @@ impl Sheet {
                 return;
             }
         }
-        let mut do_calc = false;
-        do_calc = text.starts_with('=') && recalc;
         if let Some(cell) = self.cells.get_mut(&id) {
-            cell.val = text.to_string();
-            cell.err = 0;
+            cell.set_val(text);
+            cell.reason.clear();
         } else {
             let mut cell = Cell::default();
-            cell.val = text.to_string();
+            cell.set_val(text);
             self.cells.insert(id, cell);
         }
-        if !do_calc {
+        self.deps.set_cell(id, text.strip_prefix('='));
+        if !text.starts_with('=') {
             let v = self.parse_value(text);
             self.set_cell_calc_value(col, row, Ok(v));
-            self.recalc_cells();
-            self.dirty = true;
-            return;
         }
-        info!("calculate {}", text);
-        let val = self.calc_expr(&text[1..], id);
-        self.set_cell_calc_value(col, row, val);
-        self.recalc_cells();
+        self.changed.push(id);
         self.dirty = true;
+        // without `recalc` the caller recalculates formulas after all changes are done
+        if recalc {
+            info!("calculate {}", text);
+            self.recalc_from(&[id]);
+        }
     }
     pub fn set_cell_attr(&mut self, col: usize, row: usize, attr: OptionAttr) {
         let id = pos_to_id(col, row);
@@ -826,12 +1024,14 @@ impl Sheet {
         match self.selected_range() {
             Range::Single(pos) => self.set_cell_text(pos.col, pos.row, "", true),
             Range::Multi(p1, p2) => {
+                let mut ids = Vec::new();
                 for r in p1.row..=p2.row {
                     for c in p1.col..=p2.col {
                         self.set_cell_text(c, r, "", false);
+                        ids.push(pos_to_id(c, r));
                     }
                 }
-                self.recalc_cells();
+                self.recalc_from(&ids);
             }
             Range::Col(_) => {}, // TODO:
             Range::Row(_) => {}, // TODO:
@@ -900,6 +1100,7 @@ impl Sheet {
         serialize_into(f, &0usize)?; // TODO:
         // marked ranges (first: number of items; N of {char: mark, col+row+width+height})
         serialize_into(f, &0usize)?; // TODO:
+        save_names(f, &self.names)?;
 
         // cells
         for (id, cell) in self.cells.iter() {
@@ -916,7 +1117,7 @@ impl Sheet {
     }
     // TODO: pass here and to all 'load's version number
     pub fn load<R: Read+Copy>(f: R, width: u16, height: u16, version: u16) -> Result<Sheet> {
-        if version != VERSION {
+        if version == 0 || version > VERSION {
             return Err(anyhow!("unsupported version {}", version)); // TODO:
         }
         let mut sheet = Sheet::new(0, width, height);
@@ -941,6 +1142,9 @@ impl Sheet {
         let _hidden_cols: usize = deserialize_from(f)?; // TODO:
         // marked ranges (first: number of items; N of {char: mark, col+row+width+height})
         let _ranges: usize = deserialize_from(f)?; // TODO:
+        if version >= 4 {
+            sheet.names = load_names(f, &sheet)?;
+        }
 
         // cells
         sheet.max_col = 0;
@@ -958,10 +1162,12 @@ impl Sheet {
             if row >= MAX_ROWS {
                 return Err(anyhow!("invalid row index: {}", row));
             }
-            let cell = Cell::load(f, version)?;
-            let vv = cell.val.clone();
+            let mut cell = Cell::load(f, version)?;
+            if !cell.is_expr() {
+                cell.calculated = sheet.parse_value(&cell.val);
+            }
+            sheet.deps.set_cell(pos_to_id(col, row), cell.val.strip_prefix('='));
             sheet.set_cell(col, row, cell);
-            sheet.set_cell_text(col, row, &vv, false);
             if col > sheet.max_col {
                 sheet.max_col = col;
             }
@@ -973,19 +1179,15 @@ impl Sheet {
 
         Ok(sheet)
     }
-    // TODO: optimize
-    fn recalc_cells(&mut self) {
-        let mut hm: HashMap<(usize, usize, u64), String> = HashMap::new();
+    pub fn recalc_cells(&mut self) {
+        Book::new(std::slice::from_mut(self), 0).recalc_page();
+    }
+    fn rebuild_deps(&mut self) {
+        // cells have moved, the recalculation that follows finds the spills again
+        self.spills.clear();
+        self.deps.clear();
         for (id, cell) in self.cells.iter() {
-            if !cell.is_expr() {
-                continue;
-            }
-            let (col, row) = id_to_pos(*id);
-            hm.insert((col, row, *id), cell.val[1..].to_string());
-        }
-        for ((col, row, uid), expr) in hm.drain() {
-            let val = self.calc_expr(&expr, uid);
-            self.set_cell_calc_value(col, row, val);
+            self.deps.set_cell(*id, cell.val.strip_prefix('='));
         }
     }
     pub fn resize_col(&mut self, col: usize, delta: i16) {
@@ -1074,6 +1276,7 @@ impl Sheet {
         let (col_start, row_start, col_end, row_end) = rng.indices();
         info!("YANK: {}x{} -  {}x{}", col_start, row_start, col_end, row_end);
         let mut values: BTreeMap<u64, Cell> = BTreeMap::new();
+        let mut ids = Vec::new();
         for row in row_start..=row_end {
             for col in col_start..=col_end {
                 let id = pos_to_id(col, row);
@@ -1081,12 +1284,13 @@ impl Sheet {
                     values.insert(id, cell.clone());
                     if cut {
                         self.set_cell_text(col, row, "", false);
+                        ids.push(id);
                     }
                 }
             }
         }
         if cut {
-            self.recalc_cells();
+            self.recalc_from(&ids);
         }
         self.yanked = Some(SubRange{rng, values});
     }
@@ -1101,6 +1305,7 @@ impl Sheet {
                 info!("PASTE: {}x{} -  {}x{}", col_start, row_start, col_end, row_end);
                 let dcol = self.cursor.col as isize - col_start as isize;
                 let drow = self.cursor.row as isize - row_start as isize;
+                let mut ids = Vec::new();
                 for row in row_start..=row_end {
                     for col in col_start..=col_end {
                         let id = pos_to_id(col, row);
@@ -1110,176 +1315,176 @@ impl Sheet {
                             if !clone.is_expr() {
                                 self.cells.insert(new_id, clone);
                             } else {
-                                let expr = self.move_expression(&cell.val, dcol, drow, 0, 0);
+                                let expr = self.move_expression(&cell.val, dcol, drow, 0, 0, true);
                                 info!("updated expr '{}' : '{}'", expr, cell.val);
-                                clone.val = expr;
+                                clone.set_val(&expr);
                                 clone.calculated = Arg::End;
                                 self.cells.insert(new_id, clone);
                             }
                         } else {
                             self.cells.remove(&new_id);
                         }
+                        let expr = self.cells.get(&new_id).and_then(|c| c.val.strip_prefix('='));
+                        self.deps.set_cell(new_id, expr);
+                        ids.push(new_id);
                     }
                 }
-                self.recalc_cells();
+                self.changed.extend(ids.iter());
+                self.recalc_from(&ids);
                 self.dirty = true;
             }
         }
     }
-    fn move_expression(&self, expr: &str, dcol: isize, drow: isize, bcol: usize, brow: usize) -> String {
-        let mut ex: &str = &expr["=".len()..];
-        let mut output: String = String::from("=");
-        loop {
-            if ex.is_empty() {
-                break;
+    // References to other pages are shifted only if `other_pages` is set: a copied formula points
+    // to the same relative cells, but inserting a row does not change cells of other pages
+    fn move_expression(&self, expr: &str, dcol: isize, drow: isize, bcol: usize, brow: usize, other_pages: bool) -> String {
+        let page = self.name.to_lowercase();
+        map_expression(expr, |a| {
+            let other = matches!(a, Arg::Rng(Some(name), _) if name.to_lowercase() != page);
+            if other_pages || !other {
+                a.move_by(dcol, drow, bcol, brow);
             }
-            let (st, spaces) = parse_while(ex, |c| is_white(c));
-            if !spaces.is_empty() {
-                output += &spaces;
+        })
+    }
+    // Replaces the name in all formulas of the page
+    pub fn rename_in_formulas(&mut self, old: &str, new: &str) {
+        let ids: Vec<u64> = self.cells.iter().filter(|(_, c)| c.is_expr()).map(|(id, _)| *id).collect();
+        for id in ids {
+            let val = self.cells[&id].val.clone();
+            let expr = map_expression(&val, |a| {
+                if matches!(a, Arg::Name(name) if name.to_lowercase() == old) {
+                    *a = Arg::Name(new.to_string());
+                }
+            });
+            if expr != val {
+                self.deps.set_cell(id, expr.strip_prefix('='));
+                if let Some(c) = self.cells.get_mut(&id) {
+                    c.set_val(&expr);
+                }
+                self.dirty = true;
             }
-            match parse_arg(st) {
-                Err(e) => return expr.to_string(),
-                Ok((s, mut a)) => {
-                    ex = s;
-                    if let Arg::End = a {
-                        break;
-                    }
+        }
+    }
+    // Updates references in all formulas and names of the page after inserting or deleting rows or columns
+    fn shift_refs(&mut self, dcol: isize, drow: isize, bcol: usize, brow: usize) {
+        shift_names(&mut self.names, &self.name, dcol, drow, bcol, brow);
+        let ids: Vec<u64> = self.cells.iter().filter(|(_, c)| c.is_expr()).map(|(id, _)| *id).collect();
+        for id in ids {
+            let val = self.cells[&id].val.clone();
+            let expr = self.move_expression(&val, dcol, drow, bcol, brow, false);
+            info!("updated expr '{}' : '{}'", expr, val);
+            if let Some(c) = self.cells.get_mut(&id) {
+                c.set_val(&expr);
+                c.calculated = Arg::End;
+            }
+        }
+    }
+    // Updates references to another page in formulas and names after inserting or deleting its rows or columns
+    pub fn shift_page_refs(&mut self, page: &str, dcol: isize, drow: isize, bcol: usize, brow: usize) {
+        shift_names(&mut self.names, page, dcol, drow, bcol, brow);
+        let page = page.to_lowercase();
+        let ids: Vec<u64> = self.cells.iter().filter(|(_, c)| c.is_expr()).map(|(id, _)| *id).collect();
+        for id in ids {
+            let val = self.cells[&id].val.clone();
+            let expr = map_expression(&val, |a| {
+                if matches!(a, Arg::Rng(Some(name), _) if name.to_lowercase() == page) {
                     a.move_by(dcol, drow, bcol, brow);
-                    output += &a.to_expr();
-                },
+                }
+            });
+            if expr != val {
+                self.deps.set_cell(id, expr.strip_prefix('='));
+                if let Some(c) = self.cells.get_mut(&id) {
+                    c.set_val(&expr);
+                    c.calculated = Arg::End;
+                }
+                self.dirty = true;
             }
         }
-        output
     }
-    pub fn insert_cols(&mut self, from: usize, cnt: usize, after: bool) {
+    pub fn insert_cols(&mut self, from: usize, cnt: usize) {
         if cnt == 0 || from+cnt >= MAX_COLS { // TODO: error on >MAX_COLS?
             return;
         }
         info!("shifting {} cols from {} to {}(rows: {})", cnt, from, self.max_col, self.max_row);
+        self.shift_refs(cnt as isize, 0, from, MAX_ROWS);
         for row in 0..=self.max_row {
             for col in (from..=self.max_col).rev() {
                 let id = pos_to_id(col, row);
                 let new_id = pos_to_id(col+cnt, row);
                 info!("moving from {}x{} to {}x{}", col, row, col+cnt, row);
-                let mut cell: Option<Cell> = if let Some(v) = self.cells.get(&id) {
-                    Some(v.clone())
-                } else {
-                    None
-                };
-                if let Some(mut c) = cell {
-                    let bcol = if after { self.cursor.col+1} else {self.cursor.col};
-                    if c.is_expr() {
-                        let expr = self.move_expression(&c.val, cnt as isize, 0, bcol, MAX_ROWS);
-                        info!("updated expr '{}' : '{}'", expr, c.val);
-                        c.val = expr;
-                        c.calculated = Arg::End;
-                    }
+                if let Some(c) = self.cells.remove(&id) {
                     self.cells.insert(new_id, c);
-                    self.cells.remove(&id);
                 }
             }
         }
         self.max_col += cnt;
+        self.rebuild_deps();
         self.recalc_cells();
         self.dirty = true;
     }
-    pub fn insert_rows(&mut self, from: usize, cnt: usize, after: bool) {
+    pub fn insert_rows(&mut self, from: usize, cnt: usize) {
         if cnt == 0 || from+cnt >= MAX_ROWS { // TODO: error on >MAX_ROWS?
             return;
         }
         info!("shifting {} rows from {} to {}(cols: {})", cnt, from, self.max_row, self.max_col);
+        self.shift_refs(0, cnt as isize, MAX_COLS, from);
         for row in (from..=self.max_row).rev() {
             for col in 0..=self.max_col {
                 let id = pos_to_id(col, row);
                 let new_id = pos_to_id(col, row+cnt);
                 info!("moving from {}x{} to {}x{}", col, row, col, row+cnt);
-                let mut cell: Option<Cell> = if let Some(v) = self.cells.get(&id) {
-                    Some(v.clone())
-                } else {
-                    None
-                };
-                if let Some(mut c) = cell {
-                    let brow = if after { self.cursor.row+1} else {self.cursor.row};
-                    if c.is_expr() {
-                        let expr = self.move_expression(&c.val, 0, cnt as isize, MAX_COLS, brow);
-                        info!("updated expr '{}' : '{}'", expr, c.val);
-                        c.val = expr;
-                        c.calculated = Arg::End;
-                    }
+                if let Some(c) = self.cells.remove(&id) {
                     self.cells.insert(new_id, c);
-                    self.cells.remove(&id);
                 }
             }
         }
         self.max_row += cnt;
+        self.rebuild_deps();
         self.recalc_cells();
         self.dirty = true;
     }
-    pub fn delete_cols(&mut self, from: usize, cnt: usize, after: bool) {
+    // References to the cells after the deleted ones move back. References to deleted cells become #REF!
+    pub fn delete_cols(&mut self, from: usize, cnt: usize) {
         if cnt == 0 {
             return;
         }
         info!("shifting {} cols from {} to {}(rows: {})", cnt, from, self.max_col, self.max_row);
+        self.shift_refs(-(cnt as isize), 0, from+cnt, MAX_ROWS);
         for row in 0..=self.max_row {
             for col in from..=self.max_col {
                 let new_id = pos_to_id(col, row);
                 let id = pos_to_id(col+cnt, row);
                 info!("moving from {}x{} to {}x{}", col+cnt, row, col, row);
-                let mut cell: Option<Cell> = if let Some(v) = self.cells.get(&id) {
-                    Some(v.clone())
-                } else {
-                    None
-                };
-                if let Some(mut c) = cell {
-                    let bcol = if after { self.cursor.col+1} else {self.cursor.col};
-                    if c.is_expr() {
-                        let expr = self.move_expression(&c.val, cnt as isize, 0, bcol, MAX_ROWS);
-                        info!("updated expr '{}' : '{}'", expr, c.val);
-                        c.val = expr;
-                        c.calculated = Arg::End;
-                    }
-                    self.cells.insert(new_id, c);
-                    self.cells.remove(&id);
-                } else {
-                    self.cells.remove(&new_id);
+                match self.cells.remove(&id) {
+                    Some(c) => { self.cells.insert(new_id, c); },
+                    None => { self.cells.remove(&new_id); },
                 }
             }
         }
-        self.max_col -= cnt;
+        self.max_col = self.max_col.saturating_sub(cnt);
+        self.rebuild_deps();
         self.recalc_cells();
         self.dirty = true;
     }
-    pub fn delete_rows(&mut self, from: usize, cnt: usize, after: bool) {
+    pub fn delete_rows(&mut self, from: usize, cnt: usize) {
         if cnt == 0 {
             return;
         }
         info!("shifting {} rows from {} to {}(cols: {})", cnt, from, self.max_row, self.max_col);
+        self.shift_refs(0, -(cnt as isize), MAX_COLS, from+cnt);
         for row in from..=self.max_row {
             for col in 0..=self.max_col {
                 let new_id = pos_to_id(col, row);
                 let id = pos_to_id(col, row+cnt);
                 info!("moving from {}x{} to {}x{}", col, row+cnt, col, row);
-                let mut cell: Option<Cell> = if let Some(v) = self.cells.get(&id) {
-                    Some(v.clone())
-                } else {
-                    None
-                };
-                if let Some(mut c) = cell {
-                    let brow = if after { self.cursor.row+1} else {self.cursor.row};
-                    if c.is_expr() {
-                        let expr = self.move_expression(&c.val, 0, cnt as isize, MAX_COLS, brow);
-                        info!("updated expr '{}' : '{}'", expr, c.val);
-                        c.val = expr;
-                        c.calculated = Arg::End;
-                    }
-                    self.cells.insert(new_id, c);
-                    self.cells.remove(&id);
-                } else {
-                    self.cells.remove(&new_id);
+                match self.cells.remove(&id) {
+                    Some(c) => { self.cells.insert(new_id, c); },
+                    None => { self.cells.remove(&new_id); },
                 }
             }
         }
-        self.max_row -= cnt;
+        self.max_row = self.max_row.saturating_sub(cnt);
+        self.rebuild_deps();
         self.recalc_cells();
         self.dirty = true;
     }
@@ -1290,3 +1495,263 @@ impl Sheet {
     }
     */
 }
+
+#[rustfmt::skip]
+#[cfg(test)]
+mod sheet_test {
+    use super::*;
+
+    #[test]
+    fn save_load_dates() {
+        let mut sheet = Sheet::new(0, 80, 25);
+        sheet.set_cell_text(0, 0, "2026-10-17", true);
+        sheet.set_cell_text(1, 0, "14:30", true);
+        sheet.set_cell_text(2, 0, "=A1+B1", true);
+        let path = std::env::temp_dir().join(format!("tspss_dates_{}.bin", std::process::id()));
+        {
+            let f = File::create(&path).unwrap();
+            sheet.save(&f).unwrap();
+        }
+        let f = File::open(&path).unwrap();
+        let loaded = Sheet::load(&f, 80, 25, VERSION).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(loaded.cell(0, 0).calculated, Arg::Date(46312.0));
+        assert_eq!(loaded.cell(1, 0).title(), "14:30");
+        assert_eq!(loaded.cell(2, 0).title(), "2026-10-17 14:30");
+    }
+
+    #[test]
+    fn move_page_refs() {
+        let mut sheet = Sheet::new(0, 80, 25);
+        let expr = "=A1+page1!B2+'page 2'!C3";
+        assert_eq!(sheet.move_expression(expr, 1, 2, 0, 0, true), "=B3+page1!C4+'page 2'!D5");
+        assert_eq!(sheet.move_expression(expr, 0, 2, MAX_COLS, 0, false), "=A3+page1!B4+'page 2'!C3");
+        sheet.set_cell_text(0, 0, "=SUM(page2!A1:A3)", true);
+        sheet.yank(false);
+        sheet.cursor = Pos::new(2, 4);
+        sheet.paste_yanked();
+        assert_eq!(sheet.cell(2, 4).val, "=SUM(page2!C5:C7)");
+    }
+
+    #[test]
+    fn insert_delete_refs() {
+        let mut sheet = Sheet::new(0, 80, 25);
+        sheet.set_cell_text(0, 0, "=SUM(B2:B)", true);
+        sheet.set_cell_text(1, 0, "=SUM(B2:B3)+C1", true);
+        sheet.set_cell_text(1, 1, "1", true);
+        sheet.set_cell_text(1, 2, "2", true);
+        sheet.set_cell_text(2, 0, "=SUM(2:2)", true);
+        sheet.insert_rows(1, 2);
+        assert_eq!(sheet.cell(0, 0).val, "=SUM(B4:B)");
+        assert_eq!(sheet.cell(1, 0).val, "=SUM(B4:B5)+C1");
+        assert_eq!(sheet.cell(2, 0).val, "=SUM(4:4)");
+        assert_eq!(sheet.cell(0, 0).title(), "3");
+        sheet.insert_cols(1, 1);
+        assert_eq!(sheet.cell(0, 0).val, "=SUM(C4:C)");
+        assert_eq!(sheet.cell(2, 0).val, "=SUM(C4:C5)+D1");
+        sheet.delete_rows(1, 2);
+        assert_eq!(sheet.cell(0, 0).val, "=SUM(C2:C)");
+        assert_eq!(sheet.cell(3, 0).val, "=SUM(2:2)");
+        sheet.delete_cols(1, 1);
+        assert_eq!(sheet.cell(1, 0).val, "=SUM(B2:B3)+C1");
+        assert_eq!(sheet.cell(0, 0).title(), "3");
+        assert_eq!(sheet.cell(2, 0).title(), "1");
+    }
+
+    #[test]
+    fn delete_refs() {
+        let mut sheet = Sheet::new(0, 80, 25);
+        sheet.set_cell_text(0, 0, "=SUM(B:B)", true);
+        sheet.set_cell_text(0, 1, "=SUM(B4:B10)", true);
+        sheet.set_cell_text(0, 2, "=SUM(A10:C10)+C1", true);
+        sheet.set_cell_text(2, 0, "5", true);
+        sheet.delete_cols(1, 1);
+        assert_eq!(sheet.cell(0, 0).val, "=SUM(#REF!)");
+        assert_eq!(sheet.cell(0, 0).title(), "#REF!");
+        assert_eq!(sheet.cell(0, 1).val, "=SUM(#REF!)");
+        assert_eq!(sheet.cell(0, 2).val, "=SUM(A10:B10)+B1");
+        assert_eq!(sheet.cell(0, 2).title(), "5");
+
+        let mut sheet = Sheet::new(0, 80, 25);
+        sheet.set_cell_text(0, 0, "=B5*2", true);
+        sheet.set_cell_text(0, 1, "=SUM(B4:B10)", true);
+        sheet.set_cell_text(3, 0, "=SUM(B2:B4)", true);
+        sheet.set_cell_text(1, 5, "3", true);
+        sheet.delete_rows(4, 1);
+        assert_eq!(sheet.cell(0, 0).val, "=#REF!*2");
+        assert_eq!(sheet.cell(0, 0).title(), "#REF!");
+        sheet.delete_rows(2, 3);
+        assert_eq!(sheet.cell(0, 1).val, "=SUM(B3:B6)");
+        assert_eq!(sheet.cell(3, 0).val, "=SUM(B2:B2)");
+    }
+
+    #[test]
+    fn parse_value_test() {
+        let sheet = Sheet::new(0, 80, 25);
+        assert_eq!(sheet.parse_value("-12.5"), Arg::Number(-12.5));
+        assert_eq!(sheet.parse_value("+3"), Arg::Number(3.0));
+        assert_eq!(sheet.parse_value("-"), Arg::Str("-".to_string()));
+        assert_eq!(sheet.parse_value("-3x"), Arg::Str("-3x".to_string()));
+        assert_eq!(sheet.parse_value("true"), Arg::Bool(true));
+        assert_eq!(sheet.parse_value("#n/a"), Arg::Err(ErrKind::NA));
+    }
+
+    #[test]
+    fn incremental_recalc() {
+        let mut sheet = Sheet::new(0, 80, 25);
+        sheet.set_cell_text(0, 0, "1", true);
+        sheet.set_cell_text(1, 0, "=A1+1", true);
+        sheet.set_cell_text(3, 0, "=B1+C1", true);
+        sheet.set_cell_text(2, 0, "=B1+1", true);
+        sheet.set_cell_text(4, 0, "=SUM(A:A)", true);
+        sheet.set_cell_text(5, 0, "=7", true);
+        assert_eq!(sheet.cell(3, 0).title(), "5");
+        sheet.set_cell_text(0, 0, "10", true);
+        assert_eq!(sheet.cell(3, 0).title(), "23");
+        sheet.set_cell_text(0, 50, "5", true);
+        assert_eq!(sheet.cell(4, 0).title(), "15");
+
+        // formulas that do not read the changed cell keep their values
+        sheet.cells.get_mut(&pos_to_id(5, 0)).unwrap().calculated = Arg::Number(8.0);
+        sheet.set_cell_text(0, 0, "2", true);
+        assert_eq!(sheet.cell(3, 0).title(), "7");
+        assert_eq!(sheet.cell(5, 0).title(), "8");
+        sheet.set_cell_text(5, 1, "=RAND()", true);
+        sheet.set_cell_text(5, 0, "=F2*0+7", true);
+        sheet.cells.get_mut(&pos_to_id(5, 0)).unwrap().calculated = Arg::Number(8.0);
+        sheet.set_cell_text(0, 0, "3", true);
+        assert_eq!(sheet.cell(5, 0).title(), "7");
+
+        // removed formula does not depend on anything
+        sheet.set_cell_text(3, 0, "", true);
+        sheet.set_cell_text(0, 0, "4", true);
+        assert_eq!(sheet.cell(3, 0).title(), "");
+        assert_eq!(sheet.cell(2, 0).title(), "6");
+
+        sheet.insert_rows(0, 1);
+        sheet.set_cell_text(0, 1, "1", true);
+        assert_eq!(sheet.cell(2, 1).title(), "3");
+        sheet.delete_cols(3, 1);
+        sheet.set_cell_text(0, 1, "2", true);
+        assert_eq!(sheet.cell(2, 1).title(), "4");
+        assert_eq!(sheet.cell(3, 1).title(), "7");
+    }
+
+    #[test]
+    fn named_function_deps() {
+        let mut sheet = Sheet::new(0, 80, 25);
+        sheet.set_cell_text(1, 0, "3", true);
+        for (name, val) in [("g", "LAMBDA(x, x*$B$1)"), ("h", "LAMBDA(x, g(x)+1)"), ("loop", "LAMBDA(n, loop(n))")] {
+            let f = sheet.parse_name_value(val);
+            sheet.names.insert(name.to_string(), f);
+        }
+        sheet.set_cell_text(0, 0, "=g(2)", true);
+        sheet.set_cell_text(0, 1, "=h(2)", true);
+        sheet.set_cell_text(0, 2, "=loop(1)", true);
+        assert_eq!(sheet.cell(0, 0).title(), "6");
+        sheet.set_cell_text(1, 0, "10", true);
+        assert_eq!(sheet.cell(0, 0).title(), "20");
+        assert_eq!(sheet.cell(0, 1).title(), "21");
+    }
+
+    #[test]
+    fn program_cache() {
+        let mut sheet = Sheet::new(0, 80, 25);
+        sheet.set_cell_text(0, 0, "2", true);
+        sheet.set_cell_text(1, 0, "=A1*3", true);
+        assert!(sheet.program(0, 0).is_none());
+        let prog = sheet.program(1, 0).unwrap();
+        assert!(Rc::ptr_eq(&prog, &sheet.program(1, 0).unwrap()));
+        sheet.set_cell_text(0, 0, "4", true);
+        assert_eq!(sheet.cell(1, 0).title(), "12");
+        assert!(Rc::ptr_eq(&prog, &sheet.program(1, 0).unwrap()));
+
+        sheet.set_cell_text(1, 0, "=A1*(3", true);
+        assert!(sheet.cell(1, 0).is_err());
+        let prog = sheet.program(1, 0).unwrap();
+        assert!(prog.is_err());
+        sheet.set_cell_text(0, 0, "5", true);
+        assert!(sheet.cell(1, 0).is_err());
+        assert!(Rc::ptr_eq(&prog, &sheet.program(1, 0).unwrap()));
+
+        sheet.set_cell_text(1, 0, "=A1*4", true);
+        assert_eq!(sheet.cell(1, 0).title(), "20");
+        sheet.insert_cols(0, 1);
+        assert_eq!(sheet.cell(2, 0).val, "=B1*4");
+        sheet.set_cell_text(1, 0, "1", true);
+        assert_eq!(sheet.cell(2, 0).title(), "4");
+    }
+
+    #[test]
+    fn page_names() {
+        let mut sheet = Sheet::new(0, 80, 25);
+        sheet.set_cell_text(1, 1, "3", true);
+        sheet.set_cell_text(1, 2, "4", true);
+        let (data, rate) = (sheet.parse_name_value("b2:b3"), sheet.parse_name_value("10"));
+        sheet.names.insert("data".to_string(), data);
+        sheet.names.insert("rate".to_string(), rate);
+        sheet.set_cell_text(3, 0, "=SUM(Data)*rate", true);
+        assert_eq!(sheet.cell(3, 0).title(), "70");
+
+        sheet.insert_rows(1, 2);
+        assert_eq!(sheet.names["data"].to_expr(), "page1!B4:B5");
+        assert_eq!(sheet.cell(3, 0).title(), "70");
+        sheet.delete_cols(0, 1);
+        assert_eq!(sheet.names["data"].to_expr(), "page1!A4:A5");
+
+        sheet.rename_in_formulas("data", "values");
+        assert_eq!(sheet.cell(2, 0).val, "=SUM(values)*rate");
+        let v = sheet.names.remove("data").unwrap();
+        sheet.names.insert("values".to_string(), v);
+
+        let path = std::env::temp_dir().join(format!("tspss_names_{}.bin", std::process::id()));
+        {
+            let f = File::create(&path).unwrap();
+            sheet.save(&f).unwrap();
+        }
+        let f = File::open(&path).unwrap();
+        let loaded = Sheet::load(&f, 80, 25, VERSION).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(loaded.names, sheet.names);
+        assert_eq!(loaded.cell(2, 0).title(), "70");
+    }
+
+    #[test]
+    fn spill() {
+        let mut sheet = Sheet::new(0, 80, 25);
+        sheet.set_cell_text(0, 0, "=SEQUENCE(3)", true);
+        sheet.set_cell_text(1, 0, "=A3*10", true);
+        sheet.set_cell_text(2, 0, "=COUNT(A2:A5)", true);
+        assert_eq!(sheet.shown_cell(0, 2).title(), "3");
+        assert_eq!(sheet.cell(0, 2).val, "");
+        assert_eq!((sheet.cell(1, 0).title(), sheet.cell(2, 0).title()), ("30".to_string(), "2".to_string()));
+
+        sheet.set_cell_text(0, 0, "=SEQUENCE(3, 1, 2)", true);
+        assert_eq!((sheet.cell(1, 0).title(), sheet.cell(2, 0).title()), ("40".to_string(), "2".to_string()));
+
+        // a value in the way blocks the spill
+        sheet.set_cell_text(0, 1, "x", true);
+        assert_eq!(sheet.cell(0, 0).title(), "#SPILL!");
+        assert_eq!(sheet.cell(0, 0).reason, "spill range is not blank: A2");
+        assert_eq!(sheet.shown_cell(0, 2).title(), "");
+        assert_eq!((sheet.cell(1, 0).title(), sheet.cell(2, 0).title()), ("0".to_string(), "0".to_string()));
+        sheet.set_cell_text(0, 1, "", true);
+        assert_eq!(sheet.shown_cell(0, 0).title(), "2");
+        assert_eq!((sheet.cell(1, 0).title(), sheet.cell(2, 0).title()), ("40".to_string(), "2".to_string()));
+
+        // formulas calculated before the spill appeared or after it shrank pick up the change
+        sheet.set_cell_text(3, 0, "=E3+1", true);
+        sheet.set_cell_text(4, 0, "=SEQUENCE(3)", true);
+        assert_eq!(sheet.cell(3, 0).title(), "4");
+        sheet.set_cell_text(4, 0, "=SEQUENCE(2)", true);
+        assert_eq!(sheet.cell(3, 0).title(), "1");
+
+        // spills cannot overlap
+        sheet.set_cell_text(3, 1, "=TRANSPOSE(SEQUENCE(2))", true);
+        assert_eq!(sheet.shown_cell(3, 1).title(), "#SPILL!");
+        assert_eq!(sheet.cell(3, 1).reason, "spill range is not blank: E2");
+        sheet.set_cell_text(4, 0, "7", true);
+        assert_eq!(sheet.shown_cell(3, 1).title(), "1");
+        assert_eq!(sheet.shown_cell(4, 1).title(), "2");
+    }
+}
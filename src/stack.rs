@@ -1,27 +1,182 @@
-
 use anyhow::{anyhow, Result};
+use thiserror::Error;
 
 use crate::ops::{Pos,Arg, UNINIT, NEG_SIGN, POS_SIGN};
-use crate::parse::{skip_white, parse_arg};
+use crate::parse::{skip_white, parse_arg, idx_to_name};
+use crate::funcs;
+
+// Mistake in a formula text, `pos` is the number of characters before it
+#[derive(Debug,Clone,PartialEq,Error)]
+#[error("{msg} at position {}", .pos + 1)]
+pub struct SyntaxError {
+    pub pos: usize,
+    pub msg: String,
+}
+
+fn syntax_err<S: Into<String>>(pos: usize, msg: S) -> anyhow::Error {
+    SyntaxError { pos, msg: msg.into() }.into()
+}
 
-// TODO: detect errors:
-//  - bracket follows comma
-//  - comma follows bracket
 pub fn str_expr_to_vec(s: &str) -> Result<Vec<Arg>> {
-    // let mut last_arg = Arg::Op(String::new());
-    let mut args: Vec<Arg> = Vec::new();
+    Ok(tokenize(s, 0)?.into_iter().map(|(arg, _)| arg).collect())
+}
+
+// Splits a formula into arguments, each with its position in the text. Positions start from `base`
+fn tokenize(s: &str, base: usize) -> Result<Vec<(Arg, usize)>> {
+    let offset = |rest: &str| base + s[..s.len() - rest.len()].chars().count();
+    let mut args: Vec<(Arg, usize)> = Vec::new();
     let mut st = skip_white(s);
     loop {
-        let (st_in, arg) = parse_arg(st)?;
+        let (st_in, arg) = match parse_arg(st) {
+            Ok(v) => v,
+            Err(_) => {
+                let msg = match st.chars().next() {
+                    Some('"') => String::from("unclosed text"),
+                    Some('#') => String::from("invalid error value"),
+                    Some(c) if c.is_alphabetic() || c == '$' || c == '\'' => String::from("invalid reference"),
+                    Some(c) => format!("unexpected '{}'", c),
+                    None => String::from("unexpected end"),
+                };
+                return Err(syntax_err(offset(st), msg));
+            },
+        };
         if let Arg::End = arg {
             break;
         }
+        args.push((arg, offset(st)));
         st = skip_white(st_in);
-        args.push(arg);
     }
     Ok(args)
 }
 
+// References of a formula being edited: start and end positions(in characters) of each cell or range
+// reference in `text`. Unlike compiling, it stops at the first part that cannot be parsed, so an
+// unfinished formula still shows the references typed so far
+pub fn references(text: &str) -> Vec<(usize, usize, Arg)> {
+    let s = match text.strip_prefix('=') {
+        None => return Vec::new(),
+        Some(s) => s,
+    };
+    let offset = |rest: &str| 1 + s[..s.len() - rest.len()].chars().count();
+    let mut tokens: Vec<(usize, usize)> = Vec::new();
+    let mut args: Vec<Arg> = Vec::new();
+    let mut st = skip_white(s);
+    while let Ok((st_in, arg)) = parse_arg(st) {
+        if let Arg::End = arg {
+            break;
+        }
+        tokens.push((offset(st), offset(st_in)));
+        args.push(arg);
+        st = skip_white(st_in);
+    }
+    // names declared by LET and LAMBDA are not columns
+    tokens.into_iter().zip(bind_local_names(&args))
+        .filter_map(|((start, end), arg)| if let Arg::Rng(..) = arg { Some((start, end, arg)) } else { None })
+        .collect()
+}
+
+// Compiles a formula with or without the leading '='. Positions of syntax errors
+// are counted from the start of `text`
+pub fn compile(text: &str) -> Result<Vec<Arg>> {
+    let skip = if text.starts_with('=') { 1 } else { 0 };
+    let tokens = tokenize(&text[skip..], skip)?;
+    check_syntax(&tokens, text.chars().count())?;
+    let args: Vec<Arg> = tokens.into_iter().map(|(arg, _)| arg).collect();
+    expr_to_stack(&args)
+}
+
+// Syntax error of a cell text, None if the text is not a formula or it is correct
+pub fn syntax_error(text: &str) -> Option<SyntaxError> {
+    if !text.starts_with('=') {
+        return None;
+    }
+    compile(text).err().and_then(|e| e.downcast::<SyntaxError>().ok())
+}
+
+// Rejects formulas that cannot be calculated: unbalanced brackets, misplaced commas,
+// missing operands, and wrong number of arguments of built-in functions. `len` is the
+// length of the text to point at its end
+fn check_syntax(tokens: &[(Arg, usize)], len: usize) -> Result<()> {
+    // open brackets: the function and its position, the bracket and its position, the number of commas
+    type Func = Option<(String, usize)>;
+    let mut open: Vec<(Func, String, usize, usize)> = Vec::new();
+    let mut want_operand = true;
+    let mut prev: Option<&Arg> = None;
+    for (idx, (arg, pos)) in tokens.iter().enumerate() {
+        let pos = *pos;
+        match arg {
+            Arg::OBracket(b) => {
+                let func = match prev {
+                    Some(Arg::Func(name, _)) => Some((name.to_string(), tokens[idx-1].1)),
+                    _ if !want_operand => return Err(syntax_err(pos, format!("missing operator before '{}'", b))),
+                    _ => None,
+                };
+                open.push((func, b.to_string(), pos, 0));
+                want_operand = true;
+            },
+            Arg::CBracket(b) => {
+                let (func, ob, _, commas) = match open.pop() {
+                    None => return Err(syntax_err(pos, format!("unmatched '{}'", b))),
+                    Some(o) => o,
+                };
+                if (ob == "(") != (b == ")") {
+                    return Err(syntax_err(pos, format!("'{}' closes '{}'", b, ob)));
+                }
+                let empty = matches!(prev, Some(Arg::OBracket(_)));
+                match prev {
+                    Some(Arg::Comma) => return Err(syntax_err(pos, format!("missing argument before '{}'", b))),
+                    Some(Arg::OBracket(_)) if func.is_none() => return Err(syntax_err(pos, "empty brackets")),
+                    _ if want_operand && !empty => return Err(syntax_err(pos, format!("missing operand before '{}'", b))),
+                    _ => {},
+                }
+                if let Some((name, fpos)) = func {
+                    let cnt = if empty { 0 } else { commas + 1 };
+                    funcs::check_arity(&name, cnt).map_err(|msg| syntax_err(fpos, msg))?;
+                }
+                want_operand = false;
+            },
+            Arg::Comma => {
+                match open.last_mut() {
+                    Some((Some(_), _, _, commas)) => *commas += 1,
+                    Some(_) => return Err(syntax_err(pos, "',' in brackets that are not a function call")),
+                    None => return Err(syntax_err(pos, "',' outside of function arguments")),
+                }
+                if want_operand && !matches!(prev, Some(Arg::Comma) | Some(Arg::OBracket(_))) {
+                    return Err(syntax_err(pos, "missing operand before ','"));
+                }
+                want_operand = true;
+            },
+            Arg::Op(op) if op == "%" => if want_operand {
+                return Err(syntax_err(pos, "missing operand before '%'"));
+            },
+            Arg::Op(op) if op == "+" || op == "-" => want_operand = true,
+            Arg::Op(op) | Arg::Eq(op) => {
+                if want_operand {
+                    return Err(syntax_err(pos, format!("missing operand before '{}'", op)));
+                }
+                want_operand = true;
+            },
+            _ => {
+                if !want_operand {
+                    return Err(syntax_err(pos, format!("missing operator before '{}'", arg.title())));
+                }
+                want_operand = false;
+            },
+        }
+        prev = Some(arg);
+    }
+    if let Some((_, b, pos, _)) = open.last() {
+        return Err(syntax_err(*pos, format!("unclosed '{}'", b)));
+    }
+    if tokens.is_empty() {
+        return Err(syntax_err(len, "empty formula"));
+    }
+    if want_operand {
+        return Err(syntax_err(len, "missing operand at the end"));
+    }
+    Ok(())
+}
+
 // -> priority, right_assoc
 fn priority(arg: &Arg) -> (u16, bool) {
     match arg {
@@ -62,31 +217,157 @@ fn lookup_func_arg(args: &[Arg], idx: usize) -> bool {
             } else {
                 lvl -= 1;
             },
-            Arg::Func(_,_) | Arg::Number(_) | Arg::Str(_) | Arg::Rng(_, _) => return true,
+            Arg::Func(_,_) | Arg::Number(_) | Arg::Str(_) | Arg::Rng(_, _) | Arg::Bool(_) | Arg::Err(_) | Arg::Name(_) => return true,
             _ => {},
         }
     }
     false
 }
 
-// TODO: detect errors
+// Functions that calculate their arguments only when they need them or take failed arguments as values
+fn is_lazy_func(name: &str) -> bool {
+    matches!(name.to_lowercase().as_str(), "if" | "ifs" | "switch" | "and" | "or" | "iferror" | "ifna" |
+        "iserror" | "iserr" | "isna" | "error.type" | "let" | "lambda" | "count" | "counta")
+}
+
+// Identifier that can be a local name, including the ones that look like a column: `x`, `tax`
+fn local_ident(arg: &Arg) -> Option<String> {
+    match arg {
+        Arg::Name(name) => Some(name.to_lowercase()),
+        Arg::Rng(None, v) if v.len() == 1 && v[0].full_col && !v[0].fixed_col => Some(idx_to_name(v[0].col).to_lowercase()),
+        _ => None,
+    }
+}
+
+// Names declared by LET and LAMBDA are visible only inside the function. Their declarations and uses
+// become `Name` even if they look like columns, e.g. `x` in `LET(x, 1, x+1)` is not the column X
+pub fn bind_local_names(args: &[Arg]) -> Vec<Arg> {
+    // open brackets: the function that declares names, index of the current argument, declared names
+    let mut scopes: Vec<(Option<String>, usize, Vec<String>)> = Vec::new();
+    let mut func: Option<String> = None;
+    let mut res = Vec::with_capacity(args.len());
+    for (idx, arg) in args.iter().enumerate() {
+        match arg {
+            Arg::OBracket(_) => scopes.push((func.take(), 0, Vec::new())),
+            Arg::CBracket(_) => { scopes.pop(); },
+            Arg::Comma => if let Some(scope) = scopes.last_mut() {
+                scope.1 += 1;
+            },
+            _ => {},
+        }
+        func = match arg {
+            Arg::Func(name, _) if name.eq_ignore_ascii_case("let") || name.eq_ignore_ascii_case("lambda") => Some(name.to_lowercase()),
+            _ => None,
+        };
+        let ident = match local_ident(arg) {
+            None => {
+                res.push(arg.clone());
+                continue;
+            },
+            Some(id) => id,
+        };
+        // a declaration is a whole argument followed by another one: LET(name, value, ..., body), LAMBDA(name, ..., body)
+        let whole = matches!(args.get(idx+1), Some(Arg::Comma)) && idx > 0 && matches!(args[idx-1], Arg::Comma | Arg::OBracket(_));
+        match scopes.last_mut() {
+            Some((Some(f), n, names)) if whole && (f == "lambda" || *n % 2 == 0) => {
+                names.push(ident.clone());
+                res.push(Arg::Name(ident));
+            },
+            _ => if scopes.iter().any(|(_, _, names)| names.contains(&ident)) {
+                res.push(Arg::Name(ident));
+            } else {
+                res.push(arg.clone());
+            },
+        }
+    }
+    res
+}
+
+// Name declared by LET or LAMBDA. Their arguments are postponed, so the name comes as a program
+pub fn local_name(arg: &Arg) -> Result<String> {
+    match arg {
+        Arg::Lazy(v) => match v.as_slice() {
+            [Arg::Name(name)] => Ok(name.to_lowercase()),
+            _ => Err(anyhow!("invalid name {:?}", v)),
+        },
+        _ => Err(anyhow!("invalid name {:?}", arg)),
+    }
+}
+
+// Parameters and body of LAMBDA from its postponed arguments
+pub fn lambda_parts(mut args: Vec<Arg>) -> Result<(Vec<String>, Vec<Arg>)> {
+    let body = match args.pop() {
+        Some(Arg::Lazy(body)) if !body.is_empty() => body,
+        _ => return Err(anyhow!("LAMBDA requires a calculation")),
+    };
+    let mut params: Vec<String> = Vec::new();
+    for arg in args.iter() {
+        let name = local_name(arg)?;
+        if params.contains(&name) {
+            return Err(anyhow!("duplicated parameter {}", name));
+        }
+        params.push(name);
+    }
+    Ok((params, body))
+}
+
+// Compiles LAMBDA stored in a name, e.g. `LAMBDA(x, y, x*y)`
+pub fn parse_lambda(src: &str) -> Result<Arg> {
+    let mut prog = compile(src)?;
+    match prog.pop() {
+        Some(Arg::Func(name, cnt)) if name.eq_ignore_ascii_case("lambda") && cnt == prog.len() => {
+            let (params, body) = lambda_parts(prog)?;
+            Ok(Arg::Lambda(src.to_string(), params, body, Vec::new()))
+        },
+        _ => Err(anyhow!("not a LAMBDA: {}", src)),
+    }
+}
+
+// Move everything added to the output since `start` into a single postponed argument
+fn wrap_lazy(expr: &mut Vec<Arg>, start: usize) {
+    let body = expr.split_off(start);
+    expr.push(Arg::Lazy(body));
+}
+
+// Omitted function argument, e.g. the second one in `f(1,,3)`, is passed as `End`.
+// Lazy functions get an empty thunk instead
+fn is_omitted_arg(args: &[Arg], idx: usize, lazy: &[(usize, usize)], depth: usize) -> bool {
+    if idx == 0 {
+        return false;
+    }
+    if let Some(&(d, _)) = lazy.last() {
+        if d + 1 == depth {
+            return false;
+        }
+    }
+    matches!((&args[idx-1], &args[idx]), (Arg::Comma, _) | (Arg::OBracket(_), Arg::Comma))
+}
+
 // Convert raw argument list to an easy to calculate vector
 pub fn expr_to_stack(args: &[Arg]) -> Result<Vec<Arg>> {
+    let args = &bind_local_names(args);
     let mut is_last_op = true;
     let mut stack: Vec<Arg> = Vec::new();
     let mut expr: Vec<Arg> = Vec::new();
+    // Open lazy functions: position of their bracket in `stack` and where the current argument starts in `expr`
+    let mut lazy: Vec<(usize, usize)> = Vec::new();
 
     for (idx, arg) in args.iter().enumerate() {
         match arg {
             Arg::OBracket(_) => {
+                if let Some(Arg::Func(name, _)) = stack.last() {
+                    if is_lazy_func(name) {
+                        lazy.push((stack.len(), expr.len()));
+                    }
+                }
                 stack.push(arg.clone());
                 is_last_op = true;
             },
-            Arg::Number(_) | Arg::Str(_) | Arg::Rng(_, _) => {
+            Arg::Number(_) | Arg::Str(_) | Arg::Rng(_, _) | Arg::Date(_) | Arg::Time(_) | Arg::Err(_) | Arg::Name(_) => {
                 expr.push(arg.clone());
                 is_last_op = false;
             },
-            Arg::Bool(_) => {
+            Arg::Bool(_) | Arg::Lazy(_) | Arg::Lambda(..) | Arg::Array(_) => {
                 expr.push(arg.clone());
                 is_last_op = false;
             },
@@ -98,11 +379,24 @@ pub fn expr_to_stack(args: &[Arg]) -> Result<Vec<Arg>> {
             },
             Arg::End => break,
             Arg::CBracket(b) => {
+                if is_omitted_arg(args, idx, &lazy, stack.len()) {
+                    expr.push(Arg::End);
+                }
                 loop {
                     match stack.pop() {
                         None => return Err(anyhow!("unmatched '{}'", b)),
                         Some(st) => match st {
                             Arg::OBracket(ref bb) => if (bb == "(" && b == ")") || (bb == "[" && b == "]") {
+                                    if let Some(&(depth, start)) = lazy.last() {
+                                        if depth == stack.len() {
+                                            if let Some(Arg::Func(_, cnt)) = stack.last() {
+                                                if *cnt != 0 {
+                                                    wrap_lazy(&mut expr, start);
+                                                }
+                                            }
+                                            lazy.pop();
+                                        }
+                                    }
                                     break;
                                 } else {
                                     return Err(anyhow!("brackets mismatch"));
@@ -120,11 +414,20 @@ pub fn expr_to_stack(args: &[Arg]) -> Result<Vec<Arg>> {
                 is_last_op = false;
             },
             Arg::Comma => {
+                if is_omitted_arg(args, idx, &lazy, stack.len()) {
+                    expr.push(Arg::End);
+                }
                 loop {
                     match stack.pop() {
                         None => return Err(anyhow!("comma outside brackets")),
                         Some(st) => match st {
                             Arg::OBracket(_) => {
+                                if let Some((depth, start)) = lazy.last_mut() {
+                                    if *depth == stack.len() {
+                                        wrap_lazy(&mut expr, *start);
+                                        *start = expr.len();
+                                    }
+                                }
                                 let mut fn_arg = Arg::End;
                                 if let Some(Arg::Func(name, arg_cnt)) = stack.last() {
                                     fn_arg = Arg::Func(name.to_string(), arg_cnt+1);
@@ -179,6 +482,9 @@ pub fn expr_to_stack(args: &[Arg]) -> Result<Vec<Arg>> {
         }
     }
     for a in stack.drain(..).rev() {
+        if let Arg::OBracket(b) = a {
+            return Err(anyhow!("unclosed '{}'", b));
+        }
         expr.push(a);
     }
 
@@ -216,6 +522,54 @@ mod stack_test {
                     Arg::Number(8.0), Arg::Op("-".to_string()),
                 ],
             },
+            Tst{
+                val: "max(1,,2)", err: false,
+                res: vec![
+                    Arg::Number(1.0), Arg::End, Arg::Number(2.0), Arg::Func("max".to_string(), 3),
+                ],
+            },
+            Tst{
+                val: "if(a1=0,,2+sum(1))", err: false,
+                res: vec![
+                    Arg::Lazy(vec![
+                        Arg::Rng(None, vec![Pos{col:0, row: 0, ..Pos::default()}]),
+                        Arg::Number(0.0), Arg::Eq("=".to_string()),
+                    ]),
+                    Arg::Lazy(vec![]),
+                    Arg::Lazy(vec![
+                        Arg::Number(2.0), Arg::Number(1.0), Arg::Func("sum".to_string(), 1), Arg::Op("+".to_string()),
+                    ]),
+                    Arg::Func("if".to_string(), 3),
+                ],
+            },
+            Tst{
+                val: "1+or(true, and(false))", err: false,
+                res: vec![
+                    Arg::Number(1.0),
+                    Arg::Lazy(vec![Arg::Bool(true)]),
+                    Arg::Lazy(vec![Arg::Lazy(vec![Arg::Bool(false)]), Arg::Func("and".to_string(), 1)]),
+                    Arg::Func("or".to_string(), 2),
+                    Arg::Op("+".to_string()),
+                ],
+            },
+            Tst{
+                val: "let(x, 1, x+b)*x", err: false,
+                res: vec![
+                    Arg::Lazy(vec![Arg::Name("x".to_string())]),
+                    Arg::Lazy(vec![Arg::Number(1.0)]),
+                    Arg::Lazy(vec![
+                        Arg::Name("x".to_string()),
+                        Arg::Rng(None, vec![Pos{col: 1, full_col: true, ..Pos::default()}]),
+                        Arg::Op("+".to_string()),
+                    ]),
+                    Arg::Func("let".to_string(), 3),
+                    Arg::Rng(None, vec![Pos{col: 23, full_col: true, ..Pos::default()}]),
+                    Arg::Op("*".to_string()),
+                ],
+            },
+            Tst{
+                val: "sum(1,(2+3)", err: true, res: vec![],
+            },
         ];
         for t in tests {
             let s = str_expr_to_vec(t.val).unwrap();
@@ -230,4 +584,55 @@ mod stack_test {
             }
         }
     }
+
+    #[test]
+    fn syntax_test() {
+        let tests: Vec<(&str, Option<(usize, &str)>)> = vec![
+            ("=SUM(1,,2)", None),
+            ("=-(1+2)%*-A1", None),
+            ("=IF(A1,,2)", None),
+            ("=LET(x, 2, f, LAMBDA(y, x*y), f(3))", None),
+            ("=SUM(1,,)", Some((8, "missing argument before ')'"))),
+            ("=(1+", Some((1, "unclosed '('"))),
+            ("=1+", Some((3, "missing operand at the end"))),
+            ("=SUM(1))", Some((7, "unmatched ')'"))),
+            ("=(1,2)", Some((3, "',' in brackets that are not a function call"))),
+            ("=1,2", Some((2, "',' outside of function arguments"))),
+            ("=SUM(1+)", Some((7, "missing operand before ')'"))),
+            ("=SUM(1*,2)", Some((7, "missing operand before ','"))),
+            ("=1 2", Some((3, "missing operator before '2'"))),
+            ("=(1)(2)", Some((4, "missing operator before '('"))),
+            ("=*2", Some((1, "missing operand before '*'"))),
+            ("=1+()", Some((4, "empty brackets"))),
+            ("=SUM(1]", Some((6, "']' closes '('"))),
+            ("=1 + MID(\"abc\", 2)", Some((5, "MID requires 3 arguments"))),
+            ("=PI(1)", Some((1, "PI does not take arguments"))),
+            ("=\"abc", Some((1, "unclosed text"))),
+            ("=", Some((1, "empty formula"))),
+        ];
+        for (text, res) in tests {
+            let err = compile(text).err().map(|e| {
+                let e = e.downcast::<SyntaxError>().unwrap();
+                (e.pos, e.msg)
+            });
+            assert_eq!(err, res.map(|(pos, msg)| (pos, msg.to_string())), "{}", text);
+        }
+        assert_eq!(syntax_error("=1+").unwrap().to_string(), "missing operand at the end at position 4");
+        assert!(syntax_error("1+").is_none());
+    }
+
+    #[test]
+    fn references_test() {
+        let tests: Vec<(&str, Vec<(usize, usize)>)> = vec![
+            ("=SUM(A1:B2, $C$3) + Page2!D4", vec![(5, 10), (12, 16), (20, 28)]),
+            ("=LET(x, A1, x+B:B)", vec![(8, 10), (14, 17)]),
+            ("=A1 + SUM(A1, \"B", vec![(1, 3), (10, 12)]),
+            ("=\"A1\" & taxrate", vec![]),
+            ("A1+B2", vec![]),
+        ];
+        for (text, res) in tests {
+            let refs: Vec<(usize, usize)> = references(text).into_iter().map(|(start, end, _)| (start, end)).collect();
+            assert_eq!(refs, res, "{}", text);
+        }
+    }
 }
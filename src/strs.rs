@@ -110,6 +110,197 @@ pub fn pad(s: &str, width: usize) -> String {
     s.to_string() + &" ".repeat(width-w)
 }
 
+// Substring by character positions, unlike `cut` that counts display width
+pub fn substr(s: &str, start: usize, len: usize) -> String {
+    s.chars().skip(start).take(len).collect()
+}
+
+// Character index of the first occurrence of `what` at or after character `start`
+pub fn find(s: &str, what: &str, start: usize, ignore_case: bool) -> Option<usize> {
+    let hay: Vec<char> = s.chars().collect();
+    let needle: Vec<char> = what.chars().collect();
+    if start > hay.len() || needle.len() > hay.len() {
+        return None;
+    }
+    let same = |a: char, b: char| if ignore_case { a.to_lowercase().eq(b.to_lowercase()) } else { a == b };
+    (start..=hay.len() - needle.len()).find(|&i| needle.iter().enumerate().all(|(j, &c)| same(hay[i + j], c)))
+}
+
+// Capitalizes the first letter of every word and lowercases the rest
+pub fn proper(s: &str) -> String {
+    let mut res = String::new();
+    let mut in_word = false;
+    for c in s.chars() {
+        if in_word {
+            res.extend(c.to_lowercase());
+        } else {
+            res.extend(c.to_uppercase());
+        }
+        in_word = c.is_alphabetic();
+    }
+    res
+}
+
+// Spreadsheet wildcard match: `*` - any sequence, `?` - any character, `~` escapes the next character.
+// Case-insensitive
+pub fn wildcard_match(pattern: &str, s: &str) -> bool {
+    let text: Vec<char> = s.chars().collect();
+    wildcard_at(&wildcard_pattern(pattern), &text, true)
+}
+
+// Character index of the first text matching the wildcard pattern at or after character `start`.
+// Case-insensitive, like SEARCH
+pub fn wildcard_find(s: &str, pattern: &str, start: usize) -> Option<usize> {
+    let text: Vec<char> = s.chars().collect();
+    if start > text.len() {
+        return None;
+    }
+    let pat = wildcard_pattern(pattern);
+    (start..=text.len()).find(|&i| wildcard_at(&pat, &text[i..], false))
+}
+
+// Pattern characters with a flag that is true for `*` and `?` that are not escaped
+fn wildcard_pattern(pattern: &str) -> Vec<(char, bool)> {
+    let mut pat: Vec<(char, bool)> = Vec::new();
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '~' => match chars.next() {
+                Some(n) => pat.push((n, false)),
+                None => pat.push((c, false)),
+            },
+            '*' | '?' => pat.push((c, true)),
+            _ => pat.push((c, false)),
+        }
+    }
+    pat
+}
+
+// Matches the whole text or, if `whole` is false, only its beginning
+fn wildcard_at(pat: &[(char, bool)], text: &[char], whole: bool) -> bool {
+    let same = |a: char, b: char| a == b || a.to_lowercase().eq(b.to_lowercase());
+    let (mut pi, mut ti) = (0usize, 0usize);
+    let mut star: Option<(usize, usize)> = None;
+    while ti < text.len() {
+        if !whole && pi == pat.len() {
+            return true;
+        }
+        if pi < pat.len() && pat[pi] == ('*', true) {
+            star = Some((pi, ti));
+            pi += 1;
+        } else if pi < pat.len() && (pat[pi] == ('?', true) || (!pat[pi].1 && same(pat[pi].0, text[ti]))) {
+            pi += 1;
+            ti += 1;
+        } else if let Some((sp, st)) = star {
+            pi = sp + 1;
+            ti = st + 1;
+            star = Some((sp, st + 1));
+        } else {
+            return false;
+        }
+    }
+    pat[pi..].iter().all(|&p| p == ('*', true))
+}
+
+fn group_thousands(digits: &str) -> String {
+    let mut res = String::new();
+    let cnt = digits.chars().count();
+    for (i, c) in digits.chars().enumerate() {
+        res.push(c);
+        let left = cnt - i - 1;
+        if left != 0 && left.is_multiple_of(3) {
+            res.push(',');
+        }
+    }
+    res
+}
+
+// Formats a number using a spreadsheet-like mask: "0.00", "#,##0", "0.0%", "0.00E+00", "$#,##0.00 USD".
+// Text in double quotes and characters outside the number mask are copied as is
+pub fn format_number(val: f64, mask: &str) -> String {
+    let chars: Vec<char> = mask.chars().collect();
+    let is_digit = |c: char| c == '0' || c == '#' || c == '.' || c == ',';
+    let epos = (1..chars.len().saturating_sub(1)).find(|&i| {
+        (chars[i] == 'E' || chars[i] == 'e') && (chars[i + 1] == '+' || chars[i + 1] == '-') && is_digit(chars[i - 1])
+    });
+    let num_end = epos.unwrap_or(chars.len());
+    let (first, mut last) = match (chars[..num_end].iter().position(|&c| is_digit(c)), chars[..num_end].iter().rposition(|&c| is_digit(c))) {
+        (Some(f), Some(l)) => (f, l),
+        _ => return mask.replace('"', ""),
+    };
+    let exp = epos.is_some();
+    if let Some(pos) = epos {
+        last = pos + 1;
+        while last + 1 < chars.len() && chars[last + 1] == '0' {
+            last += 1;
+        }
+    }
+    let prefix: String = chars[..first].iter().filter(|&&c| c != '"').collect();
+    let suffix: String = chars[last + 1..].iter().filter(|&&c| c != '"').collect();
+    let num: String = chars[first..=last].iter().collect();
+    let (num, exp_digits) = if exp {
+        let pos = num.find(['E', 'e']).unwrap_or(num.len());
+        (num[..pos].to_string(), num[pos + 2..].len())
+    } else {
+        (num, 0)
+    };
+    let (int_mask, frac_mask) = match num.find('.') {
+        Some(pos) => (&num[..pos], &num[pos + 1..]),
+        None => (num.as_str(), ""),
+    };
+    let min_frac = frac_mask.chars().filter(|&c| c == '0').count();
+    let max_frac = frac_mask.chars().filter(|&c| c == '0' || c == '#').count();
+    let min_int = int_mask.chars().filter(|&c| c == '0').count();
+    let thousands = int_mask.contains(',');
+
+    let mut val = val;
+    if prefix.contains('%') || suffix.contains('%') {
+        val *= 100.0;
+    }
+    let mut power = 0i32;
+    if exp && val != 0.0 {
+        power = val.abs().log10().floor() as i32;
+        val /= 10f64.powi(power);
+        if format!("{:.*}", max_frac, val.abs()).starts_with("10") {
+            val /= 10.0;
+            power += 1;
+        }
+    }
+    // spreadsheets round halves away from zero
+    let scale = 10f64.powi(max_frac as i32);
+    let text = format!("{:.*}", max_frac, (val.abs() * scale).round() / scale);
+    let (int_part, frac_part) = match text.find('.') {
+        Some(pos) => (&text[..pos], &text[pos + 1..]),
+        None => (text.as_str(), ""),
+    };
+    let mut frac = frac_part.to_string();
+    while frac.len() > min_frac && frac.ends_with('0') {
+        frac.pop();
+    }
+    let mut int = int_part.trim_start_matches('0').to_string();
+    while int.len() < min_int {
+        int.insert(0, '0');
+    }
+    if thousands {
+        int = group_thousands(&int);
+    }
+    let mut res = String::new();
+    if val < 0.0 && (int.chars().any(|c| c != '0' && c != ',') || frac.chars().any(|c| c != '0')) {
+        res.push('-');
+    }
+    res += &prefix;
+    res += &int;
+    if !frac.is_empty() || (frac_mask.is_empty() && num.ends_with('.')) {
+        res.push('.');
+    }
+    res += &frac;
+    if exp {
+        let sign = if power < 0 { '-' } else { '+' };
+        res += &format!("E{}{:0>width$}", sign, power.abs(), width = exp_digits);
+    }
+    res + &suffix
+}
+
 #[rustfmt::skip]
 #[cfg(test)]
 mod str_test {
@@ -174,4 +365,63 @@ mod str_test {
         let c = pad(s, 16);
         assert_eq!(c, "ʃʤaíckəʊkʌɪg    ".to_string());
     }
+
+    #[test]
+    fn substr_find_test() {
+        let s = "ʃʤaíckəʊkʌɪg";
+        assert_eq!(substr(s, 2, 3), "aíc".to_string());
+        assert_eq!(substr(s, 10, 5), "ɪg".to_string());
+        assert_eq!(substr(s, 20, 5), "".to_string());
+        assert_eq!(find(s, "kə", 0, false), Some(5));
+        assert_eq!(find(s, "k", 6, false), Some(8));
+        assert_eq!(find("Hello", "LL", 0, false), None);
+        assert_eq!(find("Hello", "LL", 0, true), Some(2));
+        assert_eq!(find("Hello", "", 3, false), Some(3));
+        assert_eq!(find("Hello", "o", 6, false), None);
+        assert_eq!(proper("hELLO wORLD-wide 2nd"), "Hello World-Wide 2Nd".to_string());
+    }
+
+    #[test]
+    fn wildcard_test() {
+        assert!(wildcard_match("apple*", "Apple pie"));
+        assert!(wildcard_match("apple*", "apple"));
+        assert!(!wildcard_match("apple*", "pineapple"));
+        assert!(wildcard_match("*a?e", "grape"));
+        assert!(!wildcard_match("*a?e", "grapes"));
+        assert!(wildcard_match("a*b*c", "aXXbYYbc"));
+        assert!(wildcard_match("what~?", "What?"));
+        assert!(!wildcard_match("what~?", "whats"));
+        assert!(wildcard_match("~*", "*"));
+        assert!(wildcard_match("", ""));
+        assert!(!wildcard_match("", "a"));
+        assert_eq!(wildcard_find("hello", "l?o", 0), Some(2));
+        assert_eq!(wildcard_find("hello", "L*O", 0), Some(2));
+        assert_eq!(wildcard_find("hello", "*", 4), Some(4));
+        assert_eq!(wildcard_find("a?b", "~?", 0), Some(1));
+        assert_eq!(wildcard_find("hello", "l?l", 0), None);
+        assert_eq!(wildcard_find("hello", "", 6), None);
+    }
+
+    #[test]
+    fn format_number_test() {
+        let tests: Vec<(f64, &str, &str)> = vec![
+            (1234.567, "0", "1235"),
+            (1234.567, "0.00", "1234.57"),
+            (1234.567, "#,##0.00", "1,234.57"),
+            (1234567.0, "#,##0", "1,234,567"),
+            (0.256, "0.0%", "25.6%"),
+            (0.5, "#.##", ".5"),
+            (2.5, "0.0#", "2.5"),
+            (2.125, "0.0#", "2.13"),
+            (-3.0, "0.00", "-3.00"),
+            (-0.001, "0.00", "0.00"),
+            (12345.0, "0.00E+00", "1.23E+04"),
+            (0.00012, "0.0E+0", "1.2E-4"),
+            (9.99, "$#,##0.00 \"USD\"", "$9.99 USD"),
+            (7.0, "no digits", "no digits"),
+        ];
+        for (val, mask, res) in tests {
+            assert_eq!(format_number(val, mask), res.to_string(), "{} - {}", val, mask);
+        }
+    }
 }
@@ -0,0 +1,177 @@
+use std::collections::HashSet;
+use std::fs;
+use std::sync::OnceLock;
+
+use anyhow::{anyhow, Result};
+
+use crate::ops::Arg;
+use crate::book::Names;
+use crate::parse::{parse_func, parse_ident, skip_white};
+use crate::stack::parse_lambda;
+
+// User-defined functions shared by all workbooks. The file has a line per function:
+//   "MARGIN(p, c)" = "(p-c)/p"
+// Built-in functions and names of a workbook hide them
+const FUNCTIONS_FILE: &str = "functions.toml";
+
+static FUNCTIONS: OnceLock<Names> = OnceLock::new();
+
+// Loads functions from the user config directory. Returns descriptions of definitions that failed
+pub fn load() -> Vec<String> {
+    let path = match dirs::config_dir() {
+        None => return Vec::new(),
+        Some(dir) => dir.join("tspss").join(FUNCTIONS_FILE),
+    };
+    let text = match fs::read_to_string(&path) {
+        Err(_) => return Vec::new(),
+        Ok(t) => t,
+    };
+    let (funcs, errs) = parse_functions(&text);
+    info!("Loaded {} functions from {:?}", funcs.len(), path);
+    for e in errs.iter() {
+        warn!("{}: {}", FUNCTIONS_FILE, e);
+    }
+    init(funcs);
+    errs.iter().map(|e| format!("{}: {}", FUNCTIONS_FILE, e)).collect()
+}
+
+// Functions are set only once, the following calls are ignored
+pub fn init(funcs: Names) {
+    let _ = FUNCTIONS.set(funcs);
+}
+
+// LAMBDA of a user-defined function
+pub fn lookup(name: &str) -> Option<Arg> {
+    FUNCTIONS.get().and_then(|funcs| funcs.get(&name.to_lowercase())).cloned()
+}
+
+// Names of all user-defined functions in lower case
+pub fn names() -> Vec<String> {
+    FUNCTIONS.get().map(|funcs| funcs.keys().cloned().collect()).unwrap_or_default()
+}
+
+// Compiles all good definitions. A bad one is skipped, so the rest can be used
+pub fn parse_functions(text: &str) -> (Names, Vec<String>) {
+    let mut funcs = Names::new();
+    let table = match text.parse::<toml::Value>() {
+        Err(e) => return (funcs, vec![e.to_string()]),
+        Ok(toml::Value::Table(t)) => t,
+        Ok(_) => return (funcs, vec![String::from("not a table")]),
+    };
+    let mut errs = Vec::new();
+    for (sign, body) in table.iter() {
+        let body = match body.as_str() {
+            None => {
+                errs.push(format!("{}: the formula must be a string", sign));
+                continue;
+            },
+            Some(b) => b,
+        };
+        match parse_function(sign, body) {
+            Err(e) => errs.push(format!("{}: {}", sign, e)),
+            Ok((name, f)) => {
+                if funcs.insert(name, f).is_some() {
+                    errs.push(format!("{}: the function is defined twice", sign));
+                }
+            },
+        }
+    }
+    (funcs, errs)
+}
+
+// `sign` is the name with parameters: `MARGIN(p, c)`, `body` is the formula with or without leading '='
+fn parse_function(sign: &str, body: &str) -> Result<(String, Arg)> {
+    let sign = sign.trim();
+    let (rest, name) = parse_func(sign);
+    if name.is_empty() {
+        return Err(anyhow!("expected NAME(param1, param2, ...)"));
+    }
+    let mut params: Vec<String> = Vec::new();
+    let mut st = skip_white(skip_white(rest).strip_prefix('(').unwrap_or(rest));
+    if let Some(s) = st.strip_prefix(')') {
+        st = s;
+    } else {
+        loop {
+            let (s, param) = parse_ident(st);
+            if param.is_empty() {
+                return Err(anyhow!("invalid parameter at '{}'", st));
+            }
+            params.push(param.to_lowercase());
+            let s = skip_white(s);
+            if let Some(s) = s.strip_prefix(',') {
+                st = skip_white(s);
+            } else if let Some(s) = s.strip_prefix(')') {
+                st = s;
+                break;
+            } else {
+                return Err(anyhow!("expected ',' or ')' at '{}'", s));
+            }
+        }
+    }
+    if !st.trim().is_empty() {
+        return Err(anyhow!("unexpected '{}' after parameters", st.trim()));
+    }
+    let mut seen = HashSet::new();
+    if let Some(p) = params.iter().find(|p| !seen.insert(*p)) {
+        return Err(anyhow!("duplicated parameter {}", p));
+    }
+    let body = body.trim();
+    let body = body.strip_prefix('=').unwrap_or(body);
+    if body.trim().is_empty() {
+        return Err(anyhow!("empty formula"));
+    }
+    let mut args = params.clone();
+    args.push(body.to_string());
+    let f = parse_lambda(&format!("LAMBDA({})", args.join(", ")))?;
+    Ok((name.to_lowercase(), f))
+}
+
+#[rustfmt::skip]
+#[cfg(test)]
+mod udf_test {
+    use super::*;
+    use crate::sheet::Sheet;
+
+    #[test]
+    fn functions_test() {
+        let text = r#"
+"MARGIN(p, c)" = "(p-c)/p"
+"Double(x)" = "=x*2"
+"ANSWER()" = "42"
+"RATED(x)" = "x*$C$1"
+"BAD(x" = "x"
+"TWICE(x, x)" = "x+x"
+"BROKEN(x)" = "(x+1"
+"NUM(x)" = 5
+"#;
+        let (funcs, mut errs) = parse_functions(text);
+        errs.sort();
+        assert_eq!(funcs.keys().cloned().collect::<Vec<String>>(), vec!["answer", "double", "margin", "rated"]);
+        assert_eq!(errs.len(), 4, "{:?}", errs);
+        assert!(errs[0].starts_with("BAD(x: "), "{}", errs[0]);
+        assert!(errs[1].starts_with("BROKEN(x): "), "{}", errs[1]);
+        assert_eq!(errs[2], "NUM(x): the formula must be a string");
+        assert_eq!(errs[3], "TWICE(x, x): duplicated parameter x");
+        assert_eq!(parse_functions("not toml").0.len(), 0);
+
+        init(funcs);
+        let mut sheet = Sheet::new(0, 80, 25);
+        sheet.set_cell_text(0, 0, "20", true);
+        let tests: Vec<(&str, &str)> = vec![
+            ("=margin(A1, 15)", "0.25"),
+            ("=DOUBLE(ANSWER())", "84"),
+            ("=margin(1)", "#VALUE!"),
+            ("=unknown(1)", "#NAME?"),
+        ];
+        for (expr, res) in tests {
+            sheet.set_cell_text(1, 0, expr, true);
+            assert_eq!(sheet.cell(1, 0).title(), res, "{}", expr);
+        }
+        // a function that reads a cell is recalculated when the cell changes
+        sheet.set_cell_text(2, 0, "3", true);
+        sheet.set_cell_text(1, 0, "=rated(2)", true);
+        assert_eq!(sheet.cell(1, 0).title(), "6");
+        sheet.set_cell_text(2, 0, "10", true);
+        assert_eq!(sheet.cell(1, 0).title(), "20");
+    }
+}
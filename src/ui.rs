@@ -20,16 +20,26 @@ pub struct PageListArgs {
     pub title: String,
 }
 
+// List that opens under the given position, e.g. completions of a word in the editor
+#[derive(Clone,Debug)]
+pub struct PopupArgs {
+    pub items: Vec<String>,
+    pub col: u16,
+    pub row: u16,
+}
+
 #[derive(Debug,Clone)]
 pub enum Dialog {
     None,
     PageList(PageListArgs),
+    Popup(PopupArgs),
 }
 
 #[derive(Debug,Copy,Clone)]
 pub enum Command {
     None,
     Page_ID(usize),
+    Item(usize),
 }
 
 #[derive(Debug,Clone)]
@@ -281,6 +291,24 @@ impl WidgetStack {
                         self.push(lbx);
                         self.set_focus("lbx", scr)?;
                     },
+                    Dialog::Popup(args) => {
+                        if args.items.is_empty() {
+                            return Err(anyhow!("popup list is empty"));
+                        }
+                        let mx = args.items.iter().map(|item| item.width()).max().unwrap_or(0) + 2;
+                        let w = std::cmp::min(mx, ctx.w as usize - 2) as u16;
+                        let h = std::cmp::min(args.items.len(), ctx.h as usize / 2) as u16;
+                        let posx = std::cmp::min(args.col, ctx.w - w);
+                        // below the position if the list fits the screen, above it otherwise
+                        let posy = if args.row + 1 + h <= ctx.h { args.row + 1 } else { args.row.saturating_sub(h) };
+                        let mut lbx = Box::new(ListBox::new(ctx, "lbx", posx, posy, w, h, Color::White, Color::DarkBlue));
+                        for (idx, item) in args.items.iter().enumerate() {
+                            lbx.push_item(ListItem::new(item, Command::Item(idx)));
+                        }
+                        self.next_gen();
+                        self.push(lbx);
+                        self.set_focus("lbx", scr)?;
+                    },
                     _ => info!("unimplemented dialog {:?}", dlg),
                 }
                 Ok(Transition::None)